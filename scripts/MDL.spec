@@ -67,6 +67,7 @@ mesh [constants] :filename [coord_system]
 			  you can specify) from a file into the pointlist 
 			  and or edge list directly.
 			- ":filename" is a file name preceded by token ":"
+			  and is relative to the file with the mesh.
 
 Knobs/Animation
 ---------------
@@ -91,11 +92,26 @@ tween start_frame end_frame knoblist0 knoblist1
 
 frames num_frames	- How many frames to generate all together.
 
-vary knob start_frame end_frame start_val end_val
+vary knob start_frame end_frame start_val end_val [easing]
 			- vary a knob from start_val to end_val over
 			  the course of start_frame to end_frame
+			- easing is one of linear (default), ease-in,
+			  ease-out, ease-in-out, exponential, sine,
+			  bounce or elastic
 setknobs value		- set all the knobs to value
 
+fps value		- frames per second of the animation
+
+loop count		- number of times the gif loops, 0 (default)
+			  loops forever
+
+output gif|frames [extension]
+			- gif (default) saves the animation as
+			  basename.gif. frames saves every frame on
+			  its own as basename-000.extension,
+			  basename-001.extension, ... extension
+			  defaults to ppm.
+
 
 Lighting
 --------
@@ -116,6 +132,62 @@ shading wireframe|flat|gouraud|phong|raytrace
 			- set the shading mode
 
 
+Variables and expressions
+-------------------------
+let name = expression	- evaluates expression and saves it under "name."
+			  It's evaluated again in every frame, in script
+			  order, so it can use knobs and the frame number.
+
+Any number above, other than frame numbers and the screen size, can be
+an expression with + - * / ^, parentheses, unary minus, and the functions
+sin cos tan asin acos atan sqrt abs floor ceil round exp ln min max
+(angles in radians). Expressions can use variables, knobs, pi and frame,
+the number of the frame being rendered (0 in a still image).
+
+An argument can't have spaces outside of parentheses, since spaces
+separate arguments:
+	move 1 -2 3		- three numbers
+	move (x + 1) y*2 0	- three expressions
+A variable where a shape can have a constants name, like sphere x 0 0 10,
+is the first number when there aren't enough numbers without it. When
+both fit, like sphere a x y z r, it's the constants name if there's
+constants with that name, and the first number otherwise.
+
+set, setknobs, vary, fps, light, ambient and constants are evaluated once
+before rendering, so they can only use variables that don't depend on
+knobs or the frame.
+
+
+Blocks
+------
+repeat count [as var] {	- runs the commands up to the matching } count
+			  times. count must be a whole number >= 0. If
+			  var is given, it's 0 in the first run, 1 in
+			  the next and so on.
+def name(params) {	- defines the commands up to the matching } as
+			  name, which runs them with call. params is a
+			  list of variable names separated by commas.
+call name(args)		- runs def name, with each parameter set to
+			  the expression in the same place in args.
+}			- ends the latest repeat or def, on its own line.
+
+Blocks can only have drawing commands, transformations, push, pop, let,
+call, repeat, shading and the commands in MISC other than screen.
+A def can't be in a block, but can be called before it's defined. It
+can't end up calling itself, since nothing could stop it.
+
+Variables set in a block, the loop variable and the parameters can only
+be used inside the block. A def can also use the variables set before it.
+Transformations are not undone at the end of a block, so use push and
+pop inside of it:
+	repeat 12 as i {
+		push
+		rotate z i*30
+		box 100 10 0 50 20 20
+		pop
+	}
+
+
 MISC
 ----
 //			- comment to the end of a line, just like c++
@@ -124,16 +196,40 @@ save_coord_system name
 			  saves it in the symbol table under "name."
 
 camera eye aim		- establishes a camera. Eye and aim are
-			  x y z triples.
+			  x y z triples, and can't be the same point.
 
 
 save filename		- save the image in its current state under
 			  the name "filename."
+			- .png, .ppm and .gif are written directly,
+			  other formats are converted with ImageMagick.
+			- in an animation, %d or %03d in filename is
+			  replaced by the frame number. Without it, the
+			  frame number is added before the extension.
 
 generate_rayfiles	- Instruct the interpreter to generate source
 			  files for a ray tracer for each frame rendered.
 
-focal value		- set the focal length of the camera
+focal value		- set the focal length of the camera, which
+			  must be > 0
 
 display			- display the current image on the screen
 
+
+screen width height [depth]
+			- set the size of the image. depth is the max
+			  value of a color channel, and defaults to 255.
+			  Lighting keeps the precision of the depth, so
+			  a depth above 255 writes 16 bit ppm and png files
+			  with more than 256 levels per channel.
+			- mdl --screen width height [depth] overrides this
+
+include :filename	- reads the commands of filename as if they
+			  were written in place of this line, so its
+			  constants, lights, knobs and defs can be used
+			  in the rest of the script. filename is relative
+			  to the file with the include.
+			- a file is only included the first time, and a
+			  file can't end up including itself.
+			- can't be in a block, and blocks in filename
+			  must end in it.
@@ -25,7 +25,11 @@ fn main() {
         }
     };
 
-    let mut magick = pipe_to_magick(vec!["-delay", &format!("{}", 6), "ppm:-", filename]);
+    let mut magick = pipe_to_magick(vec!["-delay", &format!("{}", 6), "ppm:-", filename])
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        });
 
     let lights_for_around: Vec<Light> = vec![
         Light::Ambient(RGB::new(100, 100, 50)),
@@ -89,7 +93,7 @@ fn main() {
         .with_lights(lights_for_around)
         .build();
 
-    for rot in (0..360).into_iter().step_by(2) {
+    for rot in (0..360).step_by(2) {
         // let mut stack: Vec<Matrix> = Vec::<Matrix>::new_stack();
 
         // moving to the center
@@ -234,6 +238,9 @@ fn main() {
     drawer.finish().expect("Error writing img data");
 
     println!("Waiting for convert/magick to exit...");
-    println!("convert/magick {}", wait_for_magick(magick));
+    match wait_for_magick(magick) {
+        Ok(status) => println!("convert/magick {}", status),
+        Err(e) => eprintln!("{}", e),
+    }
     println!("File name: {}", filename);
 }
@@ -1,16 +1,137 @@
-use std::{env, process};
+use std::{
+    env,
+    io::{self, IsTerminal},
+    path::Path,
+    process,
+    time::Duration,
+};
 
-use graphics::mdl::Interpreter;
+use graphics::mdl::{ast::Screen, format, repl::Repl, Backend, Interpreter};
+
+/// How often `--watch` looks at the files of the script
+const WATCH_INTERVAL: Duration = Duration::from_millis(300);
+
+const USAGE: &str =
+    "usage: mdl [--screen width height [depth]] [--backend raster|vector|test] [--watch] file.mdl
+       mdl repl [--screen width height [depth]]
+       mdl fmt [--check] file.mdl...";
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("provide a path to mdl file");
-        process::exit(1);
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "fmt") {
+        fmt(&args[1..]);
+    }
+    let repl = args.first().is_some_and(|arg| arg == "repl");
+    if repl {
+        args.remove(0);
+    }
+
+    let mut filename = None;
+    let mut screen = None;
+    let mut watch = false;
+    let mut backend = None;
+    let mut iter = args.iter().peekable();
+    while let Some(arg) = iter.next() {
+        if arg == "--screen" {
+            let mut size = || iter.next().and_then(|s| s.parse::<u32>().ok());
+            let (width, height) = match (size(), size()) {
+                (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+                _ => exit_with_usage("--screen needs a width and a height > 0"),
+            };
+            // depth is optional, so only take the next argument if it's a number
+            let depth = match iter.peek().map(|s| s.parse::<u16>()) {
+                Some(Ok(depth)) if depth > 0 => {
+                    iter.next();
+                    depth
+                }
+                _ => Screen::DEFAULT.depth,
+            };
+            let size = Screen {
+                width,
+                height,
+                depth,
+            };
+            if let Err(e) = size.check() {
+                exit_with_usage(&format!("--screen: {}", e));
+            }
+            screen = Some(size);
+        } else if arg == "--backend" && !repl {
+            backend = match iter.next().map(String::as_str) {
+                Some("raster") => Some(Backend::Raster),
+                Some("vector") => Some(Backend::Vector),
+                Some("test") => Some(Backend::Test),
+                _ => exit_with_usage("--backend needs raster, vector or test"),
+            };
+        } else if arg == "--watch" && !repl {
+            watch = true;
+        } else if filename.is_none() && !repl {
+            filename = Some(arg);
+        } else {
+            exit_with_usage(&format!("unexpected argument \"{}\"", arg));
+        }
+    }
+
+    if repl {
+        let stdin = io::stdin();
+        if stdin.is_terminal() {
+            println!("MDL repl, type :help for help");
+        }
+        Repl::new(screen.unwrap_or(Screen::DEFAULT))
+            .run(stdin.lock(), io::stdout())
+            .unwrap_or_else(|e| {
+                eprintln!("io error: {}", e);
+                process::exit(1);
+            });
+        return;
+    }
+
+    let filename = filename.unwrap_or_else(|| exit_with_usage("provide a path to mdl file"));
+    let mut interpreter = Interpreter::new(filename);
+    if let Some(screen) = screen {
+        interpreter = interpreter.with_screen(screen);
+    }
+    if let Some(backend) = backend {
+        interpreter = interpreter.with_backend(backend);
     }
 
-    Interpreter::new(&args[1]).run().unwrap_or_else(|e| {
+    if watch {
+        interpreter.watch(WATCH_INTERVAL);
+    }
+    interpreter.run().unwrap_or_else(|e| {
         eprintln!("engine error: {}", e);
         process::exit(1);
     });
 }
+
+/// Format every file in place, or with `--check` list the ones that aren't formatted and fail if there are any
+fn fmt(args: &[String]) -> ! {
+    let check = args.iter().any(|arg| arg == "--check");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if files.is_empty() {
+        exit_with_usage("provide a path to mdl file");
+    }
+    if let Some(flag) = files.iter().find(|arg| arg.starts_with("--")) {
+        exit_with_usage(&format!("unexpected argument \"{}\"", flag));
+    }
+
+    let mut failed = false;
+    for file in files {
+        match format::format_file(Path::new(file), check) {
+            Ok(true) if check => {
+                println!("{}", file);
+                failed = true;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                failed = true;
+            }
+        }
+    }
+    process::exit(if failed { 1 } else { 0 });
+}
+
+fn exit_with_usage(msg: &str) -> ! {
+    eprintln!("{}\n{}", msg, USAGE);
+    process::exit(1);
+}
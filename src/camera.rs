@@ -0,0 +1,244 @@
+//! Perspective camera set up by the `camera` and `focal` commands
+
+use crate::{
+    canvas::Vertex,
+    matrix::{projections, Matrix},
+    vector::Vec3,
+};
+
+/// Anything closer to the camera than this is clipped
+pub const NEAR: f64 = 1.;
+/// Far plane of the projection. Points farther than this are still drawn, but lose depth precision
+pub const FAR: f64 = 10_000.;
+/// Default focal length
+///
+/// With this focal length, a camera 500 units away sees a 500 by 500 area, just like the screen without a camera
+pub const DEFAULT_FOCAL: f64 = 1.;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Camera {
+    pub eye: Vec3,
+    pub aim: Vec3,
+    /// Focal length, relative to the height of the image
+    pub focal: f64,
+}
+
+impl Camera {
+    pub fn new(eye: Vec3, aim: Vec3, focal: f64) -> Self {
+        Self { eye, aim, focal }
+    }
+
+    /// Vertical field of view in radians
+    pub fn fov(&self) -> f64 {
+        2. * (0.5 / self.focal).atan()
+    }
+
+    /// Matrix that takes world coordinates to view coordinates
+    pub fn view_matrix(&self) -> Matrix {
+        let forward = (self.aim - self.eye).norm();
+        // +y is up, unless the camera is looking straight up or down
+        let up = if forward.cross(Vec3(0., 1., 0.)).mag() < 1e-9 {
+            Vec3(0., 0., -1.)
+        } else {
+            Vec3(0., 1., 0.)
+        };
+        projections::look_at(self.eye, self.aim, up)
+    }
+
+    /// Matrix that takes world coordinates to clip coordinates
+    pub fn clip_matrix(&self, aspect: f64) -> Matrix {
+        self.view_matrix() * projections::perspective(self.fov(), aspect, NEAR, FAR)
+    }
+
+    /// Project the edges of edge matrix `m` onto a `width` by `height` screen
+    ///
+    /// Edges are clipped against the near plane, so the result may have fewer edges than `m`
+    pub fn project_edges(&self, m: &Matrix, width: f64, height: f64) -> Matrix {
+        let mut clipped = Matrix::new_edge_matrix();
+        let clip = m * self.clip_matrix(width / height);
+
+        let mut iter = clip.iter_by_row();
+        while let (Some(p0), Some(p1)) = (iter.next(), iter.next()) {
+            let (mut p0, mut p1) = ([p0[0], p0[1], p0[2], p0[3]], [p1[0], p1[1], p1[2], p1[3]]);
+            let (d0, d1) = (near_distance(&p0), near_distance(&p1));
+            if d0 < 0. && d1 < 0. {
+                continue;
+            }
+            if d0 < 0. {
+                p0 = lerp4(&p0, &p1, d0 / (d0 - d1));
+            } else if d1 < 0. {
+                p1 = lerp4(&p1, &p0, d1 / (d1 - d0));
+            }
+            clipped.append_row(&mut p0.to_vec());
+            clipped.append_row(&mut p1.to_vec());
+        }
+
+        clipped.perspective_divide();
+        clipped.ndc_n1to1_to_device(width, height);
+        clipped
+    }
+
+    /// Project a triangle in world coordinates onto a `width` by `height` screen
+    ///
+    /// `clip_matrix` should come from `Camera::clip_matrix`. Only the `pos` of the vertices changes;
+    /// the parts of the triangle in front of the near plane may need up to two triangles.
+    pub(crate) fn project_triangle(
+        clip_matrix: &Matrix,
+        vertices: [Vertex; 3],
+        width: f64,
+        height: f64,
+    ) -> Vec<[Vertex; 3]> {
+        let mut world = Matrix::new_polygon_matrix();
+        world.append_polygon(
+            (vertices[0].pos.0, vertices[0].pos.1, vertices[0].pos.2),
+            (vertices[1].pos.0, vertices[1].pos.1, vertices[1].pos.2),
+            (vertices[2].pos.0, vertices[2].pos.1, vertices[2].pos.2),
+        );
+        let clip = world * clip_matrix;
+        let clip: Vec<[f64; 4]> = clip
+            .iter_by_row()
+            .map(|p| [p[0], p[1], p[2], p[3]])
+            .collect();
+
+        // Sutherland-Hodgman against the near plane only
+        let mut polygon: Vec<([f64; 4], Vertex)> = Vec::with_capacity(4);
+        for i in 0..3 {
+            let (a, b) = (i, (i + 1) % 3);
+            let (da, db) = (near_distance(&clip[a]), near_distance(&clip[b]));
+            if da >= 0. {
+                polygon.push((clip[a], vertices[a]));
+            }
+            if (da >= 0.) != (db >= 0.) {
+                let t = da / (da - db);
+                polygon.push((
+                    lerp4(&clip[a], &clip[b], t),
+                    vertices[a].lerp(&vertices[b], t),
+                ));
+            }
+        }
+        if polygon.len() < 3 {
+            return vec![];
+        }
+
+        let mut screen = Matrix::new_polygon_matrix();
+        for (p, _) in polygon.iter() {
+            screen.append_row(&mut p.to_vec());
+        }
+        screen.perspective_divide();
+        screen.ndc_n1to1_to_device(width, height);
+
+        let projected: Vec<Vertex> = screen
+            .iter_by_row()
+            .zip(polygon)
+            .map(|(p, (_, v))| Vertex {
+                pos: Vec3(p[0], p[1], p[2]),
+                ..v
+            })
+            .collect();
+
+        // the clipped polygon is convex, so a fan works
+        (1..projected.len() - 1)
+            .map(|i| [projected[0], projected[i], projected[i + 1]])
+            .collect()
+    }
+}
+
+/// Signed distance (in clip space) from the near plane, negative if the point should be clipped
+fn near_distance(p: &[f64; 4]) -> f64 {
+    p[2] + p[3]
+}
+
+fn lerp4(a: &[f64; 4], b: &[f64; 4], t: f64) -> [f64; 4] {
+    [
+        a[0] + (b[0] - a[0]) * t,
+        a[1] + (b[1] - a[1]) * t,
+        a[2] + (b[2] - a[2]) * t,
+        a[3] + (b[3] - a[3]) * t,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(x: f64, y: f64, z: f64) -> Vertex {
+        Vertex::new(Vec3(x, y, z), Vec3(0., 0., 1.))
+    }
+
+    #[test]
+    fn test_aim_is_at_the_center() {
+        let camera = Camera::new(Vec3(0., 0., 500.), Vec3(0., 0., 0.), DEFAULT_FOCAL);
+        let mut m = Matrix::new_edge_matrix();
+        m.append_edge(&[0., 0., 0., 250., 250., 0.]);
+        let screen = camera.project_edges(&m, 500., 500.);
+
+        let rows: Vec<&[f64]> = screen.iter_by_row().collect();
+        assert!((rows[0][0] - 250.).abs() < 1e-9 && (rows[0][1] - 250.).abs() < 1e-9);
+        // the default focal length shows 500 units at a distance of 500
+        assert!((rows[1][0] - 500.).abs() < 1e-6 && (rows[1][1] - 500.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_farther_is_smaller_and_deeper() {
+        let camera = Camera::new(Vec3(0., 0., 500.), Vec3(0., 0., 0.), DEFAULT_FOCAL);
+        let mut m = Matrix::new_edge_matrix();
+        m.append_edge(&[100., 0., 0., 100., 0., -500.]);
+        let screen = camera.project_edges(&m, 500., 500.);
+
+        let rows: Vec<&[f64]> = screen.iter_by_row().collect();
+        assert!(rows[0][0] > rows[1][0]);
+        assert!(
+            rows[0][2] > rows[1][2],
+            "closer points must have larger depth"
+        );
+    }
+
+    #[test]
+    fn test_triangle_clipped_by_near_plane() {
+        let camera = Camera::new(Vec3(0., 0., 0.), Vec3(0., 0., -1.), DEFAULT_FOCAL);
+        let clip_matrix = camera.clip_matrix(1.);
+
+        // fully in front
+        let tris = Camera::project_triangle(
+            &clip_matrix,
+            [
+                vertex(0., 0., -10.),
+                vertex(1., 0., -10.),
+                vertex(0., 1., -10.),
+            ],
+            500.,
+            500.,
+        );
+        assert_eq!(1, tris.len());
+
+        // fully behind
+        let tris = Camera::project_triangle(
+            &clip_matrix,
+            [
+                vertex(0., 0., 10.),
+                vertex(1., 0., 10.),
+                vertex(0., 1., 10.),
+            ],
+            500.,
+            500.,
+        );
+        assert!(tris.is_empty());
+
+        // one vertex behind the camera makes a quad, so two triangles
+        let tris = Camera::project_triangle(
+            &clip_matrix,
+            [
+                vertex(0., 0., 10.),
+                vertex(1., 0., -10.),
+                vertex(0., 1., -10.),
+            ],
+            500.,
+            500.,
+        );
+        assert_eq!(2, tris.len());
+        for v in tris.iter().flatten() {
+            assert!(v.pos.x().is_finite() && v.pos.y().is_finite());
+            assert!(v.pos.z() <= 1. + 1e-9);
+        }
+    }
+}
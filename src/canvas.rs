@@ -1,24 +1,81 @@
 // extern crate rand;
 // use rand::Rng;
 use crate::{
+    camera::Camera,
     light::Light,
-    light::{self, LightProps},
-    matrix::Matrix,
+    light::{self, LightProps, Shading},
+    matrix::{MalformedGeometry, Matrix},
     utils::{mapper, polar_to_xy},
     vector::Vec3,
     RGB,
 };
-use std::{io, process::ExitStatus};
+use std::{borrow::Cow, io, process::ExitStatus};
+
+pub mod svg;
+pub mod trace;
+
+/// A triangle vertex used by `Canvas::fill_triangle`
+#[derive(Copy, Clone, Debug)]
+pub struct Vertex {
+    /// Position on the screen, z is used for the depth buffer
+    pub pos: Vec3,
+    /// Normal used for lighting
+    pub normal: Vec3,
+    /// Position used for lighting
+    pub world: Vec3,
+    /// Lit color, only filled in for gouraud shading
+    pub color: Vec3,
+}
+
+impl Vertex {
+    /// Make a vertex where the screen position is also the position used for lighting
+    pub fn new(pos: Vec3, normal: Vec3) -> Self {
+        Self {
+            pos,
+            normal,
+            world: pos,
+            color: Vec3::ZEROS,
+        }
+    }
 
+    /// Linear interpolation of every field, `t` goes from 0 (`self`) to 1 (`other`)
+    pub fn lerp(&self, other: &Self, t: f64) -> Self {
+        let mix = |a: Vec3, b: Vec3| a + (b - a) * t;
+        Self {
+            pos: mix(self.pos, other.pos),
+            normal: mix(self.normal, other.normal),
+            world: mix(self.world, other.world),
+            color: mix(self.color, other.color),
+        }
+    }
+}
+
+/// Something to draw on
+///
+/// This can be used as a trait object, so the backend can be picked at runtime with `Box<dyn Canvas>`.
+/// Backends that don't work with pixels override the drawing methods, like `draw_line` and `fill_triangle`,
+/// instead of going through `plot`.
 pub trait Canvas {
     /// Plot a point on the screen at (`x`, `y`, `z`)
     fn plot(&mut self, x: i32, y: i32, z: f64, color: RGB);
 
+    /// Plot a lit color that hasn't been rounded yet, with each channel in [0, 255]
+    ///
+    /// Canvases with more than 8 bits per channel override this to keep the extra precision.
+    fn plot_shaded(&mut self, x: i32, y: i32, z: f64, color: Vec3) {
+        self.plot(x, y, z, RGB::from(color))
+    }
+
     // fn index(&self, x: i32, y: i32) -> Option<usize>;
     fn width(&self) -> u32;
     fn height(&self) -> u32;
     fn save(&self, filepath: &str) -> io::Result<ExitStatus>;
-    fn write_to_buf<T: io::Write>(&self, writer: &mut T) -> io::Result<()>;
+    fn write_to_buf(&self, writer: &mut dyn io::Write) -> io::Result<()>;
+
+    /// Colors of every pixel in [0, 255], in rows from top to bottom, if the canvas is made of pixels
+    fn raster(&self) -> Option<Cow<'_, [RGB]>> {
+        None
+    }
 
     /// Display the image with imagemagick
     fn display(&self);
@@ -157,14 +214,14 @@ pub trait Canvas {
 
     /// Draws an edge matrix
     ///
-    /// Number of edges must be a multiple of 2
+    /// A last point without the other end of its edge is skipped, `try_render_edge_matrix` reports it instead.
     fn render_edge_matrix(&mut self, m: &Matrix, color: RGB) {
         let mut iter = m.iter_by_row();
         while let Some(point) = iter.next() {
             let p0 = (point[0], point[1], point[2]);
             let p1 = match iter.next() {
                 Some(point1) => (point1[0], point1[1], point1[2]),
-                None => panic!("Number of edges must be a multiple of 2"),
+                None => break,
             };
 
             self.draw_line(p0, p1, color);
@@ -179,7 +236,7 @@ pub trait Canvas {
             let (x0, y0, z0) = (point[0], point[1], point[2]);
             let (x1, y1, z1) = match iter.next() {
                 Some(p1) => (p1[0], p1[1], p1[2]),
-                None => panic!("Number of edges must be a multiple of 2"),
+                None => break,
             };
 
             // we need to use inverse z to do depth buffer
@@ -191,24 +248,73 @@ pub trait Canvas {
         }
     }
 
-    /// Renders polygon matrix `m` onto screen.
+    /// Renders polygon matrix `m` onto screen with flat shading.
     ///
     /// Removes hidden surface with back-face culling
     /// Also draws scanlines
     fn render_polygon_matrix(&mut self, m: &Matrix, props: &LightProps, lights: &[Light]) {
-        // view vector for now: v = <0, 0, 1>, not needed for computation
+        self.render_shaded_polygon_matrix(m, None, Shading::Flat, props, lights, None);
+    }
+
+    /// Draws an edge matrix, or fails without drawing if its points don't make whole edges
+    fn try_render_edge_matrix(&mut self, m: &Matrix, color: RGB) -> Result<(), MalformedGeometry> {
+        m.check_edges()?;
+        self.render_edge_matrix(m, color);
+        Ok(())
+    }
+
+    /// Renders polygon matrix `m` with flat shading, or fails without drawing if its points don't make whole triangles
+    fn try_render_polygon_matrix(
+        &mut self,
+        m: &Matrix,
+        props: &LightProps,
+        lights: &[Light],
+    ) -> Result<(), MalformedGeometry> {
+        m.check_polygons()?;
+        self.render_polygon_matrix(m, props, lights);
+        Ok(())
+    }
+
+    /// Renders polygon matrix `m` onto screen with the given `shading` mode.
+    ///
+    /// `normals` holds one normal per vertex (row) of `m`, and is used for gouraud and phong shading.
+    /// If it is `None`, or a triangle has no normals, every vertex uses the surface normal of its triangle.
+    ///
+    /// Points after the last whole triangle are skipped.
+    ///
+    /// Without a `camera`, `m` is already in screen coordinates. With one, `m` is in world coordinates
+    /// and is projected through the camera after lighting.
+    fn render_shaded_polygon_matrix(
+        &mut self,
+        m: &Matrix,
+        normals: Option<&[Vec3]>,
+        shading: Shading,
+        props: &LightProps,
+        lights: &[Light],
+        camera: Option<&Camera>,
+    ) {
+        let (width, height) = (self.width() as f64, self.height() as f64);
+        let clip_matrix = camera.map(|camera| camera.clip_matrix(width / height));
+        // without a camera, the viewer is infinitely far away along +z
+        let view_from = |location: Vec3| match camera {
+            Some(camera) => camera.eye - location,
+            None => Vec3(0., 0., 1.),
+        };
 
         let mut iter = m.iter_by_row();
+        let mut index = 0;
         while let Some(point) = iter.next() {
             let p0 = (point[0], point[1], point[2]);
             let p1 = match iter.next() {
                 Some(point1) => (point1[0], point1[1], point1[2]),
-                None => panic!("Number of points must be a multiple of 2 for edge matrix"),
+                None => break,
             };
             let p2 = match iter.next() {
                 Some(point2) => (point2[0], point2[1], point2[2]),
-                None => panic!("Number of points must be a multiple of 3 for polygon matrix"),
+                None => break,
             };
+            let tri_index = index;
+            index += 3;
 
             // cull back face
             let v0 = Vec3::from_pt(p0);
@@ -216,101 +322,116 @@ pub trait Canvas {
             let v2 = Vec3::from_pt(p2);
 
             let surface_normal = (v1 - v0).cross(v2 - v0);
-            // let surface_normal = (v2 - v0).cross(v1 - v0);
-
-            // view vector is hard_coded for now
-            let viewvec = Vec3(0., 0., 1.);
 
-            if surface_normal * viewvec <= 0. {
+            if surface_normal * view_from(v0) <= 0. {
                 continue;
             }
 
-            let location = (v0 + v1 + v2) / 3.;
-
-            // self.draw_line(p0, p1);
-            // self.draw_line(p1, p2);
-            // self.draw_line(p2, p0);
-
-            // draw scanlines
-            {
-                // let mut rng = rand::thread_rng();
-                // self.set_fg_color(RGB::new(
-                //     rng.gen_range(0, 255),
-                //     rng.gen_range(0, 255),
-                //     rng.gen_range(0, 255),
-                // ));
-
-                let color = light::compute_color(&props, lights, surface_normal, viewvec, location);
-                // let color = RGB::WHITE;
-
-                // sort points by y value
-                let mut points = [v0, v1, v2];
-                points.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-                let [vb, vm, vt] = points;
-
-                if (vb.y() - vm.y()).abs() == 0. {
-                    // special: two bottom vertices on same horizontal line
-                    let d0: Vec3 = (vt - vb) / (vt.y() - vb.y());
-                    let d1: Vec3 = (vt - vm) / (vt.y() - vm.y());
-
-                    let yoffsetb = vb.y().ceil() - vb.y();
-                    let yoffsetm = vm.y().ceil() - vm.y();
-
-                    let mut x0 = vb.x() + yoffsetb * d0.x();
-                    let mut x1 = vm.x() + yoffsetm * d1.x();
-
-                    let mut z0 = vb.z() + yoffsetb * d0.z();
-                    let mut z1 = vm.z() + yoffsetm * d1.z();
-
-                    for y in (vb.y().ceil() as i64)..(vt.y().ceil() as i64) {
-                        self.draw_scanline((x0, y as f64, z0), (x1, y as f64, z1), color);
-
-                        x0 += d0.x();
-                        z0 += d0.z();
-
-                        x1 += d1.x();
-                        z1 += d1.z();
+            let (n0, n1, n2) =
+                match normals.and_then(|normals| normals.get(tri_index..tri_index + 3)) {
+                    Some(normals) => (normals[0], normals[1], normals[2]),
+                    None => (surface_normal, surface_normal, surface_normal),
+                };
+            let mut vertices = [
+                Vertex::new(v0, n0),
+                Vertex::new(v1, n1),
+                Vertex::new(v2, n2),
+            ];
+
+            let flat_color = match shading {
+                Shading::Wireframe | Shading::Flat => {
+                    let location = (v0 + v1 + v2) / 3.;
+                    light::compute_shade(
+                        props,
+                        lights,
+                        surface_normal,
+                        view_from(location),
+                        location,
+                    )
+                }
+                Shading::Gouraud => {
+                    // light each vertex, then let the scanlines blend the colors
+                    for v in vertices.iter_mut() {
+                        v.color = light::compute_shade(
+                            props,
+                            lights,
+                            v.normal,
+                            view_from(v.world),
+                            v.world,
+                        );
                     }
-                } else {
-                    // overall diff
-                    let dv: Vec3 = (vt - vb) / (vt.y() - vb.y());
-                    // bottom diff
-                    let dbottom: Vec3 = (vm - vb) / (vm.y() - vb.y());
-                    // top diff
-                    let dtop: Vec3 = (vt - vm) / (vt.y() - vm.y());
-
-                    let yoffsetb = vb.y().ceil() - vb.y();
-                    let yoffsetm = vm.y().ceil() - vm.y();
-
-                    let mut x0 = vb.x() + yoffsetb * dv.x();
-                    let mut x1 = vb.x() + yoffsetb * dbottom.x();
-                    let mut x2 = vm.x() + yoffsetm * dtop.x();
-
-                    let mut z0 = vb.z() + yoffsetb * dv.z();
-                    let mut z1 = vb.z() + yoffsetb * dbottom.z();
-                    let mut z2 = vm.z() + yoffsetm * dtop.z();
+                    Vec3::ZEROS
+                }
+                Shading::Phong => Vec3::ZEROS,
+            };
 
-                    // flat shading
-                    // let color = light.get_color_from_norm(surface_normal);
+            let triangles = match &clip_matrix {
+                Some(clip_matrix) => Camera::project_triangle(clip_matrix, vertices, width, height),
+                None => vec![vertices],
+            };
 
-                    for y in (vb.y().ceil() as i64)..(vm.y().ceil() as i64) {
-                        self.draw_scanline((x0, y as f64, z0), (x1, y as f64, z1), color);
+            for vertices in triangles {
+                match shading {
+                    Shading::Wireframe => {
+                        let [a, b, c] = vertices;
+                        let (a, b, c) = (
+                            (a.pos.0, a.pos.1, a.pos.2),
+                            (b.pos.0, b.pos.1, b.pos.2),
+                            (c.pos.0, c.pos.1, c.pos.2),
+                        );
+                        let color = RGB::from(flat_color);
+                        self.draw_line(a, b, color);
+                        self.draw_line(b, c, color);
+                        self.draw_line(c, a, color);
+                    }
+                    Shading::Flat => self.fill_triangle(vertices, &|_| flat_color),
+                    Shading::Gouraud => self.fill_triangle(vertices, &|v| v.color),
+                    Shading::Phong => self.fill_triangle(vertices, &|v| {
+                        light::compute_shade(props, lights, v.normal, view_from(v.world), v.world)
+                    }),
+                }
+            }
+        }
+    }
 
-                        x0 += dv.x();
-                        x1 += dbottom.x();
+    /// Fill a triangle with scanlines, coloring each pixel with `shade`.
+    ///
+    /// All fields of the vertices are interpolated, so `shade` gets the vertex data at that pixel.
+    /// `shade` returns an unrounded color, like `light::compute_shade`.
+    fn fill_triangle(&mut self, vertices: [Vertex; 3], shade: &dyn Fn(&Vertex) -> Vec3) {
+        // sort points by y value
+        let mut points = vertices;
+        // total_cmp, so a NaN from degenerate geometry can't stop the sort
+        points.sort_by(|a, b| a.pos.y().total_cmp(&b.pos.y()));
+        let [vb, vm, vt] = points;
+
+        // only walk the part of the triangle that is on the canvas
+        let (width, height) = (self.width() as f64, self.height() as f64);
+        let ystart = vb.pos.y().ceil().max(0.);
+        let yend = vt.pos.y().ceil().min(height);
+        for y in (ystart as i64)..(yend as i64) {
+            let yf = y as f64;
+            // the long edge goes from bottom to top, the other side changes at the middle vertex
+            let long = vb.lerp(&vt, (yf - vb.pos.y()) / (vt.pos.y() - vb.pos.y()));
+            let short = if yf < vm.pos.y() {
+                vb.lerp(&vm, (yf - vb.pos.y()) / (vm.pos.y() - vb.pos.y()))
+            } else {
+                vm.lerp(&vt, (yf - vm.pos.y()) / (vt.pos.y() - vm.pos.y()))
+            };
 
-                        z0 += dv.z();
-                        z1 += dbottom.z();
-                    }
-                    for y in (vm.y().ceil() as i64)..(vt.y().ceil() as i64) {
-                        self.draw_scanline((x0, y as f64, z0), (x2, y as f64, z2), color);
+            // swap variables if needed, since we are always going from left to right
+            let (left, right) = if long.pos.x() > short.pos.x() {
+                (short, long)
+            } else {
+                (long, short)
+            };
 
-                        x0 += dv.x();
-                        x2 += dtop.x();
-                        z0 += dv.z();
-                        z2 += dtop.z();
-                    }
-                }
+            let dx = right.pos.x() - left.pos.x();
+            let xstart = left.pos.x().ceil().max(0.);
+            let xend = right.pos.x().ceil().min(width);
+            for x in (xstart as i64)..(xend as i64) {
+                let v = left.lerp(&right, (x as f64 - left.pos.x()) / dx);
+                self.plot_shaded(x as i32, y as i32, v.pos.z(), shade(&v));
             }
         }
     }
@@ -331,7 +452,7 @@ pub trait Canvas {
         // calculate  values and then truncate
         // println!("x1 - x0: {} - {}", x1, x0);
 
-        let z_inc = (z1 - z0) / (x1 - x0) as f64;
+        let z_inc = (z1 - z0) / (x1 - x0);
 
         let mut z = z0 + xoffset * z_inc;
 
@@ -342,6 +463,75 @@ pub trait Canvas {
     }
 }
 
+/// A boxed canvas draws with the methods of the canvas inside, including the ones it overrides
+impl<C: Canvas + ?Sized> Canvas for Box<C> {
+    fn plot(&mut self, x: i32, y: i32, z: f64, color: RGB) {
+        (**self).plot(x, y, z, color)
+    }
+    fn plot_shaded(&mut self, x: i32, y: i32, z: f64, color: Vec3) {
+        (**self).plot_shaded(x, y, z, color)
+    }
+    fn width(&self) -> u32 {
+        (**self).width()
+    }
+    fn height(&self) -> u32 {
+        (**self).height()
+    }
+    fn save(&self, filepath: &str) -> io::Result<ExitStatus> {
+        (**self).save(filepath)
+    }
+    fn write_to_buf(&self, writer: &mut dyn io::Write) -> io::Result<()> {
+        (**self).write_to_buf(writer)
+    }
+    fn raster(&self) -> Option<Cow<'_, [RGB]>> {
+        (**self).raster()
+    }
+    fn display(&self) {
+        (**self).display()
+    }
+    fn clear(&mut self, color: RGB) {
+        (**self).clear(color)
+    }
+    fn draw_line(&mut self, p0: (f64, f64, f64), p1: (f64, f64, f64), color: RGB) {
+        (**self).draw_line(p0, p1, color)
+    }
+    fn draw_line_degrees(
+        &mut self,
+        point: (f64, f64, f64),
+        angle_degrees: f64,
+        mag: f64,
+        color: RGB,
+    ) -> (f64, f64, f64) {
+        (**self).draw_line_degrees(point, angle_degrees, mag, color)
+    }
+    fn render_edge_matrix(&mut self, m: &Matrix, color: RGB) {
+        (**self).render_edge_matrix(m, color)
+    }
+    fn render_ndc_edges_n1to1(&mut self, m: &Matrix, color: RGB) {
+        (**self).render_ndc_edges_n1to1(m, color)
+    }
+    fn render_polygon_matrix(&mut self, m: &Matrix, props: &LightProps, lights: &[Light]) {
+        (**self).render_polygon_matrix(m, props, lights)
+    }
+    fn render_shaded_polygon_matrix(
+        &mut self,
+        m: &Matrix,
+        normals: Option<&[Vec3]>,
+        shading: Shading,
+        props: &LightProps,
+        lights: &[Light],
+        camera: Option<&Camera>,
+    ) {
+        (**self).render_shaded_polygon_matrix(m, normals, shading, props, lights, camera)
+    }
+    fn fill_triangle(&mut self, vertices: [Vertex; 3], shade: &dyn Fn(&Vertex) -> Vec3) {
+        (**self).fill_triangle(vertices, shade)
+    }
+    fn draw_scanline(&mut self, p0: (f64, f64, f64), p1: (f64, f64, f64), color: RGB) {
+        (**self).draw_scanline(p0, p1, color)
+    }
+}
+
 /*
 #[cfg(test)]
 mod tests {
@@ -355,9 +545,9 @@ mod tests {
         let p1 = (100., 100., 100.);
         let p2 = (0., 50., 10.);
 
-        let (h, w, d) = (500, 500, 255);
-        let mut img_ln = PPMImg::new(h, w, d);
-        let mut img_polygon = PPMImg::new(h, w, d);
+        let (w, h, d) = (500, 500, 255);
+        let mut img_ln = PPMImg::new(w, h, d);
+        let mut img_polygon = PPMImg::new(w, h, d);
 
         let mut m = Matrix::new_polygon_matrix();
         m.append_polygon(p0, p1, p2);
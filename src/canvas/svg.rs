@@ -0,0 +1,222 @@
+//! Vector backend, which keeps lines and triangles as shapes and writes them as an svg
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    process::ExitStatus,
+};
+
+use super::{Canvas, Vertex};
+use crate::{utils, vector::Vec3, RGB};
+
+/// Canvas that writes an svg instead of pixels
+///
+/// There is no z-buffer, so shapes are drawn from the farthest to the closest by their average z.
+/// That's what a z-buffer would show, as long as shapes don't cross each other.
+pub struct SvgCanvas {
+    width: u32,
+    height: u32,
+    background: RGB,
+    shapes: Vec<Shape>,
+}
+
+struct Shape {
+    /// Average z of the points, larger is closer
+    z: f64,
+    color: RGB,
+    /// Points with the origin at the lower left corner, like the other canvases
+    points: Vec<(f64, f64)>,
+}
+
+impl SvgCanvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            background: RGB::BLACK,
+            shapes: vec![],
+        }
+    }
+
+    fn add(&mut self, points: &[(f64, f64, f64)], color: RGB) {
+        self.shapes.push(Shape {
+            z: points.iter().map(|p| p.2).sum::<f64>() / points.len() as f64,
+            color,
+            points: points.iter().map(|p| (p.0, p.1)).collect(),
+        });
+    }
+
+    /// Points of a shape in svg coordinates, where y goes down
+    fn svg_points(&self, shape: &Shape) -> String {
+        let points: Vec<String> = shape
+            .points
+            .iter()
+            .map(|(x, y)| format!("{:.2},{:.2}", x, self.height as f64 - y))
+            .collect();
+        points.join(" ")
+    }
+}
+
+fn svg_color(color: RGB) -> String {
+    format!(
+        "rgb({},{},{})",
+        color.red.min(255),
+        color.green.min(255),
+        color.blue.min(255)
+    )
+}
+
+impl Canvas for SvgCanvas {
+    /// A point is a square the size of a pixel
+    fn plot(&mut self, x: i32, y: i32, z: f64, color: RGB) {
+        let (x, y) = (x as f64, y as f64);
+        self.add(
+            &[
+                (x, y, z),
+                (x + 1., y, z),
+                (x + 1., y + 1., z),
+                (x, y + 1., z),
+            ],
+            color,
+        );
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Only svg files can be saved
+    fn save(&self, filepath: &str) -> io::Result<ExitStatus> {
+        let extension = Path::new(filepath)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+        if extension.as_deref() != Some("svg") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the vector backend can only save .svg files, not \"{}\"",
+                    filepath
+                ),
+            ));
+        }
+        let mut file = BufWriter::new(File::create(filepath)?);
+        self.write_to_buf(&mut file)?;
+        file.flush()?;
+        Ok(ExitStatus::default())
+    }
+
+    fn write_to_buf(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            writer,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+            w = self.width,
+            h = self.height
+        )?;
+        writeln!(
+            writer,
+            r#"<rect width="100%" height="100%" fill="{}"/>"#,
+            svg_color(self.background)
+        )?;
+
+        let mut shapes: Vec<&Shape> = self.shapes.iter().collect();
+        // stable, so shapes at the same depth keep the order they're drawn in
+        shapes.sort_by(|a, b| a.z.partial_cmp(&b.z).unwrap_or(std::cmp::Ordering::Equal));
+        for shape in shapes {
+            let color = svg_color(shape.color);
+            if shape.points.len() == 2 {
+                writeln!(
+                    writer,
+                    r#"<polyline points="{}" stroke="{}"/>"#,
+                    self.svg_points(shape),
+                    color
+                )?;
+            } else {
+                // the stroke covers the seams between triangles
+                writeln!(
+                    writer,
+                    r#"<polygon points="{}" fill="{c}" stroke="{c}" stroke-width="0.5"/>"#,
+                    self.svg_points(shape),
+                    c = color
+                )?;
+            }
+        }
+        writeln!(writer, "</svg>")
+    }
+
+    fn display(&self) {
+        utils::display_with("svg", |file| self.write_to_buf(file));
+    }
+
+    fn clear(&mut self, color: RGB) {
+        self.background = color;
+        self.shapes.clear();
+    }
+
+    fn draw_line(&mut self, p0: (f64, f64, f64), p1: (f64, f64, f64), color: RGB) {
+        self.add(&[p0, p1], color);
+    }
+
+    /// The whole triangle gets the color at its center
+    fn fill_triangle(&mut self, vertices: [Vertex; 3], shade: &dyn Fn(&Vertex) -> Vec3) {
+        let [a, b, c] = vertices;
+        let center = a.lerp(&b, 0.5).lerp(&c, 1. / 3.);
+        let points: Vec<(f64, f64, f64)> = vertices
+            .iter()
+            .map(|v| (v.pos.0, v.pos.1, v.pos.2))
+            .collect();
+        self.add(&points, RGB::from(shade(&center)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        light::{LightProps, Shading},
+        Matrix,
+    };
+
+    #[test]
+    fn test_closest_shape_is_drawn_last() {
+        let mut svg = SvgCanvas::new(100, 50);
+        let mut m = Matrix::new_polygon_matrix();
+        m.append_polygon((0., 0., 10.), (50., 0., 10.), (0., 50., 10.));
+        m.append_polygon((0., 0., -10.), (50., 0., -10.), (0., 50., -10.));
+        svg.render_shaded_polygon_matrix(
+            &m,
+            None,
+            Shading::Flat,
+            &LightProps::DEFAULT_PROPS,
+            &[],
+            None,
+        );
+        svg.draw_line((0., 0., 20.), (100., 50., 20.), RGB::WHITE);
+
+        let mut out = vec![];
+        svg.write_to_buf(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(6, lines.len());
+        assert!(lines[0].contains(r#"viewBox="0 0 100 50""#));
+        assert!(lines[2].starts_with(r#"<polygon points="0.00,50.00 50.00,50.00 0.00,0.00""#));
+        assert_eq!(
+            r#"<polyline points="0.00,50.00 100.00,0.00" stroke="rgb(255,255,255)"/>"#,
+            lines[4]
+        );
+        assert!(svg.save("image.png").is_err());
+
+        let dir = std::env::temp_dir().join(format!("svg-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("image.SVG");
+        let saved = svg
+            .save(&path.to_string_lossy())
+            .map(|_| std::fs::read(&path));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(out.as_bytes(), &saved.unwrap().unwrap()[..]);
+    }
+}
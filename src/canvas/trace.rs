@@ -0,0 +1,125 @@
+//! Test backend, which draws nothing and writes down what it's asked to draw
+
+use std::{
+    fs,
+    io::{self, Write},
+    process::ExitStatus,
+};
+
+use super::{Canvas, Vertex};
+use crate::{vector::Vec3, RGB};
+
+/// Canvas that keeps a line of text for every point, line and triangle it's given
+///
+/// This shows what a script draws without comparing pixels, and without needing ImageMagick.
+pub struct TraceCanvas {
+    width: u32,
+    height: u32,
+    pub lines: Vec<String>,
+}
+
+impl TraceCanvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            lines: vec![],
+        }
+    }
+}
+
+fn point(p: (f64, f64, f64)) -> String {
+    format!("({:.2} {:.2} {:.2})", p.0, p.1, p.2)
+}
+
+fn color(color: RGB) -> String {
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        color.red.min(255),
+        color.green.min(255),
+        color.blue.min(255)
+    )
+}
+
+impl Canvas for TraceCanvas {
+    fn plot(&mut self, x: i32, y: i32, z: f64, c: RGB) {
+        self.lines.push(format!(
+            "point {} {}",
+            point((x as f64, y as f64, z)),
+            color(c)
+        ));
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The trace is saved as text, whatever the extension
+    fn save(&self, filepath: &str) -> io::Result<ExitStatus> {
+        let mut trace = vec![];
+        self.write_to_buf(&mut trace)?;
+        fs::write(filepath, trace)?;
+        Ok(ExitStatus::default())
+    }
+
+    fn write_to_buf(&self, writer: &mut dyn Write) -> io::Result<()> {
+        for line in self.lines.iter() {
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    /// There's nothing to show
+    fn display(&self) {}
+
+    fn clear(&mut self, c: RGB) {
+        self.lines.push(format!("clear {}", color(c)));
+    }
+
+    fn draw_line(&mut self, p0: (f64, f64, f64), p1: (f64, f64, f64), c: RGB) {
+        self.lines
+            .push(format!("line {} {} {}", point(p0), point(p1), color(c)));
+    }
+
+    /// The color is the one at the center of the triangle
+    fn fill_triangle(&mut self, vertices: [Vertex; 3], shade: &dyn Fn(&Vertex) -> Vec3) {
+        let [a, b, c] = vertices;
+        let center = a.lerp(&b, 0.5).lerp(&c, 1. / 3.);
+        let [a, b, c] = [a, b, c].map(|v| point((v.pos.0, v.pos.1, v.pos.2)));
+        self.lines.push(format!(
+            "triangle {} {} {} {}",
+            a,
+            b,
+            c,
+            color(RGB::from(shade(&center)))
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{light::LightProps, Drawer, Matrix};
+
+    #[test]
+    fn test_trace_of_a_drawer() {
+        let mut drawer = Drawer::new(TraceCanvas::new(10, 10));
+        let mut m = Matrix::new_polygon_matrix();
+        m.append_polygon((0., 0., 0.), (4., 0., 0.), (0., 4., 0.));
+        drawer
+            .render_polygons_with_stack(&m, None, Some(&LightProps::DEFAULT_PROPS))
+            .unwrap();
+        drawer.draw_line((1., 2., 3.), (4., 5., 6.));
+
+        let lines = &drawer.canvas().lines;
+        assert_eq!(2, lines.len());
+        assert!(
+            lines[0].starts_with("triangle (0.00 0.00 0.00) (4.00 0.00 0.00) (0.00 4.00 0.00) #")
+        );
+        assert_eq!("line (1.00 2.00 3.00) (4.00 5.00 6.00) #ffffff", lines[1]);
+    }
+}
@@ -3,7 +3,7 @@
 use std::cmp;
 use std::convert;
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct RGB {
     pub red: u16,
     pub green: u16,
@@ -35,6 +35,19 @@ impl RGB {
     pub fn new(red: u16, green: u16, blue: u16) -> Self {
         RGB { red, green, blue }
     }
+
+    /// Scale every channel from [0, `from`] to [0, `to`], rounding to the nearest level
+    ///
+    /// Channels above `from` are clamped to it first.
+    pub fn scale(self, from: u16, to: u16) -> Self {
+        let (from, to) = (from as u32, to as u32);
+        let scale = |c: u16| {
+            ((c as u32).min(from) * to + from / 2)
+                .checked_div(from)
+                .unwrap_or(0) as u16
+        };
+        RGB::new(scale(self.red), scale(self.green), scale(self.blue))
+    }
 }
 
 /// Hue, Saturation, Luminosity
@@ -1,16 +1,22 @@
 use crate::{
-    light::{self, Light, LightProps},
-    matrix::Matrix,
+    camera::Camera,
+    light::{self, Light, LightProps, Shading},
+    matrix::{MalformedGeometry, Matrix},
+    vector::Vec3,
     Canvas, RGB,
 };
 use std::{
     io::{self, Cursor, Write},
     process::ExitStatus,
 };
+use thiserror::Error;
 
 pub mod turtle;
 /// A procedural interface to simplfy drawing
 pub struct Drawer<T: Canvas> {
+    /// Coordinate system shapes are drawn in, the top of the stack
+    top: Matrix,
+    /// Coordinate systems saved by `push_matrix`, under the top
     stack: Vec<Matrix>,
     canvas: T,
     /// Buffer written to when drawer.flush() is called
@@ -23,6 +29,10 @@ pub struct Drawer<T: Canvas> {
     ///
     /// If using custom lighting, clone this vec and append
     pub env_lights: Vec<Light>,
+    /// Shading mode used to render polygons
+    pub shading: Shading,
+    /// Camera to look through. Without one, x and y are drawn directly onto the screen
+    pub camera: Option<Camera>,
 }
 
 pub struct DrawerBuilder<T: Canvas> {
@@ -31,12 +41,13 @@ pub struct DrawerBuilder<T: Canvas> {
     bg_color: RGB,
     lights: Vec<Light>,
     writer: Box<dyn Write>,
+    shading: Shading,
 }
 
 impl<T: Canvas> DrawerBuilder<T> {
     /// Fill a drawer
     ///
-    /// Default `fg_color` is white, `bg_color` is black, `lights` is empty
+    /// Default `fg_color` is white, `bg_color` is black, `lights` is empty, `shading` is flat
     pub fn new(canvas: T) -> Self {
         Self {
             canvas,
@@ -44,6 +55,7 @@ impl<T: Canvas> DrawerBuilder<T> {
             bg_color: RGB::BLACK,
             lights: vec![],
             writer: Box::new(Cursor::new(Vec::new())),
+            shading: Shading::Flat,
         }
     }
 
@@ -72,9 +84,15 @@ impl<T: Canvas> DrawerBuilder<T> {
         self
     }
 
+    pub fn with_shading(mut self, shading: Shading) -> Self {
+        self.shading = shading;
+        self
+    }
+
     pub fn build(self) -> Drawer<T> {
         Drawer {
-            stack: new_stack(),
+            top: Matrix::ident(4),
+            stack: vec![],
             canvas: self.canvas,
             fg_color: self.fg_color,
             bg_color: self.bg_color,
@@ -85,27 +103,117 @@ impl<T: Canvas> DrawerBuilder<T> {
                 self.lights
             },
             writer: self.writer,
+            shading: self.shading,
+            camera: None,
         }
     }
 }
 
 // helpers
 impl<T: Canvas> Drawer<T> {
-    pub fn render_edges_with_stack(&mut self, m: &Matrix) {
-        self.canvas
-            .render_edge_matrix(&(m * self.get_top_matrix()), self.fg_color)
+    /// Render an edge matrix after transforming it by the top of the stack
+    pub fn render_edges_with_stack(&mut self, m: &Matrix) -> Result<(), MalformedGeometry> {
+        m.check_edges()?;
+        self.render_edges(m);
+        Ok(())
+    }
+
+    /// Render edges after transforming them by `coord` instead of the top of the stack
+    pub fn render_edges_with(
+        &mut self,
+        m: &Matrix,
+        coord: &Matrix,
+    ) -> Result<(), MalformedGeometry> {
+        m.check_edges()?;
+        self.render_transformed_edges(&(m * coord));
+        Ok(())
     }
 
-    pub fn render_polygons_with_stack(&mut self, m: &Matrix, props: Option<&LightProps>) {
+    /// Render edges the drawer made itself, which are always whole
+    fn render_edges(&mut self, m: &Matrix) {
+        self.render_transformed_edges(&(m * &self.top));
+    }
+
+    fn render_transformed_edges(&mut self, m: &Matrix) {
+        match &self.camera {
+            Some(camera) => {
+                let screen = camera.project_edges(
+                    m,
+                    self.canvas.width() as f64,
+                    self.canvas.height() as f64,
+                );
+                self.canvas.render_edge_matrix(&screen, self.fg_color)
+            }
+            None => self.canvas.render_edge_matrix(m, self.fg_color),
+        }
+    }
+
+    /// Render a polygon matrix after transforming it by the top of the stack
+    ///
+    /// `normals` has one normal per vertex (row) of `m` for gouraud and phong shading, like the normals of a mesh.
+    /// Without them, they are computed from the triangles.
+    pub fn render_polygons_with_stack(
+        &mut self,
+        m: &Matrix,
+        normals: Option<&[Vec3]>,
+        props: Option<&LightProps>,
+    ) -> Result<(), MalformedGeometry> {
+        m.check_polygons()?;
+        let coord = self.top.clone();
+        self.render_transformed_polygons(&(m * &coord), normals, &coord, props);
+        Ok(())
+    }
+
+    /// Render polygons after transforming them by `coord` instead of the top of the stack
+    pub fn render_polygons_with(
+        &mut self,
+        m: &Matrix,
+        normals: Option<&[Vec3]>,
+        props: Option<&LightProps>,
+        coord: &Matrix,
+    ) -> Result<(), MalformedGeometry> {
+        m.check_polygons()?;
+        self.render_transformed_polygons(&(m * coord), normals, coord, props);
+        Ok(())
+    }
+
+    /// Render polygons the drawer made itself, which are always whole triangles
+    fn render_polygons(&mut self, m: &Matrix, props: Option<&LightProps>) {
+        self.render_transformed_polygons(&(m * &self.top), None, &Matrix::ident(4), props);
+    }
+
+    /// Render polygons transformed by `coord`, and their normals from before the transformation
+    fn render_transformed_polygons(
+        &mut self,
+        m: &Matrix,
+        normals: Option<&[Vec3]>,
+        coord: &Matrix,
+        props: Option<&LightProps>,
+    ) {
         let props = props.unwrap_or(&LightProps::DEFAULT_PROPS);
-        self.canvas
-            .render_polygon_matrix(&(m * self.get_top_matrix()), props, &self.env_lights)
+        let normals = match (self.shading, normals) {
+            (Shading::Gouraud | Shading::Phong, Some(normals)) => Some(
+                normals
+                    .iter()
+                    .map(|normal| normal.transform_normal_by(coord))
+                    .collect(),
+            ),
+            // normals computed after transforming don't need to be transformed again
+            (Shading::Gouraud | Shading::Phong, None) => Some(m.vertex_normals()),
+            (Shading::Wireframe | Shading::Flat, _) => None,
+        };
+        self.canvas.render_shaded_polygon_matrix(
+            m,
+            normals.as_deref(),
+            self.shading,
+            props,
+            &self.env_lights,
+            self.camera.as_ref(),
+        )
     }
 
     pub fn get_top_matrix(&self) -> &Matrix {
-        self.stack
-            .last()
-            .expect("Error trying to get the last stack")
+        &self.top
     }
 }
 
@@ -123,7 +231,16 @@ impl<T: Canvas> Drawer<T> {
     }
 
     pub fn reset_stack(&mut self) {
-        self.stack = new_stack();
+        self.top = Matrix::ident(4);
+        self.stack.clear();
+    }
+
+    pub fn canvas(&self) -> &T {
+        &self.canvas
+    }
+
+    pub fn canvas_mut(&mut self) -> &mut T {
+        &mut self.canvas
     }
 
     pub fn save(&self, filepath: &str) -> io::Result<ExitStatus> {
@@ -156,33 +273,45 @@ impl<T: Canvas> Drawer<T> {
     pub fn draw_line(&mut self, p0: (f64, f64, f64), p1: (f64, f64, f64)) {
         let mut edges = Matrix::new_edge_matrix();
         edges.append_edge(&[p0.0, p0.1, p0.2, p1.0, p1.1, p1.2]);
-        self.render_edges_with_stack(&edges);
+        self.render_edges(&edges);
+    }
+    /// Draw a line where each endpoint is transformed by its own coordinate system
+    pub fn draw_line_with(
+        &mut self,
+        p0: (f64, f64, f64),
+        coord0: &Matrix,
+        p1: (f64, f64, f64),
+        coord1: &Matrix,
+    ) {
+        let Vec3(x0, y0, z0) = Vec3::from_pt(p0).transform_by(coord0);
+        let Vec3(x1, y1, z1) = Vec3::from_pt(p1).transform_by(coord1);
+        let mut edge = Matrix::new_edge_matrix();
+        edge.append_edge(&[x0, y0, z0, x1, y1, z1]);
+        self.render_transformed_edges(&edge);
     }
+
     pub fn draw_circle(&mut self, c: (f64, f64, f64), r: f64) {
         let mut edges = Matrix::new_edge_matrix();
         edges.add_circle(c, r);
-        self.render_edges_with_stack(&edges);
+        self.render_edges(&edges);
     }
 
     pub fn draw_hermite(&mut self, p0: (f64, f64), p1: (f64, f64), r0: (f64, f64), r1: (f64, f64)) {
         let mut edges = Matrix::new_edge_matrix();
         edges.add_hermite3(p0, p1, r0, r1);
-        self.render_edges_with_stack(&edges);
+        self.render_edges(&edges);
     }
     pub fn draw_bezier(&mut self, p0: (f64, f64), p1: (f64, f64), p2: (f64, f64), p3: (f64, f64)) {
         let mut edges = Matrix::new_edge_matrix();
         edges.add_bezier3(p0, p1, p2, p3);
-        self.render_edges_with_stack(&edges);
+        self.render_edges(&edges);
     }
 }
 
 // transformations
 impl<T: Canvas> Drawer<T> {
     pub fn transform_by(&mut self, trans: &Matrix) {
-        *self
-            .stack
-            .last_mut()
-            .expect("Error trying to get the last stack") = trans * self.get_top_matrix();
+        self.top = trans * &self.top;
     }
 }
 
@@ -198,12 +327,12 @@ impl<T: Canvas> Drawer<T> {
     ) {
         let mut m = Matrix::new_polygon_matrix();
         m.add_box((x, y, z), dx, dy, dz);
-        self.render_polygons_with_stack(&m, props);
+        self.render_polygons(&m, props);
     }
     pub fn add_sphere(&mut self, center: (f64, f64, f64), radius: f64, props: Option<&LightProps>) {
         let mut m = Matrix::new_polygon_matrix();
         m.add_sphere(center, radius);
-        self.render_polygons_with_stack(&m, props);
+        self.render_polygons(&m, props);
     }
     pub fn add_torus(
         &mut self,
@@ -214,32 +343,43 @@ impl<T: Canvas> Drawer<T> {
     ) {
         let mut m = Matrix::new_polygon_matrix();
         m.add_torus(center, r0, r1);
-        self.render_polygons_with_stack(&m, props);
+        self.render_polygons(&m, props);
     }
 }
 
 // coordinate stack related
 impl<T: Canvas> Drawer<T> {
     pub fn push_matrix(&mut self) {
-        self.stack.push(self.get_top_matrix().clone());
+        self.stack.push(self.top.clone());
     }
 
+    /// Go back to the coordinate system saved by the last push
+    ///
+    /// Does nothing if nothing was pushed, `try_pop_matrix` reports it instead.
     pub fn pop_matrix(&mut self) {
-        self.stack.pop();
+        if let Some(top) = self.stack.pop() {
+            self.top = top;
+        }
     }
-}
 
-/// Make a new matrix stack
-fn new_stack() -> Vec<Matrix> {
-    vec![Matrix::ident(4)]
+    /// Go back to the coordinate system saved by the last push, or fail if nothing was pushed
+    pub fn try_pop_matrix(&mut self) -> Result<(), StackUnderflow> {
+        self.top = self.stack.pop().ok_or(StackUnderflow)?;
+        Ok(())
+    }
 }
 
+/// A pop with nothing pushed, which would leave no coordinate system to draw in
+#[derive(Error, Debug, PartialEq)]
+#[error("pop with nothing pushed, so there's no coordinate system to go back to")]
+pub struct StackUnderflow;
+
 #[cfg(test)]
 mod tests {
     use crate::PPMImg;
 
     use super::*;
-    use crate::utils;
+    use crate::{matrix::transform, utils};
     #[test]
     fn test_line() {
         let mut img = PPMImg::new(500, 500, 255);
@@ -247,4 +387,82 @@ mod tests {
 
         utils::display_ppm(&img);
     }
+
+    #[test]
+    fn test_render_with_coord_matches_stack() {
+        let mut m = Matrix::new_polygon_matrix();
+        m.add_sphere((0., 0., 0.), 20.);
+        let coord = transform::mv(50., 50., 0.);
+
+        let mut with_stack = Drawer::new(PPMImg::new(100, 100, 255));
+        with_stack.transform_by(&coord);
+        with_stack
+            .render_polygons_with_stack(&m, None, None)
+            .unwrap();
+
+        let mut with_coord = Drawer::new(PPMImg::new(100, 100, 255));
+        with_coord
+            .render_polygons_with(&m, None, None, &coord)
+            .unwrap();
+
+        assert_ne!(PPMImg::new(100, 100, 255), with_coord.canvas);
+        assert_eq!(with_stack.canvas, with_coord.canvas);
+    }
+
+    #[test]
+    fn test_given_normals_are_transformed() {
+        let mut m = Matrix::new_polygon_matrix();
+        m.append_polygon((10., 10., 0.), (40., 10., 0.), (10., 40., 0.));
+        let render = |m: &Matrix, normals: Option<&[Vec3]>, coord: &Matrix| {
+            let mut drawer = DrawerBuilder::new(PPMImg::new(100, 100, 255))
+                .with_shading(Shading::Gouraud)
+                .build();
+            drawer
+                .render_polygons_with(m, normals, None, coord)
+                .unwrap();
+            drawer.canvas
+        };
+
+        let ident = Matrix::ident(4);
+        let tilted = [Vec3(1., 0., 1.); 3];
+        assert_ne!(render(&m, None, &ident), render(&m, Some(&tilted), &ident));
+
+        // stretching along x makes the surface steeper, so the normal leans less towards x
+        let stretch = transform::scale(2., 1., 1.);
+        assert_eq!(
+            render(&(&m * &stretch), Some(&[Vec3(1., 0., 2.); 3]), &ident),
+            render(&m, Some(&tilted), &stretch)
+        );
+    }
+
+    #[test]
+    fn test_bad_pops_and_matrices_are_errors() {
+        let mut drawer = Drawer::new(PPMImg::new(100, 100, 255));
+        drawer.push_matrix();
+        drawer.transform_by(&transform::mv(10., 0., 0.));
+        assert_eq!(Ok(()), drawer.try_pop_matrix());
+        assert_eq!(Err(StackUnderflow), drawer.try_pop_matrix());
+        drawer.pop_matrix();
+        assert_eq!(
+            Matrix::ident(4).to_string(),
+            drawer.get_top_matrix().to_string()
+        );
+
+        let mut m = Matrix::new_polygon_matrix();
+        m.append_polygon((0., 0., 0.), (50., 0., 0.), (0., 50., 0.));
+        m.append_row(&mut vec![0., 0., 0., 1.]);
+        assert_eq!(
+            Err(MalformedGeometry::Triangles(4)),
+            drawer.render_polygons_with_stack(&m, None, None)
+        );
+        assert_eq!(
+            Err(MalformedGeometry::Edges(1)),
+            drawer.render_edges_with_stack(&{
+                let mut edges = Matrix::new_edge_matrix();
+                edges.append_row(&mut vec![0., 0., 0., 1.]);
+                edges
+            })
+        );
+        assert_eq!(PPMImg::new(100, 100, 255), drawer.canvas);
+    }
 }
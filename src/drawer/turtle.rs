@@ -26,7 +26,7 @@ impl<T: Canvas> Turtle<T> {
     pub fn forward(&mut self, steps: i32) {
         let (x0, y0) = (self.x, self.y);
         let (dx, dy) = polar_to_xy(steps.into(), self.angle_deg);
-        let (x1, y1) = (x0 as f64 + dx, y0 as f64 + dy);
+        let (x1, y1) = (x0 + dx, y0 + dy);
         if self.pen_down {
             self.img
                 .draw_line((x0, y0, 0.), (x1, y1, 0.), self.fg_color);
@@ -0,0 +1,471 @@
+//! Animated GIF encoder
+//!
+//! Every frame gets its own palette of up to 255 colors, made with median cut.
+//! Only the part of a frame that changed from the last one is written, and pixels in that part
+//! that didn't change are left transparent, so still backgrounds cost almost nothing.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io::{self, Write},
+};
+
+use crate::{Canvas, PPMImg, RGB};
+
+/// Max number of colors in a palette, one more index is kept for transparency
+const MAX_COLORS: usize = 255;
+/// LZW codes can't be longer than 12 bits
+const MAX_CODES: u16 = 4096;
+
+/// Shortest frame delay in 1/100 of a second that browsers play as it is, shorter ones are shown for about 10
+pub const MIN_DELAY: u16 = 2;
+
+pub struct GifEncoder<W: Write> {
+    writer: W,
+    width: u16,
+    height: u16,
+    /// Pixels of the last frame, to find what changed
+    previous: Option<Vec<RGB>>,
+}
+
+impl<W: Write> GifEncoder<W> {
+    /// Start a gif of `width` by `height` that plays `loops` times after the first, 0 is forever
+    pub fn new(mut writer: W, width: u32, height: u32, loops: u16) -> io::Result<Self> {
+        let too_large = |_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "gif can't be larger than 65535 pixels",
+            )
+        };
+        let width = u16::try_from(width).map_err(too_large)?;
+        let height = u16::try_from(height).map_err(too_large)?;
+
+        writer.write_all(b"GIF89a")?;
+        // logical screen descriptor, without a global color table
+        writer.write_all(&width.to_le_bytes())?;
+        writer.write_all(&height.to_le_bytes())?;
+        writer.write_all(&[0, 0, 0])?;
+        // NETSCAPE2.0 application extension for looping
+        writer.write_all(&[0x21, 0xff, 0x0b])?;
+        writer.write_all(b"NETSCAPE2.0")?;
+        writer.write_all(&[0x03, 0x01])?;
+        writer.write_all(&loops.to_le_bytes())?;
+        writer.write_all(&[0x00])?;
+
+        Ok(Self {
+            writer,
+            width,
+            height,
+            previous: None,
+        })
+    }
+
+    /// Add a frame that stays on screen for `delay` hundredths of a second
+    ///
+    /// The image must be the same size as the gif.
+    pub fn add_frame(&mut self, img: &PPMImg, delay: u16) -> io::Result<()> {
+        if img.width() != self.width as u32 || img.height() != self.height as u32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame has a different size than the gif",
+            ));
+        }
+        self.add_pixels(&img.pixels_at(255), delay)
+    }
+
+    /// Add a frame from pixels in rows from top to bottom, with every channel in [0, 255]
+    pub fn add_pixels(&mut self, pixels: &[RGB], delay: u16) -> io::Result<()> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        assert_eq!(width * height, pixels.len(), "wrong number of pixels");
+        // colors are drawn in [0, 255], but can go over
+        let clamp = |c: &RGB| RGB::new(c.red.min(255), c.green.min(255), c.blue.min(255));
+        let pixels: Vec<RGB> = pixels.iter().map(clamp).collect();
+
+        let changed = |i: usize| match &self.previous {
+            Some(previous) => previous[i] != pixels[i],
+            None => true,
+        };
+
+        // bounding box of the pixels that changed
+        let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+        for y in 0..height {
+            for x in 0..width {
+                if changed(y * width + x) {
+                    left = left.min(x);
+                    right = right.max(x + 1);
+                    top = top.min(y);
+                    bottom = bottom.max(y + 1);
+                }
+            }
+        }
+        if left >= right {
+            // nothing changed, but the frame is still needed for its delay
+            left = 0;
+            top = 0;
+            right = 1;
+            bottom = 1;
+        }
+
+        let region: Vec<usize> = (top..bottom)
+            .flat_map(|y| (left..right).map(move |x| y * width + x))
+            .collect();
+
+        let mut histogram: HashMap<RGB, u32> = HashMap::new();
+        let mut has_transparent = false;
+        for &i in region.iter() {
+            if changed(i) {
+                *histogram.entry(pixels[i]).or_insert(0) += 1;
+            } else {
+                has_transparent = true;
+            }
+        }
+        let (palette, lookup) = median_cut(histogram, MAX_COLORS);
+        let transparent = palette.len() as u8;
+
+        let indices: Vec<u8> = region
+            .iter()
+            .map(|&i| {
+                if changed(i) {
+                    lookup[&pixels[i]]
+                } else {
+                    transparent
+                }
+            })
+            .collect();
+
+        // the color table holds 2^(size + 1) colors
+        let colors = palette.len() + has_transparent as usize;
+        let mut table_size = 0;
+        while (2 << table_size) < colors {
+            table_size += 1;
+        }
+
+        let w = &mut self.writer;
+        // graphic control extension, with disposal method 1 (leave the frame in place)
+        w.write_all(&[0x21, 0xf9, 0x04, (1 << 2) | has_transparent as u8])?;
+        w.write_all(&delay.to_le_bytes())?;
+        w.write_all(&[if has_transparent { transparent } else { 0 }, 0x00])?;
+
+        // image descriptor, with a local color table
+        w.write_all(&[0x2c])?;
+        for v in [left, top, right - left, bottom - top].iter() {
+            w.write_all(&(*v as u16).to_le_bytes())?;
+        }
+        w.write_all(&[0x80 | table_size])?;
+        for i in 0..(2 << table_size) {
+            let c = palette.get(i).copied().unwrap_or(RGB::BLACK);
+            w.write_all(&[c.red as u8, c.green as u8, c.blue as u8])?;
+        }
+
+        let min_code_size = (table_size + 1).max(2);
+        w.write_all(&[min_code_size])?;
+        let data = lzw_encode(&indices, min_code_size);
+        for block in data.chunks(255) {
+            w.write_all(&[block.len() as u8])?;
+            w.write_all(block)?;
+        }
+        w.write_all(&[0x00])?;
+
+        self.previous = Some(pixels);
+        Ok(())
+    }
+
+    /// Write the trailer and return the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(&[0x3b])?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reduce the colors in `histogram` to at most `max_colors`
+///
+/// Returns the palette, and the index into the palette of every color in `histogram`.
+fn median_cut(histogram: HashMap<RGB, u32>, max_colors: usize) -> (Vec<RGB>, HashMap<RGB, u8>) {
+    let mut boxes: Vec<Vec<(RGB, u32)>> = vec![histogram.into_iter().collect()];
+
+    while boxes.len() < max_colors {
+        // split the box with the widest channel
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| {
+                let (channel, range) = widest_channel(b);
+                (i, channel, range)
+            })
+            .max_by_key(|&(_, _, range)| range);
+        let (i, channel, _) = match widest {
+            Some(widest) => widest,
+            None => break,
+        };
+
+        let mut b = boxes.swap_remove(i);
+        b.sort_by_key(|(c, _)| channel_of(c, channel));
+        // split where half of the pixels are on each side
+        let total: u64 = b.iter().map(|(_, n)| *n as u64).sum();
+        let mut count = 0;
+        let mut split = 1;
+        for (j, (_, n)) in b.iter().enumerate() {
+            count += *n as u64;
+            if count * 2 >= total {
+                split = (j + 1).min(b.len() - 1).max(1);
+                break;
+            }
+        }
+        let rest = b.split_off(split);
+        boxes.push(b);
+        boxes.push(rest);
+    }
+
+    let mut palette = Vec::with_capacity(boxes.len());
+    let mut lookup = HashMap::new();
+    for (index, b) in boxes.iter().enumerate() {
+        let (mut r, mut g, mut bl, mut total) = (0u64, 0u64, 0u64, 0u64);
+        for (c, n) in b {
+            let n = *n as u64;
+            r += c.red as u64 * n;
+            g += c.green as u64 * n;
+            bl += c.blue as u64 * n;
+            total += n;
+            lookup.insert(*c, index as u8);
+        }
+        let total = total.max(1);
+        palette.push(RGB::new(
+            ((r + total / 2) / total) as u16,
+            ((g + total / 2) / total) as u16,
+            ((bl + total / 2) / total) as u16,
+        ));
+    }
+    (palette, lookup)
+}
+
+fn channel_of(c: &RGB, channel: usize) -> u16 {
+    match channel {
+        0 => c.red,
+        1 => c.green,
+        _ => c.blue,
+    }
+}
+
+/// The channel (0 for red, 1 for green, 2 for blue) with the widest range of values, and the range
+fn widest_channel(colors: &[(RGB, u32)]) -> (usize, u16) {
+    (0..3)
+        .map(|channel| {
+            let values = colors.iter().map(|(c, _)| channel_of(c, channel));
+            let min = values.clone().min().unwrap_or(0);
+            let max = values.max().unwrap_or(0);
+            (channel, max - min)
+        })
+        .max_by_key(|&(_, range)| range)
+        .unwrap()
+}
+
+/// Packs codes of varying length into bytes, least significant bit first
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.buffer |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Compress palette indices with the variable length LZW that gif uses
+fn lzw_encode(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+
+    let mut out = BitWriter {
+        bytes: vec![],
+        buffer: 0,
+        bits: 0,
+    };
+    // (prefix code, next index) -> code
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut size = min_code_size + 1;
+    let mut next = end + 1;
+    out.write(clear, size);
+
+    let mut iter = indices.iter();
+    let mut prefix = match iter.next() {
+        Some(&first) => first as u16,
+        None => {
+            out.write(end, size);
+            return out.finish();
+        }
+    };
+
+    for &k in iter {
+        if let Some(&code) = table.get(&(prefix, k)) {
+            prefix = code;
+            continue;
+        }
+        out.write(prefix, size);
+        table.insert((prefix, k), next);
+        next += 1;
+        // the decoder is one code behind, so the size goes up when `next` passes the largest code
+        if next > (1 << size) && size < 12 {
+            size += 1;
+        }
+        if next == MAX_CODES {
+            out.write(clear, size);
+            table.clear();
+            size = min_code_size + 1;
+            next = end + 1;
+        }
+        prefix = k as u16;
+    }
+
+    out.write(prefix, size);
+    // the decoder adds one more code after reading the last one, which may make the end code longer
+    if next == (1 << size) && size < 12 {
+        size += 1;
+    }
+    out.write(end, size);
+    out.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A plain gif LZW decoder to check the encoder against
+    fn lzw_decode(data: &[u8], min_code_size: u8) -> Vec<u8> {
+        let clear = 1u16 << min_code_size;
+        let end = clear + 1;
+        let mut table: Vec<Vec<u8>> = vec![];
+        let reset = |table: &mut Vec<Vec<u8>>| {
+            *table = (0..clear).map(|i| vec![i as u8]).collect();
+            table.push(vec![]);
+            table.push(vec![]);
+        };
+        reset(&mut table);
+
+        let mut size = min_code_size + 1;
+        let (mut pos, mut out) = (0usize, vec![]);
+        let mut previous: Option<Vec<u8>> = None;
+        loop {
+            let mut code = 0u16;
+            for bit in 0..size {
+                let byte = data[(pos + bit as usize) / 8];
+                code |= (((byte >> ((pos + bit as usize) % 8)) & 1) as u16) << bit;
+            }
+            pos += size as usize;
+
+            if code == clear {
+                reset(&mut table);
+                size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == end {
+                return out;
+            }
+            let entry = match (table.get(code as usize), &previous) {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(prev)) => {
+                    let mut e = prev.clone();
+                    e.push(prev[0]);
+                    e
+                }
+                (None, None) => panic!("bad code"),
+            };
+            out.extend(&entry);
+            if let Some(prev) = previous {
+                if table.len() < MAX_CODES as usize {
+                    let mut e = prev;
+                    e.push(entry[0]);
+                    table.push(e);
+                }
+            }
+            if table.len() == (1 << size) && size < 12 {
+                size += 1;
+            }
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn test_lzw_round_trip() {
+        // long enough to fill the code table and clear it a few times
+        let mut seed = 12345u32;
+        let noisy: Vec<u8> = (0..50_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8 % 7
+            })
+            .collect();
+        let runs: Vec<u8> = (0..20_000).map(|i| (i / 300) as u8 % 4).collect();
+
+        for (data, min_code_size) in [(noisy, 3), (runs, 2), (vec![1], 2), (vec![], 2)].iter() {
+            let encoded = lzw_encode(data, *min_code_size);
+            assert_eq!(*data, lzw_decode(&encoded, *min_code_size));
+        }
+    }
+
+    #[test]
+    fn test_median_cut_limits_colors() {
+        let mut histogram = HashMap::new();
+        for r in 0..32 {
+            for g in 0..32 {
+                histogram.insert(RGB::new(r * 8, g * 8, 100), 1);
+            }
+        }
+        let (palette, lookup) = median_cut(histogram, MAX_COLORS);
+        assert_eq!(MAX_COLORS, palette.len());
+        assert_eq!(32 * 32, lookup.len());
+
+        // few colors are kept exactly
+        let mut histogram = HashMap::new();
+        histogram.insert(RGB::new(1, 2, 3), 10);
+        histogram.insert(RGB::new(200, 100, 0), 1);
+        let (palette, lookup) = median_cut(histogram, MAX_COLORS);
+        assert_eq!(
+            RGB::new(200, 100, 0),
+            palette[lookup[&RGB::new(200, 100, 0)] as usize]
+        );
+        assert_eq!(
+            RGB::new(1, 2, 3),
+            palette[lookup[&RGB::new(1, 2, 3)] as usize]
+        );
+    }
+
+    #[test]
+    fn test_unchanged_frames_are_cropped() {
+        let mut img = PPMImg::new(8, 4, 255);
+        let mut gif = GifEncoder::new(vec![], 8, 4, 0).unwrap();
+        gif.add_frame(&img, 5).unwrap();
+        let first = gif.writer.len();
+
+        img.plot(6, 0, 0., RGB::WHITE);
+        gif.add_frame(&img, 5).unwrap();
+        let gif = gif.finish().unwrap();
+
+        assert_eq!(b"GIF89a", &gif[..6]);
+        assert_eq!(Some(&0x3b), gif.last());
+        // the second frame is a 1x1 image at the changed pixel, which is at the bottom of the image
+        let second = &gif[first..];
+        let descriptor = second.iter().position(|&b| b == 0x2c).unwrap();
+        assert_eq!(
+            &[6, 0, 3, 0, 1, 0, 1, 0],
+            &second[descriptor + 1..descriptor + 9]
+        );
+    }
+}
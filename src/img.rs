@@ -1,13 +1,23 @@
-use std::{convert::TryInto, process::ExitStatus};
+use std::{
+    borrow::Cow,
+    convert::{TryFrom, TryInto},
+    process::ExitStatus,
+};
 
 use std::{
     fmt::Debug,
+    fs::File,
     io::{self, prelude::Write},
+    path::Path,
 };
 // internal use
-use crate::{processes::pipe_to_magick, processes::wait_for_magick, utils, Canvas, RGB};
+use crate::{
+    gif::GifEncoder, png, processes::pipe_to_magick, processes::wait_for_magick, utils,
+    vector::Vec3, Canvas, RGB,
+};
 use io::BufWriter;
 
+#[derive(Clone)]
 pub struct PPMImg {
     height: u32,
     width: u32,
@@ -36,7 +46,7 @@ impl Debug for PPMImg {
         write!(
             f,
             "PPMImg {{ {} by {}, depth={} }}",
-            self.height, self.width, self.depth
+            self.width, self.height, self.depth
         )
     }
 }
@@ -45,11 +55,19 @@ impl Debug for PPMImg {
 impl PPMImg {
     /// Createa new PPMImg
     /// Default img is filled with black
-    pub fn new(height: u32, width: u32, depth: u16) -> PPMImg {
-        Self::with_bg(height, width, depth, RGB::gray(0))
+    ///
+    /// `depth` is the max value of a channel, up to 65535. Colors are drawn in the range [0, 255],
+    /// and are kept at the depth of the image, so lit colors get all of its precision.
+    /// A depth above 255 writes 16 bits per channel.
+    pub fn new(width: u32, height: u32, depth: u16) -> PPMImg {
+        Self::with_bg(width, height, depth, RGB::gray(0))
     }
 
-    pub fn with_bg(height: u32, width: u32, depth: u16, bg_color: RGB) -> PPMImg {
+    /// Panics if the pixels don't fit in memory, `mdl::ast::Screen::check` sees that first
+    pub fn with_bg(width: u32, height: u32, depth: u16, bg_color: RGB) -> PPMImg {
+        let bg_color = bg_color.scale(255, depth);
+        // a count that doesn't fit in a usize fails to allocate, like any other that's too large
+        let pixels = usize::try_from(u64::from(width) * u64::from(height)).unwrap_or(usize::MAX);
         PPMImg {
             height,
             width,
@@ -59,8 +77,32 @@ impl PPMImg {
             invert_y: false,
             // fg_color: RGB::gray(depth),
             // bg_color,
-            data: vec![bg_color; (width * height).try_into().unwrap()],
-            zbuf: vec![f64::NEG_INFINITY; (width * height).try_into().unwrap()],
+            data: vec![bg_color; pixels],
+            zbuf: vec![f64::NEG_INFINITY; pixels],
+        }
+    }
+
+    /// Colors of every pixel in [0, depth], in rows from top to bottom
+    pub fn pixels(&self) -> &[RGB] {
+        &self.data
+    }
+
+    /// Max value of a channel
+    pub fn depth(&self) -> u16 {
+        self.depth
+    }
+
+    /// Colors of every pixel scaled from [0, depth] to [0, `depth`]
+    pub(crate) fn pixels_at(&self, depth: u16) -> Cow<'_, [RGB]> {
+        if depth == self.depth {
+            Cow::Borrowed(&self.data)
+        } else {
+            Cow::Owned(
+                self.data
+                    .iter()
+                    .map(|c| c.scale(self.depth, depth))
+                    .collect(),
+            )
         }
     }
 
@@ -86,10 +128,17 @@ impl PPMImg {
         Ok(())
     }
     pub fn write_binary(&self, filepath: &str) -> io::Result<()> {
-        self.write_bin_to_buf(&mut utils::create_file(filepath))
+        self.write_bin_to_buf(&mut File::create(filepath)?)
+    }
+    /// Write a png, with 16 bits per channel if depth is above 255
+    pub fn write_png(&self, filepath: &str) -> io::Result<()> {
+        let file = BufWriter::new(File::create(filepath)?);
+        let sixteen_bit = self.depth > 255;
+        let pixels = self.pixels_at(if sixteen_bit { u16::MAX } else { 255 });
+        png::write_png(file, self.width, self.height, sixteen_bit, &pixels)
     }
     pub fn write_ascii(&self, filepath: &str) -> io::Result<()> {
-        let mut file = BufWriter::new(utils::create_file(filepath));
+        let mut file = BufWriter::new(utils::create_file(filepath)?);
         writeln!(file, "P3")?;
         writeln!(file, "{} {} {}", self.width, self.height, self.depth)?;
         for t in self.data.iter() {
@@ -101,6 +150,18 @@ impl PPMImg {
 }
 
 impl PPMImg {
+    /// Plot a color that is already at the depth of the image
+    fn plot_at_depth(&mut self, x: i32, y: i32, z: f64, color: RGB) {
+        // make the origin to be lower left corner
+        let y = self.height as i32 - 1 - y;
+        if let Some(index) = self.index(x, y) {
+            if self.zbuf[index] < z {
+                self.data[index] = color;
+                self.zbuf[index] = z;
+            }
+        }
+    }
+
     /// Returns Some(index) if index exists. Otherwise None.
     fn index(&self, x: i32, y: i32) -> Option<usize> {
         let (width, height) = (
@@ -138,7 +199,7 @@ impl PPMImg {
 
         // invert y based on config
         let y = if self.invert_y {
-            self.width as i32 - y - 1
+            self.height as i32 - y - 1
         } else {
             y
         };
@@ -153,14 +214,17 @@ impl Canvas for PPMImg {
     ///
     /// `z` is used for depth-buffer. Will only plot if `z` is closer to screen (new_z > existing_z).
     fn plot(&mut self, x: i32, y: i32, z: f64, color: RGB) {
-        // make the origin to be lower left corner
-        let y = self.height as i32 - 1 - y;
-        if let Some(index) = self.index(x, y) {
-            if self.zbuf[index] < z {
-                self.data[index] = color;
-                self.zbuf[index] = z;
-            }
-        }
+        let color = color.scale(255, self.depth);
+        self.plot_at_depth(x, y, z, color);
+    }
+
+    /// The color is scaled to the depth of the image before it's rounded
+    fn plot_shaded(&mut self, x: i32, y: i32, z: f64, color: Vec3) {
+        // exactly 1 for a depth of 255, and 257 for 65535
+        let scale = self.depth as f64 / 255.;
+        let channel = |c: f64| (c.clamp(0., 255.) * scale) as u16;
+        let color = RGB::new(channel(color.0), channel(color.1), channel(color.2));
+        self.plot_at_depth(x, y, z, color);
     }
     fn width(&self) -> u32 {
         self.width
@@ -170,39 +234,63 @@ impl Canvas for PPMImg {
     }
 
     fn display(&self) {
-        utils::display_ppm(&self);
+        utils::display_ppm(self);
     }
 
     /// Fill image with a certain color
     fn clear(&mut self, color: RGB) {
+        let color = color.scale(255, self.depth);
         // let bg = self.bg_color;
         for d in self.data.iter_mut() {
             *d = color;
         }
 
-        self.zbuf = vec![f64::NEG_INFINITY; (self.height * self.width).try_into().unwrap()];
+        self.zbuf = vec![f64::NEG_INFINITY; self.data.len()];
     }
 
+    /// Save the image, as a png, ppm or gif directly, or converted by magick for any other extension
+    ///
+    /// The exit status is always success when magick isn't needed.
     fn save(&self, filepath: &str) -> io::Result<ExitStatus> {
-        // // convert to .png if wanted
-        // if filepath.ends_with(".ppm") {
-        //     self.write_binary(filepath)
-        // } else {
-        let mut process = pipe_to_magick(vec!["ppm:-", filepath]);
+        let extension = Path::new(filepath)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("png") => return self.write_png(filepath).map(|_| ExitStatus::default()),
+            Some("ppm") => return self.write_binary(filepath).map(|_| ExitStatus::default()),
+            Some("gif") => {
+                let file = BufWriter::new(File::create(filepath)?);
+                let mut gif = GifEncoder::new(file, self.width, self.height, 0)?;
+                gif.add_frame(self, 0)?;
+                return gif.finish().map(|_| ExitStatus::default());
+            }
+            _ => {}
+        }
 
-        // This cmd should have a stdnin, so it's ok to unwrap
-        let mut stdin = process.stdin.take().unwrap();
-        self.write_bin_to_buf(&mut stdin)?;
+        let mut process = pipe_to_magick(vec!["ppm:-", filepath])?;
 
-        drop(stdin);
+        let written = match process.stdin.take() {
+            // stdin is dropped after writing, so magick sees the end of the image
+            Some(mut stdin) => self.write_bin_to_buf(&mut stdin),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "imagemagick has no stdin to write the image to",
+            )),
+        };
 
-        Ok(wait_for_magick(process))
-        // }
+        // always reap magick, even if writing to it failed, and prefer its failure,
+        // which is usually why the write failed
+        let status = wait_for_magick(process)?;
+        written.map(|_| status)
     }
 
-    fn write_to_buf<T: Write>(&self, writer: &mut T) -> io::Result<()> {
+    fn write_to_buf(&self, writer: &mut dyn Write) -> io::Result<()> {
         self.write_bin_to_buf(writer)
     }
+
+    fn raster(&self) -> Option<Cow<'_, [RGB]>> {
+        Some(self.pixels_at(255))
+    }
 }
 
 // this will stay here during trait refactor, since it has assumption about the internal data structure for Img
@@ -210,7 +298,8 @@ impl PPMImg {
     /// Fill an area in img with color calculated by `fill`,
     /// starting at (x, y) and ending when encounters bound color `bound`.
     ///
-    /// Note: This function uses the fact that PPMImg is stored as a `Vec` with an `index` method.
+    /// Note: This function uses the fact that PPMImg is stored as a `Vec` with an `index` method,
+    /// so `fill` and `bound` are colors at the depth of the image.
     pub fn bound4_fill_with_fn(
         &mut self,
         x: i32,
@@ -235,7 +324,66 @@ impl PPMImg {
                 points.push((x - 1, y));
                 points.push((x, y - 1));
             }
-            assert!(points.len() <= (self.width * self.height).try_into().unwrap());
+            assert!(points.len() <= self.data.len());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_square_16_bit() {
+        let mut img = PPMImg::new(3, 2, 65535);
+        assert_eq!((3, 2), (img.width(), img.height()));
+        // top right corner
+        img.plot(2, 1, 0., RGB::new(255, 0, 1));
+
+        let mut buf = vec![];
+        img.write_bin_to_buf(&mut buf).unwrap();
+        let header = b"P6\n3 2 65535\n";
+        assert_eq!(&header[..], &buf[..header.len()]);
+
+        let pixels = &buf[header.len()..];
+        assert_eq!(3 * 2 * 3 * 2, pixels.len());
+        // first row in the file is the top of the image
+        assert_eq!(&[0xff, 0xff, 0, 0, 0x01, 0x01], &pixels[12..18]);
+        assert!(pixels[..12].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_shades_keep_16_bit_precision() {
+        let mut img = PPMImg::new(2, 1, 65535);
+        img.plot_shaded(0, 0, 0., Vec3(100.5, 0.25, 255.));
+        img.plot_shaded(1, 0, 0., Vec3(100.75, 0., 0.));
+        assert_eq!(RGB::new(25828, 64, 65535), img.pixels()[0]);
+        // both would be 100 with 8 bits
+        assert_ne!(img.pixels()[0].red, img.pixels()[1].red);
+
+        // 8-bit colors are scaled to the depth exactly
+        img.plot(0, 0, 1., RGB::new(255, 1, 0));
+        assert_eq!(RGB::new(65535, 257, 0), img.pixels()[0]);
+        assert_eq!(RGB::new(255, 1, 0), img.raster().unwrap()[0]);
+    }
+
+    #[test]
+    fn test_extension_ignores_case() {
+        let dir = std::env::temp_dir().join(format!("img-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let img = PPMImg::new(2, 2, 255);
+        let saved: Vec<Vec<u8>> = ["a.PNG", "b.Ppm", "c.GIF"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                assert!(img.save(&path.to_string_lossy()).unwrap().success());
+                std::fs::read(path).unwrap()
+            })
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(saved[0].starts_with(b"\x89PNG"));
+        assert!(saved[1].starts_with(b"P6\n"));
+        assert!(saved[2].starts_with(b"GIF89a"));
+    }
+}
@@ -1,13 +1,16 @@
 #![allow(dead_code)]
 
+pub mod camera;
 pub mod canvas;
 pub mod colors;
 pub mod drawer;
+pub mod gif;
 pub mod img;
 pub mod light;
 pub mod matrix;
 pub mod mdl;
 pub mod parametrics;
+pub mod png;
 pub mod processes;
 pub mod utils;
 pub mod vector;
@@ -1,4 +1,4 @@
-use crate::{mdl::ast::ObjConst, vector::Vec3, Matrix, RGB};
+use crate::{vector::Vec3, Matrix, RGB};
 
 /// Represents lighting configuration
 #[derive(Copy, Clone, Debug)]
@@ -32,6 +32,19 @@ pub enum Light {
     },
 }
 
+/// How polygons are shaded when they are rendered
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Shading {
+    /// Only draw the edges of each triangle
+    Wireframe,
+    /// One color for each triangle
+    Flat,
+    /// Light each vertex and interpolate the colors
+    Gouraud,
+    /// Interpolate the normals and light each pixel
+    Phong,
+}
+
 /// aka Constants
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct LightProps {
@@ -49,23 +62,6 @@ pub struct LightProps {
     pub ns: f64,
 }
 
-impl From<ObjConst> for LightProps {
-    fn from(oc: ObjConst) -> Self {
-        Self {
-            ka: Vec3(oc.kar, oc.kag, oc.kab),
-            kd: Vec3(oc.kdr, oc.kdg, oc.kdb),
-            ks: Vec3(oc.ksr, oc.ksg, oc.ksb),
-            intensities: Vec3(
-                oc.ir.unwrap_or(0.),
-                oc.ig.unwrap_or(0.),
-                oc.ib.unwrap_or(0.),
-            ),
-            // Default value
-            ns: 10.,
-        }
-    }
-}
-
 pub fn compute_color(
     props: &LightProps,
     lights: &[Light],
@@ -73,6 +69,25 @@ pub fn compute_color(
     view_vec: Vec3,
     surface_location: Vec3,
 ) -> RGB {
+    RGB::from(compute_shade(
+        props,
+        lights,
+        surface_normal,
+        view_vec,
+        surface_location,
+    ))
+}
+
+/// Same as `compute_color`, but the color isn't rounded, so it keeps its precision for deep screens
+///
+/// Each channel is in [0, 255].
+pub fn compute_shade(
+    props: &LightProps,
+    lights: &[Light],
+    surface_normal: Vec3,
+    view_vec: Vec3,
+    surface_location: Vec3,
+) -> Vec3 {
     let mut color = Vec3(0., 0., 0.);
 
     let normaln = surface_normal.norm();
@@ -111,7 +126,7 @@ pub fn compute_color(
     }
 
     // TODO: possibely add support for light intensities here by multiplying
-    RGB::from(color)
+    color
 }
 
 pub fn default_lights() -> Vec<Light> {
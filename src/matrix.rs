@@ -6,6 +6,8 @@ use std::{
     ops::{Mul, MulAssign},
 };
 
+use thiserror::Error;
+
 // standalone
 pub mod projections;
 pub mod transform;
@@ -13,6 +15,7 @@ pub mod transform;
 // impl on Matrix
 pub mod dim2;
 pub mod dim3;
+pub mod obj;
 pub mod parametrics;
 // pub mod mstack;
 
@@ -115,6 +118,34 @@ impl Matrix {
     }
 }
 
+/// A matrix whose points can't be split into whole shapes
+#[derive(Error, Debug, PartialEq)]
+pub enum MalformedGeometry {
+    #[error("{0} points don't make whole edges, which have 2 points each")]
+    Edges(usize),
+    #[error("{0} points don't make whole triangles, which have 3 points each")]
+    Triangles(usize),
+}
+
+// shape checks
+impl Matrix {
+    /// Check that every point is in an edge, so the matrix can be drawn as an edge matrix
+    pub fn check_edges(&self) -> Result<(), MalformedGeometry> {
+        match self.nrows % 2 {
+            0 => Ok(()),
+            _ => Err(MalformedGeometry::Edges(self.nrows)),
+        }
+    }
+
+    /// Check that every point is in a triangle, so the matrix can be drawn as a polygon matrix
+    pub fn check_polygons(&self) -> Result<(), MalformedGeometry> {
+        match self.nrows % 3 {
+            0 => Ok(()),
+            _ => Err(MalformedGeometry::Triangles(self.nrows)),
+        }
+    }
+}
+
 // mul
 impl Matrix {
     /// Returns (x, y) of a matrix based on ncols and i
@@ -194,7 +225,7 @@ impl MulAssign for Matrix {
 
 impl MulAssign<&Matrix> for Matrix {
     fn mul_assign(&mut self, rhs: &Matrix) {
-        *self = self._mul(&rhs)
+        *self = self._mul(rhs)
     }
 }
 
@@ -272,8 +303,7 @@ mod tests {
         let mut m = Matrix::new(0, 4, vec![]);
         println!("m: {}", m);
         println!("Adding (1, 2, 4) and (5, 6, 7) to empty matrix",);
-        m.append_edge(&mut vec![1.0, 2.0, 4.0]);
-        m.append_edge(&mut vec![5.0, 6.0, 7.0]);
+        m.append_edge(&[1.0, 2.0, 4.0, 5.0, 6.0, 7.0]);
         println!("m: {}", m);
         assert!(
             matrix_equal(
@@ -1,7 +1,11 @@
 //! Implements fn that add shapes to a vertex matrix
 
 use super::Matrix;
-use std::f64::consts::PI;
+use crate::vector::Vec3;
+use std::{collections::HashMap, f64::consts::PI};
+
+/// Faces that meet at a larger angle (in degrees) than this are not smoothed together by `vertex_normals`
+const CREASE_ANGLE: f64 = 60.;
 
 // constructor
 impl Matrix {
@@ -195,6 +199,68 @@ impl Matrix {
     }
 }
 
+// normals
+impl Matrix {
+    /// Compute a normal for every vertex (row) of a polygon matrix, used for gouraud and phong shading.
+    ///
+    /// The normal of a vertex is the average of the surface normals of the triangles that share its position.
+    /// Triangles that meet at an angle larger than `CREASE_ANGLE` are not averaged, so the edges of a box stay sharp
+    /// while spheres and tori look smooth.
+    pub fn vertex_normals(&self) -> Vec<Vec3> {
+        // points of different triangles at the same spot can be off by a tiny bit after transformations
+        let key = |row: &[f64]| {
+            (
+                (row[0] * 1e4).round() as i64,
+                (row[1] * 1e4).round() as i64,
+                (row[2] * 1e4).round() as i64,
+            )
+        };
+
+        let surface_normals: Vec<Vec3> = self
+            .data
+            .chunks_exact(self.ncols * 3)
+            .map(|tri| {
+                let v0 = Vec3(tri[0], tri[1], tri[2]);
+                let v1 = Vec3(tri[self.ncols], tri[self.ncols + 1], tri[self.ncols + 2]);
+                let v2 = Vec3(
+                    tri[self.ncols * 2],
+                    tri[self.ncols * 2 + 1],
+                    tri[self.ncols * 2 + 2],
+                );
+                (v1 - v0).cross(v2 - v0)
+            })
+            .collect();
+
+        let mut triangles_at: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+        for (i, row) in self.iter_by_row().enumerate() {
+            triangles_at.entry(key(row)).or_default().push(i / 3);
+        }
+
+        let min_cos = CREASE_ANGLE.to_radians().cos();
+        self.iter_by_row()
+            .enumerate()
+            .map(|(i, row)| {
+                let own = surface_normals[i / 3];
+                // degenerate triangles (like the ones at the poles of a sphere) have no direction of their own,
+                // so they take the average of all their neighbors
+                let degenerate = own.mag() == 0.;
+                let ownn = own.norm();
+                // summing unnormalized normals weights each triangle by its area
+                let sum = triangles_at[&key(row)]
+                    .iter()
+                    .map(|&t| surface_normals[t])
+                    .filter(|n| degenerate || n.norm().dot(ownn) >= min_cos)
+                    .fold(Vec3::ZEROS, |sum, n| sum + n);
+                if sum.mag() > 0. {
+                    sum.norm()
+                } else {
+                    own
+                }
+            })
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -211,7 +277,7 @@ mod tests {
 
     #[test]
     fn test_no_degenerate_triangles_in_sphere() {
-        for radius in (0..1000).into_iter().step_by(100) {
+        for radius in (0..1000).step_by(100) {
             let mut m = Matrix::new_polygon_matrix();
             m.add_sphere((0., 0., 0.), radius as f64 + 0.3);
 
@@ -227,6 +293,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sphere_vertex_normals_point_outward() {
+        let mut m = Matrix::new_polygon_matrix();
+        m.add_sphere((10., 20., 30.), 50.);
+        let normals = m.vertex_normals();
+        assert_eq!(m.nrows, normals.len());
+
+        for (row, normal) in m.iter_by_row().zip(normals) {
+            let outward = (Vec3(row[0], row[1], row[2]) - Vec3(10., 20., 30.)).norm();
+            assert!(normal.dot(outward) > 0.99);
+        }
+    }
+
+    #[test]
+    fn test_box_vertex_normals_stay_sharp() {
+        let mut m = Matrix::new_polygon_matrix();
+        m.add_box((0., 0., 0.), 10., 10., 10.);
+        let normals = m.vertex_normals();
+
+        for (i, tri) in m.data.chunks_exact(m.ncols * 3).enumerate() {
+            let v0 = Vec3(tri[0], tri[1], tri[2]);
+            let v1 = Vec3(tri[4], tri[5], tri[6]);
+            let v2 = Vec3(tri[8], tri[9], tri[10]);
+            let surface = (v1 - v0).cross(v2 - v0).norm();
+            for normal in &normals[i * 3..i * 3 + 3] {
+                assert!(normal.dot(surface) > 0.99);
+            }
+        }
+    }
+
     #[test]
     fn draw_torus() {
         let mut m = Matrix::new_edge_matrix();
@@ -0,0 +1,339 @@
+//! Wavefront OBJ loader
+//!
+//! Supports `v`, `vn`, `vt`, and `f` records. Other records (`o`, `g`, `s`, `usemtl`, ...) are ignored.
+
+use std::{fs, io, path::Path, str::FromStr};
+
+use thiserror::Error;
+
+use super::Matrix;
+use crate::vector::Vec3;
+
+/// Errors from reading an OBJ file. `line` is the line in the OBJ file, starting from 1
+#[derive(Error, Debug)]
+pub enum ObjError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {line}: `{record}` needs at least {min} values")]
+    TooFewValues {
+        line: usize,
+        record: &'static str,
+        min: usize,
+    },
+    #[error("line {line}: \"{found}\" is not a number")]
+    NotANumber { line: usize, found: String },
+    #[error("line {line}: \"{found}\" is not a face vertex, expected v, v/vt, v//vn, or v/vt/vn")]
+    BadFaceVertex { line: usize, found: String },
+    #[error("line {line}: {what} index {index} is out of range, only {len} defined so far")]
+    IndexOutOfRange {
+        line: usize,
+        what: &'static str,
+        index: i64,
+        len: usize,
+    },
+}
+
+/// One corner of a face, with 0-based indices into the lists of `Obj`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FaceVertex {
+    pub v: usize,
+    pub vt: Option<usize>,
+    pub vn: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Obj {
+    pub vertices: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub texcoords: Vec<(f64, f64)>,
+    /// Faces as they are in the file, with any number of vertices
+    pub faces: Vec<Vec<FaceVertex>>,
+}
+
+impl Obj {
+    /// Read and parse the OBJ file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ObjError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse the content of an OBJ file
+    pub fn parse(src: &str) -> Result<Self, ObjError> {
+        let mut obj = Obj::default();
+
+        for (i, text) in src.lines().enumerate() {
+            let line = i + 1;
+            // everything after # is a comment
+            let text = text.split('#').next().unwrap_or("");
+            let mut words = text.split_whitespace();
+            let record = match words.next() {
+                Some(record) => record,
+                None => continue,
+            };
+            let values: Vec<&str> = words.collect();
+
+            match record {
+                "v" => {
+                    let [x, y, z] = floats::<3>(&values, line, "v")?;
+                    obj.vertices.push(Vec3(x, y, z));
+                }
+                "vn" => {
+                    let [x, y, z] = floats::<3>(&values, line, "vn")?;
+                    obj.normals.push(Vec3(x, y, z));
+                }
+                "vt" => {
+                    // v is optional, and w is ignored
+                    let u = floats::<1>(&values, line, "vt")?[0];
+                    let v = match values.get(1) {
+                        Some(v) => float(v, line)?,
+                        None => 0.,
+                    };
+                    obj.texcoords.push((u, v));
+                }
+                "f" => {
+                    if values.len() < 3 {
+                        return Err(ObjError::TooFewValues {
+                            line,
+                            record: "f",
+                            min: 3,
+                        });
+                    }
+                    let face = values
+                        .iter()
+                        .map(|value| obj.face_vertex(value, line))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    obj.faces.push(face);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(obj)
+    }
+
+    /// Iterate over the faces, split into triangles
+    ///
+    /// Faces are split as fans from their first vertex, so n-gons have to be convex.
+    pub fn triangles(&self) -> impl Iterator<Item = [FaceVertex; 3]> + '_ {
+        self.faces
+            .iter()
+            .flat_map(|face| (1..face.len() - 1).map(move |i| [face[0], face[i], face[i + 1]]))
+    }
+
+    /// Parse `v`, `v/vt`, `v//vn`, or `v/vt/vn`
+    fn face_vertex(&self, value: &str, line: usize) -> Result<FaceVertex, ObjError> {
+        let bad = || ObjError::BadFaceVertex {
+            line,
+            found: value.to_owned(),
+        };
+
+        let mut parts = value.split('/');
+        let v = parts.next().filter(|s| !s.is_empty()).ok_or_else(bad)?;
+        let vt = parts.next().filter(|s| !s.is_empty());
+        let vn = parts.next().filter(|s| !s.is_empty());
+        if parts.next().is_some() {
+            return Err(bad());
+        }
+
+        let index = |s: &str, what: &'static str, len: usize| -> Result<usize, ObjError> {
+            let index = i64::from_str(s).map_err(|_| bad())?;
+            resolve_index(index, len).ok_or(ObjError::IndexOutOfRange {
+                line,
+                what,
+                index,
+                len,
+            })
+        };
+
+        Ok(FaceVertex {
+            v: index(v, "vertex", self.vertices.len())?,
+            vt: vt
+                .map(|vt| index(vt, "texture coordinate", self.texcoords.len()))
+                .transpose()?,
+            vn: vn
+                .map(|vn| index(vn, "normal", self.normals.len()))
+                .transpose()?,
+        })
+    }
+}
+
+/// Turn a 1-based index, or a negative index counting back from the end, into a 0-based index
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let resolved = if index < 0 {
+        len as i64 + index
+    } else {
+        index - 1
+    };
+    if (0..len as i64).contains(&resolved) {
+        Some(resolved as usize)
+    } else {
+        None
+    }
+}
+
+fn float(value: &str, line: usize) -> Result<f64, ObjError> {
+    f64::from_str(value).map_err(|_| ObjError::NotANumber {
+        line,
+        found: value.to_owned(),
+    })
+}
+
+/// Parse the first `N` values as floats
+fn floats<const N: usize>(
+    values: &[&str],
+    line: usize,
+    record: &'static str,
+) -> Result<[f64; N], ObjError> {
+    if values.len() < N {
+        return Err(ObjError::TooFewValues {
+            line,
+            record,
+            min: N,
+        });
+    }
+    let mut result = [0.; N];
+    for (slot, value) in result.iter_mut().zip(values) {
+        *slot = float(value, line)?;
+    }
+    Ok(result)
+}
+
+impl Matrix {
+    /// Add every face of `obj` to the polygon matrix, and return the normals of the vertices added
+    ///
+    /// OBJ faces are counter-clockwise when seen from the front, which is the order `append_polygon` wants.
+    ///
+    /// Vertices without a `vn` get the normal of their triangle. If no vertex has one,
+    /// there are no normals, and they are computed from the triangles when the mesh is drawn.
+    pub fn add_mesh(&mut self, obj: &Obj) -> Option<Vec<Vec3>> {
+        let mut normals = vec![];
+        for [a, b, c] in obj.triangles() {
+            let [va, vb, vc] = [a, b, c].map(|fv| obj.vertices[fv.v]);
+            let face_normal = (vb - va).cross(vc - va);
+            normals.extend([a, b, c].map(|fv| fv.vn.map_or(face_normal, |vn| obj.normals[vn])));
+            let point = |Vec3(x, y, z)| (x, y, z);
+            self.append_polygon(point(va), point(vb), point(vc));
+        }
+        let has_normals = obj.faces.iter().flatten().any(|fv| fv.vn.is_some());
+        if has_normals {
+            Some(normals)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quad_with_negative_indices() {
+        let obj = Obj::parse(
+            "# a square
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vn 0 0 1
+f -4/1/1 -3/1/1 -2//1 -1
+",
+        )
+        .unwrap();
+
+        assert_eq!(4, obj.vertices.len());
+        assert_eq!(
+            vec![
+                FaceVertex {
+                    v: 0,
+                    vt: Some(0),
+                    vn: Some(0)
+                },
+                FaceVertex {
+                    v: 1,
+                    vt: Some(0),
+                    vn: Some(0)
+                },
+                FaceVertex {
+                    v: 2,
+                    vt: None,
+                    vn: Some(0)
+                },
+                FaceVertex {
+                    v: 3,
+                    vt: None,
+                    vn: None
+                },
+            ],
+            obj.faces[0]
+        );
+
+        let mut m = Matrix::new_polygon_matrix();
+        let normals = m.add_mesh(&obj).unwrap();
+        assert_eq!(6, m.iter_by_row().count());
+        assert_eq!(6, normals.len());
+
+        // both triangles face +z, so they are not culled
+        let rows: Vec<&[f64]> = m.iter_by_row().collect();
+        for tri in rows.chunks(3) {
+            let v = |r: &[f64]| Vec3(r[0], r[1], r[2]);
+            let normal = (v(tri[1]) - v(tri[0])).cross(v(tri[2]) - v(tri[0]));
+            assert!(normal.z() > 0.);
+        }
+    }
+
+    #[test]
+    fn test_mesh_normals() {
+        let obj =
+            Obj::parse("v 0 0 0\nv 2 0 0\nv 0 2 0\nv 0 0 2\nvn 1 1 1\nf 1//1 2//1 3\nf 1 2 4\n")
+                .unwrap();
+        let mut m = Matrix::new_polygon_matrix();
+        let normals = m.add_mesh(&obj).unwrap();
+        // the vertex without a vn gets the normal of its face, which isn't normalized
+        assert_eq!(
+            vec![Vec3(1., 1., 1.), Vec3(1., 1., 1.), Vec3(0., 0., 4.)],
+            normals[..3]
+        );
+        assert_eq!(vec![Vec3(0., -4., 0.); 3], normals[3..]);
+
+        let obj = Obj::parse("v 0 0 0\nv 2 0 0\nv 0 2 0\nf 1 2 3\n").unwrap();
+        assert_eq!(None, Matrix::new_polygon_matrix().add_mesh(&obj));
+    }
+
+    #[test]
+    fn test_pentagon_is_fanned() {
+        let obj =
+            Obj::parse("v 0 0 0\nv 2 0 0\nv 3 1 0\nv 1 2 0\nv -1 1 0\nf 1 2 3 4 5\n").unwrap();
+        let triangles: Vec<[usize; 3]> = obj.triangles().map(|[a, b, c]| [a.v, b.v, c.v]).collect();
+        assert_eq!(vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]], triangles);
+    }
+
+    #[test]
+    fn test_errors_have_line_numbers() {
+        match Obj::parse("v 0 0 0\nv 1 0 0\nf 1 2 3\n") {
+            Err(ObjError::IndexOutOfRange {
+                line: 3,
+                index: 3,
+                len: 2,
+                ..
+            }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Obj::parse("v 0 0\n") {
+            Err(ObjError::TooFewValues { line: 1, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Obj::parse("\nv 0 zero 0\n") {
+            Err(ObjError::NotANumber { line: 2, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Obj::parse("v 0 0 0\nf 1 1 1/2/3/4\n") {
+            Err(ObjError::BadFaceVertex { line: 2, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Obj::parse("v 0 0 0\nf 0 1 1\n") {
+            Err(ObjError::IndexOutOfRange { index: 0, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
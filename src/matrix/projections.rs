@@ -1,34 +1,44 @@
-use crate::{matrix::Matrix, utils::mapper};
+use crate::{matrix::Matrix, utils::mapper, vector::Vec3};
 
 // https://developer.mozilla.org/en-US/docs/Web/API/WebGL_API/WebGL_model_view_projection#Perspective_matrix
 
 /// Construct a perspective projection matrix based
 /// ## Arguments:
-///        fov_rad - Field of view - the angle in radians of what's in view along the Y axis
-///        aspect - Aspect Ratio - the ratio of the canvas, typically width / height
-///        near - Anything before this point in the Z direction gets clipped (outside of the clip space)
-///        far - Anything after this point in the Z direction gets clipped (outside of the clip space)
+/// - `fov_rad` - Field of view - the angle in radians of what's in view along the Y axis
+/// - `aspect` - Aspect Ratio - the ratio of the canvas, typically width / height
+/// - `near` - Anything before this point in the Z direction gets clipped (outside of the clip space)
+/// - `far` - Anything after this point in the Z direction gets clipped (outside of the clip space)
 ///
+/// The camera looks down -z, so after this points in front of it have w > 0.
+/// Points have to be clipped against the near plane (z > -w) before `perspective_divide`.
 #[rustfmt::skip]
 pub fn perspective(fov_rad: f64, aspect: f64, near: f64, far: f64) -> Matrix {
-
-    
     let f = 1. / (fov_rad / 2.).tan();
     let range_inv = 1. / (near - far);
-    // Matrix::new(4, 4, vec![
-        //     f / aspect, 0., 0.,                             0.,
-        //     0.,         f,  0.,                             0.,
-        //     0.,         0., (near + far) * range_inv,       -1.,
-        //     0.,         0., near * far * range_inv * 2.,    0.,
-        // ]);
-        Matrix::new(4, 4, vec![
-            f / aspect, 0.,      0.,                             0.,
-            0.,         f,       0.,                             0.,
-        0.,         0.,      (near + far) * range_inv,       near * far * range_inv * 2.,
-        0.,         0.,      -1.,                            0.,
-    ]);
+    // row-major, so this is the transpose of the matrix on MDN
+    Matrix::new(4, 4, vec![
+        f / aspect, 0., 0.,                             0.,
+        0.,         f,  0.,                             0.,
+        0.,         0., (near + far) * range_inv,       -1.,
+        0.,         0., near * far * range_inv * 2.,    0.,
+    ])
+}
 
-    todo!("Impl clipping");
+/// Construct a view matrix for a camera at `eye` looking at `aim`
+///
+/// In view space the camera is at the origin looking down -z, with `up` pointing towards +y.
+/// `up` must not be parallel to `aim - eye`.
+#[rustfmt::skip]
+pub fn look_at(eye: Vec3, aim: Vec3, up: Vec3) -> Matrix {
+    let forward = (aim - eye).norm();
+    let right = forward.cross(up).norm();
+    let up = right.cross(forward);
+    Matrix::new(4, 4, vec![
+        right.0,            up.0,           -forward.0,         0.,
+        right.1,            up.1,           -forward.1,         0.,
+        right.2,            up.2,           -forward.2,         0.,
+        -right.dot(eye),    -up.dot(eye),   forward.dot(eye),   1.,
+    ])
 }
 
 /// Construct an orthographic projection matrix
@@ -53,13 +63,16 @@ pub fn orthographic(left: f64, right: f64, bottom: f64, top: f64, near: f64, far
 impl Matrix {
     //
     /// This should be used only after perspective divide and before rendered onto the canvas
+    ///
+    /// z is flipped, so that closer points have larger values like the depth buffer expects
     pub fn ndc_n1to1_to_device(&mut self, width: f64, height: f64) {
         let map_width = mapper(-1., 1., 0., width);
         let map_height = mapper(-1., 1., 0., height);
 
         for row in self.mut_iter_by_row() {
-            row[0] = map_width(-row[0]);
+            row[0] = map_width(row[0]);
             row[1] = map_height(row[1]);
+            row[2] = -row[2];
         }
     }
 }
@@ -90,4 +103,29 @@ mod tests {
 
         display_edge_matrix(&model, true, fg_color);
     }
+
+    #[test]
+    fn test_look_at_puts_aim_in_front() {
+        let view = look_at(Vec3(0., 0., 500.), Vec3(0., 0., 0.), Vec3(0., 1., 0.));
+        assert_eq!(Vec3(0., 0., -500.), Vec3(0., 0., 0.).transform_by(&view));
+        assert_eq!(
+            Vec3(10., 20., -500.),
+            Vec3(10., 20., 0.).transform_by(&view)
+        );
+    }
+
+    #[test]
+    fn test_perspective_near_far_to_ndc() {
+        let proj = perspective(std::f64::consts::FRAC_PI_2, 1., 1., 100.);
+        // transform_by also does the perspective divide
+        let near = Vec3(0., 0., -1.).transform_by(&proj);
+        let far = Vec3(0., 0., -100.).transform_by(&proj);
+        assert!((near.z() + 1.).abs() < 1e-9);
+        assert!((far.z() - 1.).abs() < 1e-9);
+
+        // farther points are smaller
+        let close = Vec3(1., 1., -2.).transform_by(&proj);
+        let away = Vec3(1., 1., -4.).transform_by(&proj);
+        assert!(close.x() > away.x() && close.y() > away.y());
+    }
 }
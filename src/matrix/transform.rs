@@ -1,6 +1,7 @@
+//! Note: all matrices here are row-major (transposed compared to what we have from classes)
+//! since my engine uses row-major point matrices
+
 use super::Matrix;
-///! Note: all matrices here are row-major (transposed compared to what we have from classes)
-///! since my engine uses row-major point matrices
 
 // generate transformation matrices
 /// Generate a translation matrix with (dx, dy, dz)
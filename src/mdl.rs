@@ -1,107 +1,205 @@
-#![allow(dead_code, unused_variables)]
-
 pub mod ast;
+mod check;
+pub mod diagnostics;
 pub mod exec;
+pub mod expr;
+pub mod format;
+mod frames;
+mod knobs;
 pub mod parser;
+pub mod repl;
 pub mod result;
 pub mod types;
 mod utils;
+mod watch;
 
-use std::{path::Path, path::PathBuf};
+use std::{
+    borrow::Cow, fs::File, io::BufWriter, path::Path, path::PathBuf, thread, time::Duration,
+};
 
 use indicatif::{ProgressBar, ProgressStyle};
 
 use crate::{
-    drawer::DrawerBuilder,
-    light::LightProps,
-    processes::{pipe_to_magick, wait_for_magick},
-    utils as gfxutils, PPMImg,
+    canvas::{svg::SvgCanvas, trace::TraceCanvas},
+    drawer::{Drawer, DrawerBuilder},
+    gif::{self, GifEncoder},
+    light::{Light, LightProps},
+    utils as gfxutils, Canvas, PPMImg,
 };
 
 use self::{
-    ast::{Command, VaryInfo},
-    exec::{exec_no_animation, exec_once_with_animation},
-    parser::{parse_file, SymTable},
-    result::EngineResult,
+    ast::{Command, FrameOutput, Loc, Screen, VaryInfo},
+    diagnostics::Warning,
+    exec::{exec_no_animation, Meshes, Output},
+    frames::Animation,
+    parser::{parse_file, parse_script, SymTable},
+    result::{EngineError, EngineResult},
+    watch::Watcher,
 };
 
-/// MDL Interpreter for a single file
+/// Gif frame delay in 1/100 of a second, when there is no `fps`
+const DEFAULT_DELAY: u16 = gif::MIN_DELAY;
+
+/// MDL Interpreter for a single script
 pub struct Interpreter {
-    filename: PathBuf,
+    script: Script,
+    /// Overrides the `screen` command of the script
+    screen: Option<Screen>,
+    backend: Backend,
+}
+
+/// What `Interpreter::run` draws on
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// Pixels, saved as png, ppm or gif, or converted by magick to anything else
+    Raster,
+    /// Shapes, saved as svg
+    Vector,
+    /// A text trace of what's drawn, for tests
+    Test,
+}
+
+impl Backend {
+    /// New canvas of this backend
+    pub fn canvas(self, screen: Screen) -> Box<dyn Canvas> {
+        match self {
+            Backend::Raster => Box::new(PPMImg::new(screen.width, screen.height, screen.depth)),
+            Backend::Vector => Box::new(SvgCanvas::new(screen.width, screen.height)),
+            Backend::Test => Box::new(TraceCanvas::new(screen.width, screen.height)),
+        }
+    }
+}
+
+/// Where the script comes from
+enum Script {
+    File(PathBuf),
+    /// Text of the script, which includes files relative to the working directory
+    Source(String),
+}
+
+/// A script that's parsed and checked, ready to be rendered
+pub struct Program {
+    context: ExecContext,
+    warnings: Vec<Warning>,
+    /// Overrides the `screen` command of the script
+    screen: Option<Screen>,
+}
+
+/// A script rendered in memory
+#[derive(Debug)]
+pub enum Render {
+    Still(PPMImg),
+    /// Every frame of an animation, in order
+    Frames(Vec<PPMImg>),
 }
 
 /// Config for interpreter to exec script
 pub enum ExecContext {
     Animation {
-        script: Vec<String>,
-        cmd_list: Vec<(usize, Command)>,
+        cmd_list: Vec<(Loc, Command)>,
         basename: String,
         frames: u32,
-        vary_list: Vec<(usize, VaryInfo)>,
+        vary_list: Vec<(Loc, VaryInfo)>,
+        /// Knob values set by `set` and `setknobs`, before `vary` and `tween` are applied
+        base_knobs: SymTable<f64>,
+        fps: Option<f64>,
+        /// Number of times the gif loops, 0 is forever
+        loops: u32,
+        output: FrameOutput,
         light_props: SymTable<LightProps>,
+        meshes: Meshes,
+        env_lights: Vec<Light>,
+        screen: Screen,
     },
     NoAnimation {
-        script: Vec<String>,
-        cmd_list: Vec<(usize, Command)>,
-        basename: String,
+        cmd_list: Vec<(Loc, Command)>,
+        knobs: SymTable<f64>,
         light_props: SymTable<LightProps>,
+        meshes: Meshes,
+        env_lights: Vec<Light>,
+        screen: Screen,
     },
 }
 
 impl Interpreter {
     pub fn new<T: AsRef<Path>>(path: T) -> Self {
         Self {
-            filename: path.as_ref().to_path_buf(),
+            script: Script::File(path.as_ref().to_path_buf()),
+            screen: None,
+            backend: Backend::Raster,
         }
     }
 
+    /// Interpreter for the text of a script
+    pub fn from_source(source: &str) -> Self {
+        Self {
+            script: Script::Source(source.to_owned()),
+            screen: None,
+            backend: Backend::Raster,
+        }
+    }
+
+    /// Render at this size no matter what the script says
+    pub fn with_screen(mut self, screen: Screen) -> Self {
+        self.screen = Some(screen);
+        self
+    }
+
+    /// Draw on this backend in `run`, instead of the raster one
+    ///
+    /// `render` always draws pixels.
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
     pub fn run(&self) -> EngineResult<()> {
         let pgbar = ProgressBar::new_spinner().with_style(gfxutils::shark_spinner_style());
         pgbar.set_message("Parsing file");
         pgbar.enable_steady_tick(120);
 
-        match parse_file(&self.filename)? {
+        let Program {
+            context, warnings, ..
+        } = self.parse()?;
+        // not `pgbar.println`, which prints nothing when stderr isn't a terminal
+        for warning in warnings {
+            eprintln!("{}", warning);
+        }
+
+        match context {
             ExecContext::Animation {
                 cmd_list,
                 basename,
                 frames,
                 vary_list,
-                script,
+                base_knobs,
+                fps,
+                loops,
+                output,
                 light_props,
+                meshes,
+                env_lights,
+                screen,
             } => {
+                // before any frame is rendered or the gif is created
+                if output == FrameOutput::Gif && self.backend != Backend::Raster {
+                    return Err(EngineError::NoPixels);
+                }
                 pgbar.set_message("Computing animation knobs");
                 // second pass, compute all knob values for each frame
-                let mut knob_states: Vec<SymTable<f64>> = vec![];
-
-                for cur_frame in 0..frames {
-                    let mut table: SymTable<f64> = SymTable::new();
-                    for (line, v) in vary_list.iter() {
-                        if v.start_frame <= cur_frame && cur_frame <= v.end_frame {
-                            let val = (v.end_val - v.start_val)
-                                / (v.end_frame as f64 - v.start_frame as f64)
-                                * (cur_frame - v.start_frame) as f64
-                                + v.start_val;
-
-                            // override previous vary command if they overlap in frame numbers
-                            if table.insert(v.knob.to_owned(), val).is_some() {
-                                eprintln!(
-                                    "Vary commands cannot overlap. Using vary on line {}: {}",
-                                    line,
-                                    script.get(*line).unwrap().as_str()
-                                );
-                            }
-                        }
-                    }
-                    knob_states.push(table);
-                }
+                let knob_states = knobs::knob_states(frames, &base_knobs, &vary_list);
 
-                let fout_name = format!("{}.gif", basename);
-
-                let mut magick = pipe_to_magick(vec!["-delay", "1.7", "ppm:-", &fout_name]);
-                let writer = magick.stdin.take().unwrap();
-                let mut drawer = DrawerBuilder::new(PPMImg::new(500, 500, 255))
-                    .with_writer(Box::new(writer))
-                    .build();
+                let animation = Animation {
+                    cmd_list: &cmd_list,
+                    knob_states: &knob_states,
+                    light_props: &light_props,
+                    meshes: &meshes,
+                    env_lights: &env_lights,
+                    screen: self.screen.unwrap_or(screen),
+                    basename: &basename,
+                    output: &output,
+                    in_memory: false,
+                };
 
                 pgbar.finish_and_clear();
                 let render_pg = ProgressBar::new(knob_states.len() as u64).with_style(
@@ -111,50 +209,315 @@ impl Interpreter {
                 );
                 render_pg.set_message("Rendering frames");
 
-                for knob_state in knob_states.iter() {
-                    exec_once_with_animation(
-                        &cmd_list,
-                        &script,
-                        knob_state,
-                        &mut drawer,
-                        &light_props,
-                    )?;
-                    drawer.flush()?;
-                    drawer.reset_stack();
-                    drawer.clear();
-                    render_pg.inc(1);
+                match &output {
+                    FrameOutput::Gif => {
+                        let gif_name = format!("{}.gif", basename);
+                        utils::write_atomically(Path::new(&gif_name), |partial| {
+                            let mut gif = GifEncoder::new(
+                                BufWriter::new(File::create(partial)?),
+                                animation.screen.width,
+                                animation.screen.height,
+                                loops.min(u16::MAX as u32) as u16,
+                            )?;
+                            let delay = gif_delay(fps);
+                            animation.render(
+                                |screen| self.backend.canvas(screen),
+                                |canvas| {
+                                    canvas
+                                        .raster()
+                                        .map(Cow::into_owned)
+                                        .ok_or(EngineError::NoPixels)
+                                },
+                                |pixels| Ok(gif.add_pixels(&pixels, delay)?),
+                                || render_pg.inc(1),
+                            )?;
+                            gif.finish()?;
+                            Ok(())
+                        })?;
+                        render_pg.finish_and_clear();
+                        println!("Done. Animation saved as \"{}\"", gif_name);
+                    }
+                    FrameOutput::Files { extension } => {
+                        animation.render(
+                            |screen| self.backend.canvas(screen),
+                            |_| Ok(()),
+                            |()| Ok(()),
+                            || render_pg.inc(1),
+                        )?;
+                        render_pg.finish_and_clear();
+                        println!(
+                            "Done. {} frames saved as \"{}\" and so on",
+                            frames,
+                            utils::frame_path(&format!("{}.{}", basename, extension), 0, frames)
+                        );
+                    }
                 }
-
-                drawer.finish()?;
-
-                render_pg.finish_and_clear();
-                let magick_pg = ProgressBar::new_spinner()
-                    .with_style(ProgressStyle::default_spinner().template(""));
-                magick_pg.enable_steady_tick(120);
-                // magick_pg.tick();
-
-                magick_pg.set_style(gfxutils::shark_spinner_style());
-                magick_pg.set_message("Waiting for magick gif generation");
-                let status = wait_for_magick(magick);
-                magick_pg.finish_with_message(&format!(
-                    "Done. `magick` {}. Animation saved as \"{}\"",
-                    status, fout_name
-                ));
             }
             ExecContext::NoAnimation {
-                script,
                 cmd_list,
-                basename,
+                knobs,
                 light_props,
+                meshes,
+                env_lights,
+                screen,
             } => {
-                let mut drawer = DrawerBuilder::new(PPMImg::new(500, 500, 255)).build();
+                let screen = self.screen.unwrap_or(screen);
+                let mut drawer = DrawerBuilder::new(self.backend.canvas(screen))
+                    .with_lights(env_lights)
+                    .build();
                 pgbar.println("\tAnimation not detected. Rendering still image.");
                 // pgbar.set_message("Drawing image");
-                exec_no_animation(cmd_list, &script, &light_props, &mut drawer, &pgbar)?;
+                exec_no_animation(
+                    &cmd_list,
+                    &knobs,
+                    &light_props,
+                    &meshes,
+                    &mut drawer,
+                    Output::Files(Some(&pgbar)),
+                )?;
                 pgbar.finish_with_message("Done.");
             }
         }
 
         Ok(())
     }
+
+    /// Parse and check the script, which is the first half of `render`
+    pub fn parse(&self) -> EngineResult<Program> {
+        if let Some(screen) = self.screen {
+            screen.check().map_err(EngineError::Screen)?;
+        }
+        let (context, warnings) = match &self.script {
+            Script::File(path) => parse_file(path)?,
+            Script::Source(source) => {
+                parse_script(source.lines().map(str::to_owned).collect(), None)?
+            }
+        };
+        Ok(Program {
+            context,
+            warnings,
+            screen: self.screen,
+        })
+    }
+
+    /// Render the script in memory
+    ///
+    /// Nothing is printed, no file is written and no program is started, so `save` and `display` do nothing.
+    pub fn render(&self) -> EngineResult<Render> {
+        self.parse()?.render()
+    }
+
+    /// Run the script, and run it again every time it or a file it uses changes, until the process is stopped
+    ///
+    /// Files are polled every `interval`, which works in containers, where change events often don't.
+    /// Errors are printed, and outputs are only replaced once they're written in full,
+    /// so a script that doesn't parse leaves the outputs of the last good run.
+    pub fn watch(&self, interval: Duration) -> ! {
+        loop {
+            // before running, so changes made while it runs aren't missed
+            let watcher = match &self.script {
+                Script::File(path) => Watcher::new(path),
+                Script::Source(source) => Watcher::from_source(source),
+            };
+            if let Err(e) = self.run() {
+                eprintln!("engine error: {}", e);
+            }
+            println!(
+                "Watching {} file{} for changes",
+                watcher.len(),
+                if watcher.len() == 1 { "" } else { "s" }
+            );
+            while !watcher.changed() {
+                thread::sleep(interval);
+            }
+        }
+    }
+}
+
+impl Program {
+    /// Problems that don't stop the script from running
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Render the image or the frames in memory, which is the second half of `Interpreter::render`
+    pub fn render(&self) -> EngineResult<Render> {
+        match &self.context {
+            ExecContext::Animation {
+                cmd_list,
+                basename,
+                frames,
+                vary_list,
+                base_knobs,
+                output,
+                light_props,
+                meshes,
+                env_lights,
+                screen,
+                ..
+            } => {
+                let knob_states = knobs::knob_states(*frames, base_knobs, vary_list);
+                let animation = Animation {
+                    cmd_list,
+                    knob_states: &knob_states,
+                    light_props,
+                    meshes,
+                    env_lights,
+                    screen: self.screen.unwrap_or(*screen),
+                    basename,
+                    output,
+                    in_memory: true,
+                };
+                let mut frames = Vec::with_capacity(knob_states.len());
+                animation.render(
+                    |screen| PPMImg::new(screen.width, screen.height, screen.depth),
+                    |img| Ok(img.clone()),
+                    |img| {
+                        frames.push(img);
+                        Ok(())
+                    },
+                    || {},
+                )?;
+                Ok(Render::Frames(frames))
+            }
+            ExecContext::NoAnimation {
+                cmd_list,
+                knobs,
+                light_props,
+                meshes,
+                env_lights,
+                screen,
+            } => {
+                let screen = self.screen.unwrap_or(*screen);
+                let mut drawer =
+                    DrawerBuilder::new(PPMImg::new(screen.width, screen.height, screen.depth))
+                        .with_lights(env_lights.clone())
+                        .build();
+                exec_no_animation(
+                    cmd_list,
+                    knobs,
+                    light_props,
+                    meshes,
+                    &mut drawer,
+                    Output::Memory,
+                )?;
+                Ok(Render::Still(drawer.canvas().clone()))
+            }
+        }
+    }
+}
+
+/// Save a frame to `path`, in a format that depends on the extension and the canvas
+pub(crate) fn save_frame<C: Canvas>(drawer: &Drawer<C>, path: &str) -> EngineResult<()> {
+    utils::write_atomically(Path::new(path), |partial| {
+        drawer
+            .save(&partial.to_string_lossy())
+            .map_err(EngineError::from_io)?;
+        Ok(())
+    })
+}
+
+/// Delay of every frame of a gif, in 1/100 of a second
+///
+/// Delays are whole numbers, so the same one is used for every frame, or they would stutter.
+/// More than 50 fps is slowed down to 50, since shorter delays are played as long ones.
+fn gif_delay(fps: Option<f64>) -> u16 {
+    fps.map_or(DEFAULT_DELAY, |fps| {
+        (100. / fps)
+            .round()
+            .clamp(f64::from(gif::MIN_DELAY), f64::from(u16::MAX)) as u16
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mdl::result::RuntimeError, RGB};
+
+    #[test]
+    fn test_render_in_memory() {
+        let dir = std::env::temp_dir().join(format!("mdl-render-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let saved = dir.join("still.png");
+        let still = format!(
+            "screen 40 30\nbox 5 25 0 10 10 10\nsave {}\ndisplay\ngenerate_rayfiles\n",
+            saved.display()
+        );
+
+        let program = Interpreter::from_source(&still).parse().unwrap();
+        assert_eq!(1, program.warnings().len());
+        match program.render().unwrap() {
+            Render::Still(img) => {
+                assert_eq!((40, 30), (img.width(), img.height()));
+                assert!(img.pixels().iter().any(|p| *p != RGB::BLACK));
+            }
+            other => panic!("expected a still image, got {:?}", other),
+        }
+        assert!(!saved.exists());
+
+        let animation = "frames 4\nbasename spin\nvary k 0 3 0 30\nmove k 10 0\nsphere 0 0 0 5\n";
+        let screen = Screen {
+            width: 50,
+            height: 20,
+            depth: 255,
+        };
+        match Interpreter::from_source(animation)
+            .with_screen(screen)
+            .render()
+            .unwrap()
+        {
+            Render::Frames(frames) => {
+                assert_eq!(4, frames.len());
+                assert_eq!(50, frames[3].width());
+                assert!(frames[0] != frames[3]);
+            }
+            other => panic!("expected frames, got {:?}", other),
+        }
+        assert!(!Path::new("spin.gif").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_gif_delay() {
+        assert_eq!(2, gif_delay(None));
+        assert_eq!(4, gif_delay(Some(24.)));
+        assert_eq!(3, gif_delay(Some(30.)));
+        assert_eq!(2, gif_delay(Some(60.)));
+        assert_eq!(u16::MAX, gif_delay(Some(0.001)));
+    }
+
+    #[test]
+    fn test_gif_needs_pixels() {
+        let animation =
+            "frames 4\nbasename no-pixels\nvary k 0 3 0 30\nmove k 10 0\nsphere 0 0 0 5\n";
+        for backend in [Backend::Vector, Backend::Test].iter() {
+            match Interpreter::from_source(animation)
+                .with_backend(*backend)
+                .run()
+            {
+                Err(EngineError::NoPixels) => {}
+                other => panic!("expected no pixels, got {:?}", other),
+            }
+        }
+        assert!(!Path::new("no-pixels.gif").exists());
+        assert!(!Path::new(".partial-no-pixels.gif").exists());
+    }
+
+    #[test]
+    fn test_screen_too_large() {
+        let screen = Screen {
+            width: 70000,
+            height: 70000,
+            depth: 255,
+        };
+        match Interpreter::from_source("sphere 0 0 0 5\n")
+            .with_screen(screen)
+            .render()
+        {
+            Err(EngineError::Screen(RuntimeError::ScreenTooLarge { width, height })) => {
+                assert_eq!((70000, 70000), (width, height))
+            }
+            other => panic!("expected a screen error, got {:?}", other.map(|_| ())),
+        }
+    }
 }
@@ -1,17 +1,68 @@
-use std::str::FromStr;
+use std::{fmt, path::Path, str::FromStr, sync::Arc};
 
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag, take_while1},
-    character::complete::{alpha1, alphanumeric1, multispace0, one_of},
-    combinator::{all_consuming, map, map_res, opt, recognize, value},
-    error::ParseError,
-    multi::many0,
-    number::complete::double,
+    character::complete::{alpha1, alphanumeric1, char, multispace0, multispace1, none_of, one_of},
+    combinator::{all_consuming, map, map_res, not, opt, peek, recognize, value},
+    error::{context, ErrorKind, ParseError},
+    multi::{many0, separated_list},
     sequence::{delimited, pair, preceded, terminated, tuple},
     IResult,
 };
 
+use crate::{light::LightProps, vector::Vec3, RGB};
+
+use super::{
+    expr::{self, Env, EvalError, Expr},
+    result::RuntimeError,
+};
+
+/// Result of the parsers in this module
+pub(crate) type PResult<'a, O> = IResult<&'a str, O, ParseFailure<'a>>;
+
+/// Where and why parsing failed
+///
+/// When all alternatives fail, the one that got the furthest into the line is kept,
+/// so errors point inside the command that was meant, not at its start.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct ParseFailure<'a> {
+    /// Input left at the point of failure
+    pub input: &'a str,
+    pub kind: ErrorKind,
+    /// What was expected, for parsers wrapped in `context`
+    pub context: Option<&'static str>,
+}
+
+impl<'a> ParseError<&'a str> for ParseFailure<'a> {
+    fn from_error_kind(input: &'a str, kind: ErrorKind) -> Self {
+        ParseFailure {
+            input,
+            kind,
+            context: None,
+        }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn add_context(_: &'a str, context: &'static str, other: Self) -> Self {
+        ParseFailure {
+            context: Some(context),
+            ..other
+        }
+    }
+
+    fn or(self, other: Self) -> Self {
+        if other.input.len() < self.input.len() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Command {
     Push,
@@ -21,9 +72,452 @@ pub enum Command {
     AnimateCmd(Animate),
     LightingCmd(Lighting),
     MiscCmd(Misc),
+    /// `let name = value`, which is evaluated again in every frame
+    Let {
+        name: Symbol,
+        value: Expr,
+    },
+    /// `repeat count [as var] { ... }`, where `var` counts up from 0
+    Repeat {
+        count: Expr,
+        var: Option<Symbol>,
+        body: Vec<(Loc, Command)>,
+    },
+    /// `def name(params) { ... }`, which only runs when it's called
+    Def {
+        name: Symbol,
+        params: Vec<Symbol>,
+        body: Vec<(Loc, Command)>,
+    },
+    /// `call name(args)`
+    Call {
+        name: Symbol,
+        args: Vec<Expr>,
+    },
+}
+
+/// A line of a script, which is a command, or the start or the end of a block
+///
+/// Blocks are put together into `Command::Repeat` and `Command::Def` by the parser.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum Line {
+    Cmd(Box<Command>),
+    /// `repeat count [as var] {`
+    Repeat {
+        count: Expr,
+        var: Option<Symbol>,
+    },
+    /// `def name(params) {`
+    Def {
+        name: Symbol,
+        params: Vec<Symbol>,
+    },
+    /// `}`
+    End,
+    /// `include :path`
+    Include(String),
+}
+
+/// File and line a command is on
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct Loc {
+    /// Path of the included file, None for the script itself
+    pub file: Option<Arc<Path>>,
+    /// Line number, starting from 1
+    pub line: usize,
+}
+
+impl Loc {
+    /// Line of the script itself
+    pub fn line(line: usize) -> Self {
+        Loc { file: None, line }
+    }
+}
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "line {} of {}", self.line, file.display()),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// Every command, with the commands in a block right after the block
+pub(crate) fn flatten(cmds: &[(Loc, Command)]) -> Vec<(&Loc, &Command)> {
+    let mut flat = vec![];
+    for (loc, cmd) in cmds {
+        flat.push((loc, cmd));
+        if let Some(body) = cmd.body() {
+            flat.extend(flatten(body));
+        }
+    }
+    flat
+}
+
+impl Command {
+    /// Commands in the block, if this is one
+    pub(crate) fn body(&self) -> Option<&[(Loc, Command)]> {
+        match self {
+            Command::Repeat { body, .. } | Command::Def { body, .. } => Some(body),
+            _ => None,
+        }
+    }
+
+    /// Every expression in the arguments of the command
+    pub(crate) fn exprs(&self) -> Vec<&Expr> {
+        fn point(p: &Point) -> Vec<&Expr> {
+            vec![&p.0, &p.1, &p.2]
+        }
+        match self {
+            Command::Push | Command::Pop => vec![],
+            Command::TransformCmd(transform) => match transform {
+                Transform::Move { values, .. } | Transform::Scale { values, .. } => point(values),
+                Transform::Rotate { degrees, .. } => vec![degrees],
+            },
+            Command::ShapeCmd(shape) => match shape {
+                Shape::Sphere { center, r, .. } => [point(center), vec![r]].concat(),
+                Shape::Torus { center, r0, r1, .. } => [point(center), vec![r0, r1]].concat(),
+                Shape::Box {
+                    corner,
+                    height,
+                    width,
+                    depth,
+                    ..
+                } => [point(corner), vec![height, width, depth]].concat(),
+                Shape::Line { point0, point1, .. } => [point(point0), point(point1)].concat(),
+                Shape::Mesh { .. } => vec![],
+            },
+            Command::AnimateCmd(animate) => match animate {
+                Animate::SetKnob { value, .. } | Animate::SetAllKnobs(value) => vec![value],
+                Animate::Vary(vary) => vec![&vary.start_val, &vary.end_val],
+                Animate::Fps(fps) => vec![fps],
+                _ => vec![],
+            },
+            Command::LightingCmd(lighting) => match lighting {
+                Lighting::Light {
+                    color, location, ..
+                } => [vec![&color.r, &color.g, &color.b], point(location)].concat(),
+                Lighting::Ambient(color) => vec![&color.r, &color.g, &color.b],
+                Lighting::Constants { value: c, .. } => {
+                    let mut exprs = vec![
+                        &c.kar, &c.kdr, &c.ksr, &c.kag, &c.kdg, &c.ksg, &c.kab, &c.kdb, &c.ksb,
+                    ];
+                    exprs.extend(c.ir.iter().chain(&c.ig).chain(&c.ib));
+                    exprs
+                }
+                Lighting::Shading(_) => vec![],
+            },
+            Command::MiscCmd(misc) => match misc {
+                Misc::Camera { eye, aim } => [point(eye), point(aim)].concat(),
+                Misc::Focal(value) => vec![value],
+                _ => vec![],
+            },
+            Command::Let { value, .. } => vec![value],
+            Command::Repeat { count, .. } => vec![count],
+            Command::Def { .. } => vec![],
+            Command::Call { args, .. } => args.iter().collect(),
+        }
+    }
+}
+
+/// Words of a command as it's written, which are put back together with single spaces
+///
+/// Expressions are written without spaces, so each one is a single word.
+#[derive(Default)]
+struct Words {
+    words: Vec<String>,
+    /// Whether the last word was an optional symbol that was left out
+    after_empty_symbol: bool,
+}
+
+impl Words {
+    fn new(keyword: &str) -> Self {
+        Words {
+            words: vec![keyword.to_owned()],
+            after_empty_symbol: false,
+        }
+    }
+
+    fn word(&mut self, word: impl fmt::Display) -> &mut Self {
+        self.words.push(word.to_string());
+        self.after_empty_symbol = false;
+        self
+    }
+
+    /// An expression, in parentheses if it would be taken as the symbol that was left out
+    fn arg(&mut self, e: &Expr) -> &mut Self {
+        let arg = e.to_string();
+        match opt_symbol(&arg) {
+            Ok((_, Some(_))) if self.after_empty_symbol => self.word(format!("({})", arg)),
+            _ => self.word(arg),
+        }
+    }
+
+    fn point(&mut self, p: &Point) -> &mut Self {
+        self.arg(&p.0).arg(&p.1).arg(&p.2)
+    }
+
+    fn opt_symbol(&mut self, symbol: &Option<Symbol>) -> &mut Self {
+        match symbol {
+            Some(symbol) => self.word(symbol),
+            None => {
+                self.after_empty_symbol = true;
+                self
+            }
+        }
+    }
+
+    fn take(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.words)
+    }
+}
+
+fn repeat_words(count: &Expr, var: &Option<Symbol>) -> Vec<String> {
+    let mut words = Words::new("repeat");
+    words.arg(count);
+    if let Some(var) = var {
+        words.word("as").word(var);
+    }
+    words.word("{").take()
+}
+
+fn def_words(name: &Symbol, params: &[Symbol]) -> Vec<String> {
+    let params: Vec<String> = params.iter().map(Symbol::to_string).collect();
+    Words::new("def")
+        .word(format!("{}({})", name, params.join(", ")))
+        .word("{")
+        .take()
+}
+
+impl Command {
+    /// Keyword and arguments of the command in canonical MDL, or of the first line of a block
+    pub(crate) fn words(&self) -> Vec<String> {
+        match self {
+            Command::Push => Words::new("push").take(),
+            Command::Pop => Words::new("pop").take(),
+            Command::TransformCmd(transform) => match transform {
+                Transform::Move { values, knob } => {
+                    Words::new("move").point(values).opt_symbol(knob).take()
+                }
+                Transform::Scale { values, knob } => {
+                    Words::new("scale").point(values).opt_symbol(knob).take()
+                }
+                Transform::Rotate {
+                    axis,
+                    degrees,
+                    knob,
+                } => Words::new("rotate")
+                    .word(axis)
+                    .arg(degrees)
+                    .opt_symbol(knob)
+                    .take(),
+            },
+            Command::ShapeCmd(shape) => match shape {
+                Shape::Sphere {
+                    constants,
+                    center,
+                    r,
+                    coord,
+                } => Words::new("sphere")
+                    .opt_symbol(constants)
+                    .point(center)
+                    .arg(r)
+                    .opt_symbol(coord)
+                    .take(),
+                Shape::Torus {
+                    constants,
+                    center,
+                    r0,
+                    r1,
+                    coord,
+                } => Words::new("torus")
+                    .opt_symbol(constants)
+                    .point(center)
+                    .arg(r0)
+                    .arg(r1)
+                    .opt_symbol(coord)
+                    .take(),
+                Shape::Box {
+                    constants,
+                    corner,
+                    height,
+                    width,
+                    depth,
+                    coord,
+                } => Words::new("box")
+                    .opt_symbol(constants)
+                    .point(corner)
+                    .arg(height)
+                    .arg(width)
+                    .arg(depth)
+                    .opt_symbol(coord)
+                    .take(),
+                Shape::Line {
+                    constants,
+                    point0,
+                    coord0,
+                    point1,
+                    coord1,
+                } => Words::new("line")
+                    .opt_symbol(constants)
+                    .point(point0)
+                    .opt_symbol(coord0)
+                    .point(point1)
+                    .opt_symbol(coord1)
+                    .take(),
+                Shape::Mesh {
+                    constants,
+                    filename,
+                    coord,
+                } => Words::new("mesh")
+                    .opt_symbol(constants)
+                    .word(format!(":{}", filename))
+                    .opt_symbol(coord)
+                    .take(),
+            },
+            Command::AnimateCmd(animate) => match animate {
+                Animate::Basename(name) => Words::new("basename").word(name).take(),
+                Animate::SetKnob { name, value } => Words::new("set").word(name).arg(value).take(),
+                Animate::SetAllKnobs(value) => Words::new("setknobs").arg(value).take(),
+                Animate::Tween {
+                    start_frame,
+                    end_frame,
+                    knoblist0,
+                    knoblist1,
+                } => Words::new("tween")
+                    .word(start_frame)
+                    .word(end_frame)
+                    .word(knoblist0)
+                    .word(knoblist1)
+                    .take(),
+                Animate::Frames(frames) => Words::new("frames").word(frames).take(),
+                Animate::Vary(vary) => {
+                    let mut words = Words::new("vary");
+                    words
+                        .word(&vary.knob)
+                        .word(vary.start_frame)
+                        .word(vary.end_frame)
+                        .arg(&vary.start_val)
+                        .arg(&vary.end_val);
+                    if vary.easing != Easing::Linear {
+                        words.word(vary.easing.keyword());
+                    }
+                    words.take()
+                }
+                Animate::SaveKnobList(name) => Words::new("save_knobs").word(name).take(),
+                Animate::Fps(fps) => Words::new("fps").arg(fps).take(),
+                Animate::Loop(count) => Words::new("loop").word(count).take(),
+                Animate::Output(FrameOutput::Gif) => Words::new("output").word("gif").take(),
+                Animate::Output(FrameOutput::Files { extension }) => {
+                    Words::new("output").word("frames").word(extension).take()
+                }
+            },
+            Command::LightingCmd(lighting) => match lighting {
+                Lighting::Light {
+                    name,
+                    color,
+                    location,
+                } => Words::new("light")
+                    .word(name)
+                    .arg(&color.r)
+                    .arg(&color.g)
+                    .arg(&color.b)
+                    .point(location)
+                    .take(),
+                Lighting::Ambient(color) => Words::new("ambient")
+                    .arg(&color.r)
+                    .arg(&color.g)
+                    .arg(&color.b)
+                    .take(),
+                Lighting::Constants { name, value: c } => {
+                    let mut words = Words::new("constants");
+                    words.word(name);
+                    for k in &[
+                        &c.kar, &c.kdr, &c.ksr, &c.kag, &c.kdg, &c.ksg, &c.kab, &c.kdb, &c.ksb,
+                    ] {
+                        words.arg(k);
+                    }
+                    // only the ones that were given, an intensity can't be left out before another
+                    for i in c.ir.iter().chain(&c.ig).chain(&c.ib) {
+                        words.arg(i);
+                    }
+                    words.take()
+                }
+                Lighting::Shading(mode) => Words::new("shading").word(mode.keyword()).take(),
+            },
+            Command::MiscCmd(misc) => match misc {
+                Misc::SaveCoord(name) => Words::new("save_coord_system").word(name).take(),
+                Misc::Camera { eye, aim } => Words::new("camera").point(eye).point(aim).take(),
+                Misc::Save(filename) => Words::new("save").word(filename).take(),
+                Misc::GenerateRayfiles => Words::new("generate_rayfiles").take(),
+                Misc::Focal(value) => Words::new("focal").arg(value).take(),
+                Misc::Display => Words::new("display").take(),
+                Misc::Screen(screen) => {
+                    let mut words = Words::new("screen");
+                    words.word(screen.width).word(screen.height);
+                    if screen.depth != Screen::DEFAULT.depth {
+                        words.word(screen.depth);
+                    }
+                    words.take()
+                }
+            },
+            Command::Let { name, value } => Words::new("let")
+                .word(name)
+                .word("=")
+                .word(format!("{:#}", value))
+                .take(),
+            Command::Repeat { count, var, .. } => repeat_words(count, var),
+            Command::Def { name, params, .. } => def_words(name, params),
+            Command::Call { name, args } => {
+                let args: Vec<String> = args.iter().map(|arg| format!("{:#}", arg)).collect();
+                Words::new("call")
+                    .word(format!("{}({})", name, args.join(", ")))
+                    .take()
+            }
+        }
+    }
 }
 
-fn parse_cmd(i: &str) -> IResult<&str, Command> {
+impl fmt::Display for Command {
+    /// The command in canonical MDL, which parses back into the same command
+    ///
+    /// Blocks take more than one line, with the commands in them indented by two spaces.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.words().join(" "))?;
+        if let Some(body) = self.body() {
+            for (_, cmd) in body {
+                for line in cmd.to_string().lines() {
+                    write!(f, "\n  {}", line)?;
+                }
+            }
+            write!(f, "\n}}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Line {
+    /// Keyword and arguments of the line in canonical MDL
+    pub(crate) fn words(&self) -> Vec<String> {
+        match self {
+            Line::Cmd(cmd) => cmd.words(),
+            Line::Repeat { count, var } => repeat_words(count, var),
+            Line::Def { name, params } => def_words(name, params),
+            Line::End => Words::new("}").take(),
+            Line::Include(path) => Words::new("include").word(format!(":{}", path)).take(),
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    /// The line in canonical MDL, where a block is only its first line
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.words().join(" "))
+    }
+}
+
+fn parse_cmd(i: &str) -> PResult<'_, Command> {
     let (i, cmd) = alt((
         parse_push,
         parse_pop,
@@ -32,6 +526,8 @@ fn parse_cmd(i: &str) -> IResult<&str, Command> {
         parse_animate_cmd,
         parse_lighting_cmd,
         parse_misc_cmb,
+        parse_let,
+        parse_call,
     ))(i)?;
     Ok((i, cmd))
 }
@@ -39,11 +535,22 @@ fn parse_cmd(i: &str) -> IResult<&str, Command> {
 /// Parses a single line
 ///
 /// Returns None in as data if only comment is preset
-pub(crate) fn parse_line(i: &str) -> IResult<&str, Option<Command>> {
-    all_consuming(terminated(opt(parse_cmd), opt(ws(parse_comment))))(i)
+pub(crate) fn parse_line(i: &str) -> PResult<'_, Option<Line>> {
+    let line = alt((
+        map(parse_cmd, |cmd| Line::Cmd(Box::new(cmd))),
+        parse_repeat,
+        parse_def,
+        parse_include,
+        value(Line::End, ws(char('}'))),
+    ));
+    // not `opt(line)`, which would throw away where the command failed
+    alt((
+        all_consuming(terminated(map(line, Some), opt(ws(parse_comment)))),
+        all_consuming(value(None, pair(multispace0, opt(parse_comment)))),
+    ))(i)
 }
 
-fn parse_comment(i: &str) -> IResult<&str, (&str, &str)> {
+fn parse_comment(i: &str) -> PResult<'_, (&str, &str)> {
     pair(tag("//"), is_not("\n\r"))(i)
 }
 
@@ -59,32 +566,42 @@ pub enum Transform {
     },
     Rotate {
         axis: char,
-        degrees: f64,
+        degrees: Expr,
         knob: Option<Symbol>,
     },
 }
 
+impl Transform {
+    pub(crate) fn knob(&self) -> Option<&Symbol> {
+        match self {
+            Transform::Move { knob, .. } => knob.as_ref(),
+            Transform::Scale { knob, .. } => knob.as_ref(),
+            Transform::Rotate { knob, .. } => knob.as_ref(),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Shape {
     Sphere {
         constants: Option<Symbol>,
         center: Point,
-        r: f64,
+        r: Expr,
         coord: Option<Symbol>,
     },
     Torus {
         constants: Option<Symbol>,
         center: Point,
-        r0: f64,
-        r1: f64,
+        r0: Expr,
+        r1: Expr,
         coord: Option<Symbol>,
     },
     Box {
         constants: Option<Symbol>,
         corner: Point,
-        height: f64,
-        width: f64,
-        depth: f64,
+        height: Expr,
+        width: Expr,
+        depth: Expr,
         coord: Option<Symbol>,
     },
     Line {
@@ -101,18 +618,87 @@ pub enum Shape {
     },
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct Point(pub(crate) f64, pub(crate) f64, pub(crate) f64);
-
-impl Into<(f64, f64, f64)> for Point {
-    fn into(self) -> (f64, f64, f64) {
-        (self.0, self.1, self.2)
+impl Shape {
+    /// The constants name of a shape that can also be its first number, and the shape read that way,
+    /// see [`opt_symbol_then`]
+    ///
+    /// `sphere a x y z r` is a sphere with constants `a`, or one at `(a, x, y)` with radius `z` in coordinate system `r`.
+    /// It can only be read both ways when the last number is a bare variable, which becomes the coordinate system.
+    /// Lines and meshes are always read one way.
+    pub(crate) fn without_constants(&self) -> Option<(&Symbol, Shape)> {
+        fn shift<'a>(
+            constants: &'a Option<Symbol>,
+            coord: &Option<Symbol>,
+            last: &Expr,
+        ) -> Option<(&'a Symbol, Expr, Symbol)> {
+            match (constants, coord, last) {
+                (Some(constants), None, Expr::Var(coord)) => {
+                    Some((constants, Expr::Var(constants.clone()), coord.clone()))
+                }
+                _ => None,
+            }
+        }
+        match self {
+            Shape::Sphere {
+                constants,
+                center: Point(x, y, z),
+                r,
+                coord,
+            } => shift(constants, coord, r).map(|(name, first, coord)| {
+                let sphere = Shape::Sphere {
+                    constants: None,
+                    center: Point(first, x.clone(), y.clone()),
+                    r: z.clone(),
+                    coord: Some(coord),
+                };
+                (name, sphere)
+            }),
+            Shape::Torus {
+                constants,
+                center: Point(x, y, z),
+                r0,
+                r1,
+                coord,
+            } => shift(constants, coord, r1).map(|(name, first, coord)| {
+                let torus = Shape::Torus {
+                    constants: None,
+                    center: Point(first, x.clone(), y.clone()),
+                    r0: z.clone(),
+                    r1: r0.clone(),
+                    coord: Some(coord),
+                };
+                (name, torus)
+            }),
+            Shape::Box {
+                constants,
+                corner: Point(x, y, z),
+                height,
+                width,
+                depth,
+                coord,
+            } => shift(constants, coord, depth).map(|(name, first, coord)| {
+                let shape = Shape::Box {
+                    constants: None,
+                    corner: Point(first, x.clone(), y.clone()),
+                    height: z.clone(),
+                    width: height.clone(),
+                    depth: width.clone(),
+                    coord: Some(coord),
+                };
+                (name, shape)
+            }),
+            Shape::Line { .. } | Shape::Mesh { .. } => None,
+        }
     }
 }
 
-impl Into<(f64, f64, f64)> for &Point {
-    fn into(self) -> (f64, f64, f64) {
-        (self.0, self.1, self.2)
+#[derive(Debug, PartialEq, Clone)]
+pub struct Point(pub(crate) Expr, pub(crate) Expr, pub(crate) Expr);
+
+impl Point {
+    /// Value of every coordinate, or the first name that has no value
+    pub(crate) fn eval(&self, env: &Env) -> Result<(f64, f64, f64), EvalError> {
+        Ok((self.0.eval(env)?, self.1.eval(env)?, self.2.eval(env)?))
     }
 }
 
@@ -121,9 +707,9 @@ pub enum Animate {
     Basename(String),
     SetKnob {
         name: Symbol,
-        value: f64,
+        value: Expr,
     },
-    SetAllKnobs(f64),
+    SetAllKnobs(Expr),
     Tween {
         start_frame: u32,
         end_frame: u32,
@@ -131,8 +717,21 @@ pub enum Animate {
         knoblist1: Symbol,
     },
     Frames(u32),
-    Vary(VaryInfo),
+    Vary(VaryInfo<Expr>),
     SaveKnobList(Symbol),
+    Fps(Expr),
+    /// Number of times the animation loops, 0 is forever
+    Loop(u32),
+    Output(FrameOutput),
+}
+
+/// Where the frames of an animation are written
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum FrameOutput {
+    /// A single `basename.gif`
+    Gif,
+    /// One file for each frame, `basename-000.extension`, `basename-001.extension`, ...
+    Files { extension: String },
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -153,25 +752,66 @@ pub enum Lighting {
 /// Same thing as light::LightProps, but this is for parsing types, `LightProps` is used by the engine
 #[derive(Debug, PartialEq, Clone)]
 pub struct ObjConst {
-    pub(crate) kar: f64,
-    pub(crate) kdr: f64,
-    pub(crate) ksr: f64,
-    pub(crate) kag: f64,
-    pub(crate) kdg: f64,
-    pub(crate) ksg: f64,
-    pub(crate) kab: f64,
-    pub(crate) kdb: f64,
-    pub(crate) ksb: f64,
-    pub(crate) ir: Option<f64>,
-    pub(crate) ig: Option<f64>,
-    pub(crate) ib: Option<f64>,
+    pub(crate) kar: Expr,
+    pub(crate) kdr: Expr,
+    pub(crate) ksr: Expr,
+    pub(crate) kag: Expr,
+    pub(crate) kdg: Expr,
+    pub(crate) ksg: Expr,
+    pub(crate) kab: Expr,
+    pub(crate) kdb: Expr,
+    pub(crate) ksb: Expr,
+    pub(crate) ir: Option<Expr>,
+    pub(crate) ig: Option<Expr>,
+    pub(crate) ib: Option<Expr>,
+}
+
+impl ObjConst {
+    pub(crate) fn eval(&self, env: &Env) -> Result<LightProps, EvalError> {
+        let intensity = |i: &Option<Expr>| i.as_ref().map_or(Ok(0.), |i| i.eval(env));
+        Ok(LightProps {
+            ka: Vec3(
+                self.kar.eval(env)?,
+                self.kag.eval(env)?,
+                self.kab.eval(env)?,
+            ),
+            kd: Vec3(
+                self.kdr.eval(env)?,
+                self.kdg.eval(env)?,
+                self.kdb.eval(env)?,
+            ),
+            ks: Vec3(
+                self.ksr.eval(env)?,
+                self.ksg.eval(env)?,
+                self.ksb.eval(env)?,
+            ),
+            intensities: Vec3(
+                intensity(&self.ir)?,
+                intensity(&self.ig)?,
+                intensity(&self.ib)?,
+            ),
+            // Default value
+            ns: 10.,
+        })
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Rgb {
-    r: f64,
-    g: f64,
-    b: f64,
+    pub(crate) r: Expr,
+    pub(crate) g: Expr,
+    pub(crate) b: Expr,
+}
+
+impl Rgb {
+    /// Light colors in MDL are given as rgb triples in [0, 255]
+    pub(crate) fn eval(&self, env: &Env) -> Result<RGB, EvalError> {
+        Ok(RGB::from(Vec3(
+            self.r.eval(env)?,
+            self.g.eval(env)?,
+            self.b.eval(env)?,
+        )))
+    }
 }
 
 impl From<Point> for Rgb {
@@ -187,19 +827,55 @@ impl From<Point> for Rgb {
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Symbol(pub(crate) String);
 
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl Symbol {
     fn from_opt(obj: Option<&str>) -> Option<Self> {
         obj.map(|f| Symbol(f.to_owned()))
     }
 }
 
+/// A `vary`, with values that are expressions when it's parsed, and numbers once they're evaluated
 #[derive(Debug, PartialEq, Clone)]
-pub struct VaryInfo {
+pub struct VaryInfo<V = f64> {
     pub(crate) knob: Symbol,
     pub(crate) start_frame: u32,
     pub(crate) end_frame: u32,
-    pub(crate) start_val: f64,
-    pub(crate) end_val: f64,
+    pub(crate) start_val: V,
+    pub(crate) end_val: V,
+    pub(crate) easing: Easing,
+}
+
+/// How a knob moves from `start_val` to `end_val` in `vary`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    Exponential,
+    Sine,
+    Bounce,
+    Elastic,
+}
+
+impl Easing {
+    fn keyword(self) -> &'static str {
+        match self {
+            Easing::Linear => "linear",
+            Easing::EaseIn => "ease-in",
+            Easing::EaseOut => "ease-out",
+            Easing::EaseInOut => "ease-in-out",
+            Easing::Exponential => "exponential",
+            Easing::Sine => "sine",
+            Easing::Bounce => "bounce",
+            Easing::Elastic => "elastic",
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -211,59 +887,140 @@ pub enum ShadingMode {
     Raytrace,
 }
 
+impl ShadingMode {
+    fn keyword(&self) -> &'static str {
+        match self {
+            ShadingMode::Wireframe => "wireframe",
+            ShadingMode::Flat => "flat",
+            ShadingMode::Gouraud => "gouraud",
+            ShadingMode::Phong => "phong",
+            ShadingMode::Raytrace => "raytrace",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Misc {
     SaveCoord(Symbol),
     Camera { eye: Point, aim: Point },
     Save(String),
     GenerateRayfiles,
-    Focal(f64),
+    Focal(Expr),
     Display,
+    Screen(Screen),
+}
+
+/// Size of the image, and the max value of a color channel
+///
+/// Lit colors are kept at the depth, so a depth above 255 gives 16-bit output with more than 256 levels.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Screen {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u16,
+}
+
+impl Screen {
+    /// Used when the script doesn't have a `screen` command
+    pub const DEFAULT: Screen = Screen {
+        width: 500,
+        height: 500,
+        depth: 255,
+    };
+
+    /// Most pixels an image can have, a little more than 8K UHD
+    ///
+    /// Every pixel takes 14 bytes of color and depth, so this is about 470 MB.
+    pub const MAX_PIXELS: u64 = 1 << 25;
+
+    /// Whether an image of this size can be drawn
+    pub fn check(&self) -> Result<(), RuntimeError> {
+        if self.width == 0 || self.height == 0 || self.depth == 0 {
+            Err(RuntimeError::Semantics(
+                "width, height and depth of screen must be > 0",
+            ))
+        } else if u64::from(self.width) * u64::from(self.height) > Screen::MAX_PIXELS {
+            Err(RuntimeError::ScreenTooLarge {
+                width: self.width,
+                height: self.height,
+            })
+        } else {
+            Ok(())
+        }
+    }
 }
 
 /// A combinator that takes a parser `inner` and produces a parser that also consumes leading whitespace, returning the output of `inner`.
-fn ws<'a, F: 'a, O, E: ParseError<&'a str>>(inner: F) -> impl Fn(&'a str) -> IResult<&'a str, O, E>
+fn ws<'a, F, O, E: ParseError<&'a str>>(inner: F) -> impl Fn(&'a str) -> IResult<&'a str, O, E>
 where
-    F: Fn(&'a str) -> IResult<&'a str, O, E>,
+    F: Fn(&'a str) -> IResult<&'a str, O, E> + 'a,
 {
     delimited(multispace0, inner, multispace0)
     // preceded(space0, inner)
 }
 
-fn triple_float(i: &str) -> IResult<&str, Point> {
-    map(tuple((ws(double), ws(double), ws(double))), |(x, y, z)| {
-        Point(x, y, z)
-    })(i)
+fn triple_float(i: &str) -> PResult<'_, Point> {
+    map(
+        tuple((ws(expr::arg), ws(expr::arg), ws(expr::arg))),
+        |(x, y, z)| Point(x, y, z),
+    )(i)
 }
 
 /// Parsing a symbol that starts with a letter and may contain underscores, letters and numbers
-fn symbol(input: &str) -> IResult<&str, &str> {
+fn symbol(input: &str) -> PResult<'_, &str> {
     recognize(pair(
         alpha1,
         many0(alt((alphanumeric1, tag("-"), tag("."), tag("_")))),
     ))(input)
 }
 
-fn opt_symbol(i: &str) -> IResult<&str, Option<Symbol>> {
-    let (i, s) = opt(ws(symbol))(i)?;
+/// Parsing a file path, which is anything up to the next whitespace
+fn filename(i: &str) -> PResult<'_, &str> {
+    is_not(" \t\r\n")(i)
+}
+
+/// An optional symbol, which isn't the start of an expression like `x*2` or `sin(x)`
+///
+/// A bare variable in the place of a symbol is taken as the symbol, see [`opt_symbol_then`] for when it shouldn't be.
+fn opt_symbol(i: &str) -> PResult<'_, Option<Symbol>> {
+    // followed by whitespace, a comment or the end of the line
+    let end = peek(alt((value((), tag("//")), not(none_of(" \t\r\n")))));
+    let (i, s) = opt(ws(terminated(symbol, end)))(i)?;
     Ok((i, Symbol::from_opt(s)))
 }
 
-fn uint(i: &str) -> IResult<&str, u32> {
-    map_res(take_while1(|c: char| c.is_digit(10)), u32::from_str)(i)
+/// An optional symbol followed by `rest`, which goes to the end of the line
+///
+/// A bare variable can be the symbol or the first number after it, so it's only the symbol
+/// when `rest` still parses after it: `sphere x y z r` has no constants.
+/// When both fit, like in `sphere a x y z r`, it's the symbol, and [`Shape::without_constants`] is the other way.
+fn opt_symbol_then<'a, O>(
+    rest: impl Fn(&'a str) -> PResult<'a, O>,
+) -> impl Fn(&'a str) -> PResult<'a, (Option<Symbol>, O)> {
+    move |i| {
+        let line_end = peek(all_consuming(pair(multispace0, opt(parse_comment))));
+        alt((
+            pair(opt_symbol, terminated(&rest, line_end)),
+            map(&rest, |o| (None, o)),
+        ))(i)
+    }
 }
 
-fn parse_push(input: &str) -> IResult<&str, Command> {
+fn uint(i: &str) -> PResult<'_, u32> {
+    map_res(take_while1(|c: char| c.is_ascii_digit()), u32::from_str)(i)
+}
+
+fn parse_push(input: &str) -> PResult<'_, Command> {
     let (input, _) = ws(tag("push"))(input)?;
     Ok((input, Command::Push))
 }
 
-fn parse_pop(input: &str) -> IResult<&str, Command> {
+fn parse_pop(input: &str) -> PResult<'_, Command> {
     let (input, _) = ws(tag("pop"))(input)?;
     Ok((input, Command::Pop))
 }
 
-fn parse_move(input: &str) -> IResult<&str, Transform> {
+fn parse_move(input: &str) -> PResult<'_, Transform> {
     let (input, _) = ws(tag("move"))(input)?;
     let (input, point) = triple_float(input)?;
     let (input, knob) = ws(opt_symbol)(input)?;
@@ -276,7 +1033,7 @@ fn parse_move(input: &str) -> IResult<&str, Transform> {
     ))
 }
 
-fn parse_scale(input: &str) -> IResult<&str, Transform> {
+fn parse_scale(input: &str) -> PResult<'_, Transform> {
     let (input, _) = ws(tag("scale"))(input)?;
     let (input, point) = triple_float(input)?;
     let (input, knob) = ws(opt_symbol)(input)?;
@@ -289,10 +1046,10 @@ fn parse_scale(input: &str) -> IResult<&str, Transform> {
     ))
 }
 
-fn parse_rotate(input: &str) -> IResult<&str, Transform> {
+fn parse_rotate(input: &str) -> PResult<'_, Transform> {
     let (input, _) = ws(tag("rotate"))(input)?;
     let (input, axis) = ws(one_of("xyz"))(input)?;
-    let (input, degrees) = ws(double)(input)?;
+    let (input, degrees) = ws(expr::arg)(input)?;
     let (input, knob) = ws(opt_symbol)(input)?;
     Ok((
         input,
@@ -304,17 +1061,15 @@ fn parse_rotate(input: &str) -> IResult<&str, Transform> {
     ))
 }
 
-fn parse_tr_cmd(input: &str) -> IResult<&str, Command> {
+fn parse_tr_cmd(input: &str) -> PResult<'_, Command> {
     let (input, tr) = alt((parse_move, parse_rotate, parse_scale))(input)?;
     Ok((input, Command::TransformCmd(tr)))
 }
 
-fn parse_sphere(input: &str) -> IResult<&str, Shape> {
+fn parse_sphere(input: &str) -> PResult<'_, Shape> {
     let (input, _) = ws(tag("sphere"))(input)?;
-    let (input, constants) = opt_symbol(input)?;
-    let (input, center) = ws(triple_float)(input)?;
-    let (input, r) = ws(double)(input)?;
-    let (input, coord) = opt_symbol(input)?;
+    let (input, (constants, (center, r, coord))) =
+        opt_symbol_then(tuple((ws(triple_float), ws(expr::arg), opt_symbol)))(input)?;
     Ok((
         input,
         Shape::Sphere {
@@ -326,13 +1081,14 @@ fn parse_sphere(input: &str) -> IResult<&str, Shape> {
     ))
 }
 
-fn parse_torus(input: &str) -> IResult<&str, Shape> {
+fn parse_torus(input: &str) -> PResult<'_, Shape> {
     let (input, _) = ws(tag("torus"))(input)?;
-    let (input, constants) = opt_symbol(input)?;
-    let (input, center) = triple_float(input)?;
-    let (input, r0) = ws(double)(input)?;
-    let (input, r1) = ws(double)(input)?;
-    let (input, coord) = opt_symbol(input)?;
+    let (input, (constants, (center, r0, r1, coord))) = opt_symbol_then(tuple((
+        triple_float,
+        ws(expr::arg),
+        ws(expr::arg),
+        opt_symbol,
+    )))(input)?;
     Ok((
         input,
         Shape::Torus {
@@ -345,12 +1101,10 @@ fn parse_torus(input: &str) -> IResult<&str, Shape> {
     ))
 }
 
-fn parse_box(i: &str) -> IResult<&str, Shape> {
+fn parse_box(i: &str) -> PResult<'_, Shape> {
     let (i, _) = ws(tag("box"))(i)?;
-    let (i, c) = opt_symbol(i)?;
-    let (i, p0) = triple_float(i)?;
-    let (i, dims) = triple_float(i)?;
-    let (i, cor) = opt_symbol(i)?;
+    let (i, (c, (p0, dims, cor))) =
+        opt_symbol_then(tuple((triple_float, triple_float, opt_symbol)))(i)?;
     Ok((
         i,
         Shape::Box {
@@ -364,13 +1118,13 @@ fn parse_box(i: &str) -> IResult<&str, Shape> {
     ))
 }
 
-fn parse_line_shape(i: &str) -> IResult<&str, Shape> {
+fn parse_line_shape(i: &str) -> PResult<'_, Shape> {
     let (i, _) = ws(tag("line"))(i)?;
-    let (i, c) = opt_symbol(i)?;
-    let (i, p0) = triple_float(i)?;
-    let (i, cor0) = opt_symbol(i)?;
-    let (i, p1) = triple_float(i)?;
-    let (i, cor1) = opt_symbol(i)?;
+    // the first point can also start with a variable
+    let (i, (c, (p0, (cor0, (p1, cor1))))) = opt_symbol_then(pair(
+        triple_float,
+        opt_symbol_then(pair(triple_float, opt_symbol)),
+    ))(i)?;
     Ok((
         i,
         Shape::Line {
@@ -383,10 +1137,10 @@ fn parse_line_shape(i: &str) -> IResult<&str, Shape> {
     ))
 }
 
-fn parse_mesh(i: &str) -> IResult<&str, Shape> {
+fn parse_mesh(i: &str) -> PResult<'_, Shape> {
     let (i, _) = ws(tag("mesh"))(i)?;
     let (i, c) = opt_symbol(i)?;
-    let (i, filename) = ws(preceded(tag(":"), symbol))(i)?;
+    let (i, filename) = ws(preceded(tag(":"), filename))(i)?;
     let (i, coord) = opt_symbol(i)?;
     Ok((
         i,
@@ -398,7 +1152,7 @@ fn parse_mesh(i: &str) -> IResult<&str, Shape> {
     ))
 }
 
-fn parse_shape_cmd(i: &str) -> IResult<&str, Command> {
+fn parse_shape_cmd(i: &str) -> PResult<'_, Command> {
     let (i, shape) = alt((
         parse_sphere,
         parse_torus,
@@ -409,16 +1163,16 @@ fn parse_shape_cmd(i: &str) -> IResult<&str, Command> {
     Ok((i, Command::ShapeCmd(shape)))
 }
 
-fn parse_basename(i: &str) -> IResult<&str, Animate> {
+fn parse_basename(i: &str) -> PResult<'_, Animate> {
     let (i, _) = ws(tag("basename"))(i)?;
     let (i, name) = ws(symbol)(i)?;
     Ok((i, Animate::Basename(name.to_owned())))
 }
 
-fn parse_set_knob(i: &str) -> IResult<&str, Animate> {
+fn parse_set_knob(i: &str) -> PResult<'_, Animate> {
     let (i, _) = ws(tag("set"))(i)?;
     let (i, name) = ws(symbol)(i)?;
-    let (i, value) = ws(double)(i)?;
+    let (i, value) = ws(expr::arg)(i)?;
     Ok((
         i,
         Animate::SetKnob {
@@ -428,13 +1182,13 @@ fn parse_set_knob(i: &str) -> IResult<&str, Animate> {
     ))
 }
 
-fn parse_save_knobs(i: &str) -> IResult<&str, Animate> {
+fn parse_save_knobs(i: &str) -> PResult<'_, Animate> {
     let (i, _) = ws(tag("save_knobs"))(i)?;
     let (i, knoblist) = ws(symbol)(i)?;
     Ok((i, Animate::SaveKnobList(Symbol(knoblist.to_owned()))))
 }
 
-fn parse_tween(i: &str) -> IResult<&str, Animate> {
+fn parse_tween(i: &str) -> PResult<'_, Animate> {
     let (i, _) = ws(tag("tween"))(i)?;
     let (i, start_frame) = ws(uint)(i)?;
     let (i, end_frame) = ws(uint)(i)?;
@@ -451,20 +1205,45 @@ fn parse_tween(i: &str) -> IResult<&str, Animate> {
     ))
 }
 
-fn parse_num_frames(i: &str) -> IResult<&str, Animate> {
+fn parse_num_frames(i: &str) -> PResult<'_, Animate> {
     let (i, (_, num)) = pair(ws(tag("frames")), ws(uint))(i)?;
     Ok((i, Animate::Frames(num)))
 }
 
-fn parse_vary(i: &str) -> IResult<&str, Animate> {
+fn parse_fps(i: &str) -> PResult<'_, Animate> {
+    let (i, (_, fps)) = pair(ws(tag("fps")), ws(expr::arg))(i)?;
+    Ok((i, Animate::Fps(fps)))
+}
+
+fn parse_loop(i: &str) -> PResult<'_, Animate> {
+    let (i, (_, count)) = pair(ws(tag("loop")), ws(uint))(i)?;
+    Ok((i, Animate::Loop(count)))
+}
+
+fn parse_output(i: &str) -> PResult<'_, Animate> {
+    let (i, _) = ws(tag("output"))(i)?;
+    let (i, output) = alt((
+        value(FrameOutput::Gif, ws(tag("gif"))),
+        map(
+            preceded(ws(tag("frames")), opt(ws(alphanumeric1))),
+            |extension: Option<&str>| FrameOutput::Files {
+                extension: extension.unwrap_or("ppm").to_owned(),
+            },
+        ),
+    ))(i)?;
+    Ok((i, Animate::Output(output)))
+}
+
+fn parse_vary(i: &str) -> PResult<'_, Animate> {
     let (i, (_, knob, start_frame, end_frame, start_val, end_val)) = tuple((
         ws(tag("vary")),
         ws(symbol),
         ws(uint),
         ws(uint),
-        ws(double),
-        ws(double),
+        ws(expr::arg),
+        ws(expr::arg),
     ))(i)?;
+    let (i, easing) = opt(ws(parse_easing))(i)?;
     Ok((
         i,
         Animate::Vary(VaryInfo {
@@ -473,29 +1252,48 @@ fn parse_vary(i: &str) -> IResult<&str, Animate> {
             end_frame,
             start_val,
             end_val,
+            easing: easing.unwrap_or(Easing::Linear),
         }),
     ))
 }
 
-fn parse_set_all_knobs(i: &str) -> IResult<&str, Animate> {
-    let (i, (_, value)) = pair(ws(tag("setknobs")), ws(double))(i)?;
+fn parse_easing(i: &str) -> PResult<'_, Easing> {
+    alt((
+        value(Easing::Linear, tag(Easing::Linear.keyword())),
+        // `ease-in-out` goes first, since `ease-in` is a prefix of it
+        value(Easing::EaseInOut, tag(Easing::EaseInOut.keyword())),
+        value(Easing::EaseIn, tag(Easing::EaseIn.keyword())),
+        value(Easing::EaseOut, tag(Easing::EaseOut.keyword())),
+        value(Easing::Exponential, tag(Easing::Exponential.keyword())),
+        value(Easing::Sine, tag(Easing::Sine.keyword())),
+        value(Easing::Bounce, tag(Easing::Bounce.keyword())),
+        value(Easing::Elastic, tag(Easing::Elastic.keyword())),
+    ))(i)
+}
+
+fn parse_set_all_knobs(i: &str) -> PResult<'_, Animate> {
+    let (i, (_, value)) = pair(ws(tag("setknobs")), ws(expr::arg))(i)?;
     Ok((i, Animate::SetAllKnobs(value)))
 }
 
-fn parse_animate_cmd(i: &str) -> IResult<&str, Command> {
+fn parse_animate_cmd(i: &str) -> PResult<'_, Command> {
     let (i, animate) = alt((
         parse_basename,
+        // `setknobs` goes before `set`, which would take "knobs" as the knob name
+        parse_set_all_knobs,
         parse_set_knob,
         parse_save_knobs,
         parse_tween,
         parse_num_frames,
         parse_vary,
-        parse_set_all_knobs,
+        parse_fps,
+        parse_loop,
+        parse_output,
     ))(i)?;
     Ok((i, Command::AnimateCmd(animate)))
 }
 
-fn parse_light(i: &str) -> IResult<&str, Lighting> {
+fn parse_light(i: &str) -> PResult<'_, Lighting> {
     let (i, (_, name, color_triple, location)) =
         tuple((ws(tag("light")), ws(symbol), triple_float, triple_float))(i)?;
     Ok((
@@ -508,32 +1306,21 @@ fn parse_light(i: &str) -> IResult<&str, Lighting> {
     ))
 }
 
-fn parse_ambient(i: &str) -> IResult<&str, Lighting> {
+fn parse_ambient(i: &str) -> PResult<'_, Lighting> {
     let (i, (_, triple)) = pair(ws(tag("ambient")), ws(triple_float))(i)?;
     Ok((i, Lighting::Ambient(triple.into())))
 }
 
-fn parse_constants(i: &str) -> IResult<&str, Lighting> {
+fn parse_constants(i: &str) -> PResult<'_, Lighting> {
     let (i, _) = ws(tag("constants"))(i)?;
-    let (i, (name, kr, kg, kb, ir, ig, ib)): (
-        &str,
-        (
-            &str,
-            Point,
-            Point,
-            Point,
-            Option<f64>,
-            Option<f64>,
-            Option<f64>,
-        ),
-    ) = tuple((
+    let (i, (name, kr, kg, kb, ir, ig, ib)) = tuple((
         ws(symbol),
         triple_float,
         triple_float,
         triple_float,
-        opt(ws(double)),
-        opt(ws(double)),
-        opt(ws(double)),
+        opt(ws(expr::arg)),
+        opt(ws(expr::arg)),
+        opt(ws(expr::arg)),
     ))(i)?;
 
     Ok((
@@ -558,55 +1345,162 @@ fn parse_constants(i: &str) -> IResult<&str, Lighting> {
     ))
 }
 
-fn parse_shading(i: &str) -> IResult<&str, Lighting> {
+fn parse_shading(i: &str) -> PResult<'_, Lighting> {
     let (i, _) = ws(tag("shading"))(i)?;
     let (i, mode) = ws(alt((
-        value(ShadingMode::Wireframe, tag("wireframe")),
-        value(ShadingMode::Flat, tag("flat")),
-        value(ShadingMode::Gouraud, tag("gouraud")),
-        value(ShadingMode::Phong, tag("phong")),
-        value(ShadingMode::Raytrace, tag("raytrace")),
+        value(
+            ShadingMode::Wireframe,
+            tag(ShadingMode::Wireframe.keyword()),
+        ),
+        value(ShadingMode::Flat, tag(ShadingMode::Flat.keyword())),
+        value(ShadingMode::Gouraud, tag(ShadingMode::Gouraud.keyword())),
+        value(ShadingMode::Phong, tag(ShadingMode::Phong.keyword())),
+        value(ShadingMode::Raytrace, tag(ShadingMode::Raytrace.keyword())),
     )))(i)?;
     Ok((i, Lighting::Shading(mode)))
 }
 
-fn parse_lighting_cmd(i: &str) -> IResult<&str, Command> {
+fn parse_lighting_cmd(i: &str) -> PResult<'_, Command> {
     let (i, lighting) = alt((parse_light, parse_ambient, parse_constants, parse_shading))(i)?;
     Ok((i, Command::LightingCmd(lighting)))
 }
 
-fn parse_save_cor(i: &str) -> IResult<&str, Misc> {
+fn parse_save_cor(i: &str) -> PResult<'_, Misc> {
     let (i, (_, name)) = pair(ws(tag("save_coord_system")), ws(symbol))(i)?;
     Ok((i, Misc::SaveCoord(Symbol(name.to_owned()))))
 }
 
-fn parse_cam(i: &str) -> IResult<&str, Misc> {
-    let (i, (_, eye, aim)) = tuple((tag("camera"), triple_float, triple_float))(i)?;
+fn parse_cam(i: &str) -> PResult<'_, Misc> {
+    let (i, (_, eye, aim)) = tuple((ws(tag("camera")), triple_float, triple_float))(i)?;
     Ok((i, Misc::Camera { eye, aim }))
 }
 
-fn parse_save_file(i: &str) -> IResult<&str, Misc> {
-    let (i, (_, filename)) = pair(ws(tag("save")), ws(symbol))(i)?;
+fn parse_save_file(i: &str) -> PResult<'_, Misc> {
+    let (i, (_, filename)) = pair(ws(tag("save")), ws(filename))(i)?;
     Ok((i, Misc::Save(filename.to_owned())))
 }
 
-fn parse_gen_rayfiles(i: &str) -> IResult<&str, Misc> {
+fn parse_gen_rayfiles(i: &str) -> PResult<'_, Misc> {
     let (i, _) = ws(tag("generate_rayfiles"))(i)?;
     Ok((i, Misc::GenerateRayfiles))
 }
 
-fn parse_focal(i: &str) -> IResult<&str, Misc> {
-    let (i, (_, value)) = pair(ws(tag("focal")), ws(double))(i)?;
+fn parse_focal(i: &str) -> PResult<'_, Misc> {
+    let (i, (_, value)) = pair(ws(tag("focal")), ws(expr::arg))(i)?;
     Ok((i, Misc::Focal(value)))
 }
 
-fn parse_display(i: &str) -> IResult<&str, Misc> {
+fn parse_display(i: &str) -> PResult<'_, Misc> {
     let (i, _) = ws(tag("display"))(i)?;
     Ok((i, Misc::Display))
 }
 
-fn parse_misc_cmb(i: &str) -> IResult<&str, Command> {
+fn parse_screen(i: &str) -> PResult<'_, Misc> {
+    let (i, (_, width, height, depth)) = tuple((
+        ws(tag("screen")),
+        ws(uint),
+        ws(uint),
+        opt(ws(map_res(
+            take_while1(|c: char| c.is_ascii_digit()),
+            u16::from_str,
+        ))),
+    ))(i)?;
+    Ok((
+        i,
+        Misc::Screen(Screen {
+            width,
+            height,
+            depth: depth.unwrap_or(Screen::DEFAULT.depth),
+        }),
+    ))
+}
+
+fn parse_let(i: &str) -> PResult<'_, Command> {
+    let (i, (_, name, _, value)) = tuple((
+        ws(tag("let")),
+        ws(expr::ident),
+        ws(tag("=")),
+        ws(expr::expr),
+    ))(i)?;
+    Ok((
+        i,
+        Command::Let {
+            name: Symbol(name.to_owned()),
+            value,
+        },
+    ))
+}
+
+fn parse_repeat(i: &str) -> PResult<'_, Line> {
+    let (i, (_, count, var, _)) = tuple((
+        ws(tag("repeat")),
+        ws(expr::arg),
+        opt(preceded(
+            ws(terminated(tag("as"), multispace1)),
+            ws(expr::ident),
+        )),
+        context("`{`", ws(char('{'))),
+    ))(i)?;
+    Ok((
+        i,
+        Line::Repeat {
+            count,
+            var: var.map(|var| Symbol(var.to_owned())),
+        },
+    ))
+}
+
+fn parse_def(i: &str) -> PResult<'_, Line> {
+    let (i, (_, name, params, _)) = tuple((
+        ws(tag("def")),
+        ws(expr::ident),
+        delimited(
+            context("`(`", ws(char('('))),
+            separated_list(ws(char(',')), ws(expr::ident)),
+            context("`)`", ws(char(')'))),
+        ),
+        context("`{`", ws(char('{'))),
+    ))(i)?;
+    Ok((
+        i,
+        Line::Def {
+            name: Symbol(name.to_owned()),
+            params: params
+                .into_iter()
+                .map(|param| Symbol(param.to_owned()))
+                .collect(),
+        },
+    ))
+}
+
+fn parse_include(i: &str) -> PResult<'_, Line> {
+    let (i, _) = ws(tag("include"))(i)?;
+    let (i, path) = ws(preceded(tag(":"), filename))(i)?;
+    Ok((i, Line::Include(path.to_owned())))
+}
+
+fn parse_call(i: &str) -> PResult<'_, Command> {
+    let (i, (_, name, args)) = tuple((
+        ws(tag("call")),
+        ws(expr::ident),
+        delimited(
+            context("`(`", ws(char('('))),
+            separated_list(ws(char(',')), ws(expr::expr)),
+            context("`)`", ws(char(')'))),
+        ),
+    ))(i)?;
+    Ok((
+        i,
+        Command::Call {
+            name: Symbol(name.to_owned()),
+            args,
+        },
+    ))
+}
+
+fn parse_misc_cmb(i: &str) -> PResult<'_, Command> {
     let (i, misc) = alt((
+        parse_screen,
         parse_save_cor,
         parse_cam,
         parse_save_file,
@@ -621,11 +1515,15 @@ fn parse_misc_cmb(i: &str) -> IResult<&str, Command> {
 mod tests {
     use super::*;
 
+    fn point(x: f64, y: f64, z: f64) -> Point {
+        Point(Expr::Num(x), Expr::Num(y), Expr::Num(z))
+    }
+
     const MISC_CASES: [(&str, Misc); 1] = [(
         "camera 1 2 3 10 20 30 ",
         Misc::Camera {
-            eye: Point(1., 2., 3.),
-            aim: Point(10., 20., 30.),
+            eye: Point(Expr::Num(1.), Expr::Num(2.), Expr::Num(3.)),
+            aim: Point(Expr::Num(10.), Expr::Num(20.), Expr::Num(30.)),
         },
     )];
 
@@ -635,7 +1533,7 @@ mod tests {
         let (_, cmd) = parse_scale(string).unwrap();
         assert_eq!(
             Transform::Scale {
-                values: Point(0.2, 3.2, 4.2),
+                values: point(0.2, 3.2, 4.2),
                 knob: Some(Symbol(String::from("kooo")))
             },
             cmd
@@ -662,14 +1560,216 @@ mod tests {
             parse_line("move 1 2 3 fred"),
             Ok((
                 "",
-                Some(Command::TransformCmd(Transform::Move {
-                    values: Point(1., 2., 3.),
-                    knob: Some(Symbol(String::from("fred"))),
-                }))
+                Some(Line::Cmd(Box::new(Command::TransformCmd(
+                    Transform::Move {
+                        values: point(1., 2., 3.),
+                        knob: Some(Symbol(String::from("fred"))),
+                    }
+                ))))
             ))
         )
     }
 
+    #[test]
+    fn test_mesh_path() {
+        assert_eq!(
+            Ok((
+                "",
+                Shape::Mesh {
+                    constants: Some(Symbol(String::from("shiny"))),
+                    filename: String::from("../models/teapot.obj"),
+                    coord: Some(Symbol(String::from("spin"))),
+                }
+            )),
+            parse_mesh("mesh shiny :../models/teapot.obj spin")
+        );
+    }
+
+    #[test]
+    fn test_setknobs_is_not_set() {
+        assert_eq!(
+            Ok((
+                "",
+                Command::AnimateCmd(Animate::SetAllKnobs(Expr::Num(0.2)))
+            )),
+            parse_animate_cmd("setknobs .2")
+        );
+    }
+
+    #[test]
+    fn test_vary_easing() {
+        let vary = |easing| {
+            Ok((
+                "",
+                Animate::Vary(VaryInfo {
+                    knob: Symbol(String::from("k")),
+                    start_frame: 0,
+                    end_frame: 9,
+                    start_val: Expr::Num(0.),
+                    end_val: Expr::Num(1.),
+                    easing,
+                }),
+            ))
+        };
+        assert_eq!(vary(Easing::Linear), parse_vary("vary k 0 9 0 1"));
+        assert_eq!(vary(Easing::EaseIn), parse_vary("vary k 0 9 0 1 ease-in"));
+        assert_eq!(
+            vary(Easing::EaseInOut),
+            parse_vary("vary k 0 9 0 1 ease-in-out")
+        );
+        assert_eq!(vary(Easing::Elastic), parse_vary("vary k 0 9 0 1 elastic"));
+    }
+
+    #[test]
+    fn test_screen() {
+        let screen = |width, height, depth| {
+            Ok((
+                "",
+                Misc::Screen(Screen {
+                    width,
+                    height,
+                    depth,
+                }),
+            ))
+        };
+        assert_eq!(screen(1920, 1080, 255), parse_screen("screen 1920 1080"));
+        assert_eq!(
+            screen(640, 480, 65535),
+            parse_screen("screen 640 480 65535")
+        );
+        assert!(parse_line("screen 640 480 65536").is_err());
+    }
+
+    #[test]
+    fn test_animation_output() {
+        assert_eq!(Ok(("", Animate::Fps(Expr::Num(24.)))), parse_fps("fps 24"));
+        assert_eq!(Ok(("", Animate::Loop(0))), parse_loop("loop 0"));
+        assert_eq!(
+            Ok(("", Animate::Output(FrameOutput::Gif))),
+            parse_output("output gif")
+        );
+        assert_eq!(
+            Ok((
+                "",
+                Animate::Output(FrameOutput::Files {
+                    extension: String::from("ppm")
+                })
+            )),
+            parse_output("output frames")
+        );
+        assert_eq!(
+            Ok((
+                "",
+                Animate::Output(FrameOutput::Files {
+                    extension: String::from("png")
+                })
+            )),
+            parse_output("output frames png")
+        );
+    }
+
+    #[test]
+    fn test_expression_arguments() {
+        let x = || Expr::Var(Symbol(String::from("x")));
+        assert_eq!(
+            Ok((
+                "",
+                Command::Let {
+                    name: Symbol(String::from("x")),
+                    value: Expr::Binary(
+                        expr::Op::Add,
+                        Box::new(Expr::Num(1.)),
+                        Box::new(Expr::Num(2.))
+                    ),
+                }
+            )),
+            parse_let("let x = 1 + 2")
+        );
+        // a name followed by more of an expression is not the constants of the shape
+        assert_eq!(
+            Ok((
+                "",
+                Shape::Sphere {
+                    constants: None,
+                    center: Point(
+                        Expr::Binary(expr::Op::Mul, Box::new(x()), Box::new(Expr::Num(2.))),
+                        Expr::Num(-1.),
+                        Expr::Num(0.)
+                    ),
+                    r: x(),
+                    coord: None,
+                }
+            )),
+            parse_sphere("sphere x*2 -1 0 x")
+        );
+        assert_eq!(
+            Some(Line::Cmd(Box::new(Command::TransformCmd(
+                Transform::Move {
+                    values: Point(Expr::Num(1.), Expr::Num(2.), x()),
+                    knob: Some(Symbol(String::from("k"))),
+                }
+            )))),
+            parse_line("move 1 2 x k// comment").unwrap().1
+        );
+    }
+
+    #[test]
+    fn test_blocks() {
+        let name = |s: &str| Symbol(s.to_owned());
+        assert_eq!(
+            Ok((
+                "",
+                Some(Line::Repeat {
+                    count: Expr::Num(12.),
+                    var: Some(name("i")),
+                })
+            )),
+            parse_line("repeat 12 as i { // spokes")
+        );
+        assert_eq!(
+            Some(Line::Repeat {
+                count: Expr::Var(name("n")),
+                var: None,
+            }),
+            parse_line("  repeat n{").unwrap().1
+        );
+        assert_eq!(
+            Some(Line::Def {
+                name: name("petal"),
+                params: vec![name("angle"), name("size")],
+            }),
+            parse_line("def petal(angle, size) {").unwrap().1
+        );
+        assert_eq!(
+            Some(Line::Def {
+                name: name("flower"),
+                params: vec![],
+            }),
+            parse_line("def flower() {").unwrap().1
+        );
+        assert_eq!(Some(Line::End), parse_line("  } // petal").unwrap().1);
+        assert_eq!(
+            Some(Line::Cmd(Box::new(Command::Call {
+                name: name("petal"),
+                args: vec![
+                    Expr::Binary(
+                        expr::Op::Mul,
+                        Box::new(Expr::Var(name("i"))),
+                        Box::new(Expr::Num(30.))
+                    ),
+                    Expr::Num(5.)
+                ],
+            }))),
+            parse_line("call petal(i * 30, 5)").unwrap().1
+        );
+        assert_eq!(
+            Some(Line::Include(String::from("lib/materials.mdl"))),
+            parse_line("include :lib/materials.mdl").unwrap().1
+        );
+        assert!(parse_line("repeat 3").is_err());
+        assert!(parse_line("def petal(1) {").is_err());
+    }
+
     #[test]
     fn test_push_pop() {
         assert_eq!(Command::Push, parse_push("push").unwrap().1);
@@ -682,7 +1782,7 @@ mod tests {
             Ok((
                 "",
                 Transform::Move {
-                    values: Point(0.1, 0.2, 3.4),
+                    values: point(0.1, 0.2, 3.4),
                     knob: None,
                 }
             )),
@@ -693,7 +1793,7 @@ mod tests {
             Ok((
                 "",
                 Transform::Move {
-                    values: Point(-0.1, -0.2, -3.4),
+                    values: point(-0.1, -0.2, -3.4),
                     knob: None
                 }
             )),
@@ -704,7 +1804,7 @@ mod tests {
             Ok((
                 "",
                 Transform::Move {
-                    values: Point(1., 2., 3.),
+                    values: point(1., 2., 3.),
                     knob: Some(Symbol(String::from("fred"))),
                 }
             )),
@@ -721,4 +1821,150 @@ mod tests {
             );
         }
     }
+
+    fn reparse(line: &Line) -> Line {
+        let printed = line.to_string();
+        match parse_line(&printed) {
+            Ok(("", Some(reparsed))) => reparsed,
+            other => panic!(
+                "{:?} was printed as {:?}, which parses as {:?}",
+                line, printed, other
+            ),
+        }
+    }
+
+    #[test]
+    fn test_variables_in_place_of_constants() {
+        let var = |name: &str| Expr::Var(Symbol(name.to_owned()));
+        let sym = |name: &str| Some(Symbol(name.to_owned()));
+        let shape = |source: &str| match parse_line(source) {
+            Ok((_, Some(Line::Cmd(cmd)))) => match *cmd {
+                Command::ShapeCmd(shape) => shape,
+                other => panic!("expected a shape, got {:?}", other),
+            },
+            other => panic!("expected a command, got {:?}", other),
+        };
+
+        // too few numbers are left with `x` as the constants
+        let sphere = Shape::Sphere {
+            constants: None,
+            center: Point(var("x"), var("y"), var("z")),
+            r: var("r"),
+            coord: None,
+        };
+        assert_eq!(sphere, shape("sphere x y z r"));
+        assert_eq!(sphere, shape("sphere x y z r // comment"));
+        assert_eq!(None, sphere.without_constants());
+        assert_eq!(
+            Shape::Torus {
+                constants: None,
+                center: Point(var("x"), var("y"), Expr::Num(0.)),
+                r0: var("r"),
+                r1: Expr::Num(1.),
+                coord: None,
+            },
+            shape("torus x y 0 r 1")
+        );
+        assert_eq!(
+            Shape::Box {
+                constants: None,
+                corner: Point(var("x"), Expr::Num(0.), Expr::Num(0.)),
+                height: var("w"),
+                width: var("w"),
+                depth: var("w"),
+                coord: None,
+            },
+            shape("box x 0 0 w w w")
+        );
+        assert_eq!(
+            Shape::Line {
+                constants: None,
+                point0: Point(var("x0"), var("y0"), var("z0")),
+                coord0: None,
+                point1: Point(var("x1"), var("y1"), var("z1")),
+                coord1: None,
+            },
+            shape("line x0 y0 z0 x1 y1 z1")
+        );
+
+        // both fit, and `a` is only a variable if it isn't the name of constants
+        let ambiguous = shape("sphere a x y z r");
+        assert_eq!(
+            Shape::Sphere {
+                constants: sym("a"),
+                center: Point(var("x"), var("y"), var("z")),
+                r: var("r"),
+                coord: None,
+            },
+            ambiguous
+        );
+        assert_eq!(
+            Some((
+                &Symbol(String::from("a")),
+                Shape::Sphere {
+                    constants: None,
+                    center: Point(var("a"), var("x"), var("y")),
+                    r: var("z"),
+                    coord: sym("r"),
+                }
+            )),
+            ambiguous.without_constants()
+        );
+        assert_eq!(None, shape("sphere a x y z 5").without_constants());
+        assert!(parse_line("sphere a x y").is_err());
+    }
+
+    #[test]
+    fn test_printed_lines_parse_back() {
+        let mut lines = 0;
+        for source in include_str!("../../scripts/test.mdl").lines() {
+            if let Ok((_, Some(line))) = parse_line(source) {
+                assert_eq!(line, reparse(&line), "{}", source);
+                lines += 1;
+            }
+        }
+        assert!(lines > 40);
+
+        for (source, printed) in &[
+            ("  move .1 0.2   3.4 ", "move 0.1 0.2 3.4"),
+            ("sphere 0 0 0 100 //big", "sphere 0 0 0 100"),
+            ("sphere x 0 0 0 1", "sphere x 0 0 0 1"),
+            ("sphere (x) 0 0 1", "sphere (x) 0 0 1"),
+            ("sphere (x-1) 0 0 1", "sphere (x-1) 0 0 1"),
+            ("line 0 0 0 (a) 1 1", "line 0 0 0 (a) 1 1"),
+            ("line 0 0 0 a 1 1 1", "line 0 0 0 a 1 1 1"),
+            ("vary k 0 9 0 1 linear", "vary k 0 9 0 1"),
+            ("vary k 0 9 -1 1 ease-in-out", "vary k 0 9 -1 1 ease-in-out"),
+            ("output frames", "output frames ppm"),
+            ("screen 50 60 255", "screen 50 60"),
+            ("let r=2*(x+1)", "let r = 2 * (x + 1)"),
+            ("repeat n as i {", "repeat n as i {"),
+            ("def  f( a,b ){", "def f(a, b) {"),
+            ("call f( 1,x*2 )", "call f(1, x * 2)"),
+            ("include :lib/a.mdl", "include :lib/a.mdl"),
+            ("mesh :teapot.obj", "mesh :teapot.obj"),
+            ("  camera 0 0 1 0 0 0", "camera 0 0 1 0 0 0"),
+        ] {
+            let line = parse_line(source).unwrap().1.unwrap();
+            assert_eq!(*printed, line.to_string());
+            assert_eq!(line, reparse(&line));
+        }
+    }
+
+    #[test]
+    fn test_printed_blocks_indent_their_body() {
+        let cmd = Command::Repeat {
+            count: Expr::Num(2.),
+            var: None,
+            body: vec![(
+                Loc::line(2),
+                Command::Def {
+                    name: Symbol("f".to_owned()),
+                    params: vec![],
+                    body: vec![(Loc::line(3), Command::Push)],
+                },
+            )],
+        };
+        assert_eq!("repeat 2 {\n  def f() {\n    push\n  }\n}", cmd.to_string());
+    }
 }
@@ -0,0 +1,780 @@
+//! Check the symbols and the coordinate stack of a script before anything is rendered
+//!
+//! Constants, knobs, knob lists, lights, coordinate systems, variables and `def`s share one namespace.
+//! This finds names that are used but never defined, names used as the wrong kind of symbol,
+//! and definitions that are never used.
+//! It also follows `push` and `pop` through the script, to find pops with nothing pushed and pushes that are never popped.
+
+use std::collections::HashMap;
+
+use crate::drawer::StackUnderflow;
+
+use super::{
+    ast::{self, Animate, Command, Lighting, Loc, Misc, Shape, Symbol},
+    diagnostics::Report,
+    expr::{Env, Expr, BUILTIN_NAMES},
+    parser::SymTable,
+    result::{EngineError, RuntimeError},
+    types::Kind,
+};
+
+/// Where a symbol is first defined, and whether it's used
+struct Definition {
+    loc: Loc,
+    used: bool,
+}
+
+/// Check every command of the script, in order, add what's wrong to `report`,
+/// and return the kind of every name
+///
+/// `tween` with a knob list that isn't saved before it is reported by `knobs::run_knob_cmds`,
+/// since that depends on the order the knob commands run in.
+pub(crate) fn check_symbols(cmds: &[(Loc, Command)], report: &mut Report) -> SymTable<Kind> {
+    let mut kinds: SymTable<Kind> = SymTable::new();
+    let mut definitions: HashMap<Symbol, Definition> = HashMap::new();
+    // number of parameters of every `def`
+    let mut defs: HashMap<&Symbol, usize> = HashMap::new();
+
+    // everything but coordinate systems and variables can be used before the line it's defined on
+    for (loc, cmd) in ast::flatten(cmds) {
+        let (names, kind) = match cmd {
+            Command::Let { name, .. } => (vec![name], Kind::Var),
+            Command::Repeat { var, .. } => (var.iter().collect(), Kind::Var),
+            Command::Def { name, params, .. } => {
+                if defs.insert(name, params.len()).is_some() {
+                    report.error(EngineError::Runtime {
+                        loc: loc.clone(),
+                        source: RuntimeError::Semantics("there's already a def with this name"),
+                    });
+                }
+                define(&mut kinds, &mut definitions, name, Kind::Def, loc, report);
+                (params.iter().collect(), Kind::Var)
+            }
+            Command::LightingCmd(Lighting::Constants { name, .. }) => (vec![name], Kind::Const),
+            Command::LightingCmd(Lighting::Light { name, .. }) => (vec![name], Kind::Light),
+            Command::AnimateCmd(Animate::SetKnob { name, .. }) => (vec![name], Kind::Knob),
+            Command::AnimateCmd(Animate::Vary(vary)) => (vec![&vary.knob], Kind::Knob),
+            Command::AnimateCmd(Animate::SaveKnobList(name)) => (vec![name], Kind::KnobList),
+            Command::MiscCmd(Misc::SaveCoord(name)) => (vec![name], Kind::Coord),
+            _ => continue,
+        };
+        for name in names {
+            if kind == Kind::Var && BUILTIN_NAMES.contains(&name.0.as_str()) {
+                report.error(EngineError::Runtime {
+                    loc: loc.clone(),
+                    source: RuntimeError::Semantics(
+                        "`pi` and `frame` are built in and can't be set",
+                    ),
+                });
+            } else {
+                define(&mut kinds, &mut definitions, name, kind, loc, report);
+            }
+        }
+    }
+    check_recursion(cmds, report);
+
+    let mut checker = Checker {
+        // `setknobs` gives a value to every knob that's named anywhere
+        all_knobs_set: cmds
+            .iter()
+            .any(|(_, cmd)| matches!(cmd, Command::AnimateCmd(Animate::SetAllKnobs(_)))),
+        kinds,
+        definitions,
+        defs,
+        saved_coords: vec![],
+        report,
+    };
+    checker.check_block(cmds, &mut vec![]);
+    let Checker {
+        kinds, definitions, ..
+    } = checker;
+
+    let mut unused: Vec<(&Symbol, &Definition)> = definitions
+        .iter()
+        // lights aren't used by name
+        .filter(|(name, d)| !d.used && kinds[*name] != Kind::Light)
+        .collect();
+    unused.sort_by(|(_, a), (_, b)| a.loc.cmp(&b.loc));
+    for (name, d) in unused {
+        report.warn(
+            Some(d.loc.clone()),
+            format!("{} {} is never used", kinds[name], name.0),
+        );
+    }
+    kinds
+}
+
+/// Read every shape whose constants name isn't the name of any `constants` without it,
+/// see [`Shape::without_constants`]
+///
+/// Constants can be defined after the shapes that use them, so this is done once every name is known.
+pub(crate) fn resolve_constants(
+    cmds: &mut [(Loc, Command)],
+    is_constants: &impl Fn(&Symbol) -> bool,
+) {
+    for (_, cmd) in cmds.iter_mut() {
+        match cmd {
+            Command::ShapeCmd(shape) => {
+                let other = match shape.without_constants() {
+                    Some((name, other)) if !is_constants(name) => other,
+                    _ => continue,
+                };
+                *shape = other;
+            }
+            Command::Repeat { body, .. } | Command::Def { body, .. } => {
+                resolve_constants(body, is_constants)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// What's known while going through the commands in order
+struct Checker<'a, 'r> {
+    kinds: SymTable<Kind>,
+    definitions: HashMap<Symbol, Definition>,
+    defs: HashMap<&'a Symbol, usize>,
+    all_knobs_set: bool,
+    saved_coords: Vec<&'a Symbol>,
+    report: &'r mut Report,
+}
+
+impl<'a, 'r> Checker<'a, 'r> {
+    /// Check the commands of a block, `defined_vars` are the variables that can be used in it
+    ///
+    /// Variables defined in a block can't be used after it.
+    fn check_block(&mut self, cmds: &'a [(Loc, Command)], defined_vars: &mut Vec<&'a Symbol>) {
+        for (loc, cmd) in cmds {
+            self.check_cmd(loc, cmd, defined_vars);
+        }
+    }
+
+    fn check_cmd(&mut self, loc: &Loc, cmd: &'a Command, defined_vars: &mut Vec<&'a Symbol>) {
+        check_expr_names(
+            cmd,
+            loc,
+            &self.kinds,
+            &mut self.definitions,
+            defined_vars,
+            self.report,
+        );
+
+        let mut used = vec![];
+        match cmd {
+            Command::ShapeCmd(shape) => match shape {
+                Shape::Sphere {
+                    constants, coord, ..
+                }
+                | Shape::Torus {
+                    constants, coord, ..
+                }
+                | Shape::Box {
+                    constants, coord, ..
+                }
+                | Shape::Mesh {
+                    constants, coord, ..
+                } => {
+                    used.push((constants, Kind::Const));
+                    used.push((coord, Kind::Coord));
+                }
+                Shape::Line {
+                    constants,
+                    coord0,
+                    coord1,
+                    ..
+                } => {
+                    used.push((constants, Kind::Const));
+                    used.push((coord0, Kind::Coord));
+                    used.push((coord1, Kind::Coord));
+                }
+            },
+            Command::TransformCmd(transform) => {
+                if let Some(knob) = transform.knob() {
+                    if self.kinds.get(knob).is_none() {
+                        if self.all_knobs_set {
+                            define(
+                                &mut self.kinds,
+                                &mut self.definitions,
+                                knob,
+                                Kind::Knob,
+                                loc,
+                                self.report,
+                            );
+                        } else {
+                            self.report.warn(
+                                Some(loc.clone()),
+                                format!(
+                                    "knob {} is never set, so this transformation doesn't use it",
+                                    knob.0
+                                ),
+                            );
+                            return;
+                        }
+                    }
+                    self.mark_used(knob, Kind::Knob, loc);
+                }
+            }
+            Command::AnimateCmd(Animate::Tween {
+                knoblist0,
+                knoblist1,
+                ..
+            }) => {
+                for list in [knoblist0, knoblist1].iter() {
+                    if self.kinds.get(list).is_some() {
+                        self.mark_used(list, Kind::KnobList, loc);
+                    }
+                }
+            }
+            Command::MiscCmd(Misc::SaveCoord(name)) => self.saved_coords.push(name),
+            Command::Let { name, .. } => defined_vars.push(name),
+            Command::Repeat { var, body, .. } => {
+                let outer = defined_vars.len();
+                defined_vars.extend(var);
+                self.check_block(body, defined_vars);
+                defined_vars.truncate(outer);
+            }
+            // the body runs where it's called, but can only use what's defined before it
+            Command::Def { params, body, .. } => {
+                let outer = defined_vars.len();
+                defined_vars.extend(params);
+                self.check_block(body, defined_vars);
+                defined_vars.truncate(outer);
+            }
+            Command::Call { name, args } => self.check_call(name, args.len(), loc),
+            _ => {}
+        }
+
+        for (symbol, kind) in used {
+            let name = match symbol {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Err(source) = self.kinds.check(symbol, kind) {
+                self.report.error(EngineError::Runtime {
+                    loc: loc.clone(),
+                    source,
+                });
+            } else if kind == Kind::Coord && !self.saved_coords.contains(&name) {
+                self.report.error(EngineError::Runtime {
+                    loc: loc.clone(),
+                    source: RuntimeError::UsedBeforeDefined {
+                        name: name.0.to_owned(),
+                        kind,
+                    },
+                });
+            } else {
+                self.mark_used(name, kind, loc);
+            }
+        }
+    }
+
+    /// A `def` can be called before the line it's defined on
+    fn check_call(&mut self, name: &Symbol, args: usize, loc: &Loc) {
+        if let Err(source) = self.kinds.check(&Some(name.clone()), Kind::Def) {
+            self.report.error(EngineError::Runtime {
+                loc: loc.clone(),
+                source,
+            });
+            return;
+        }
+        self.mark_used(name, Kind::Def, loc);
+        let params = self.defs[name];
+        if params != args {
+            self.report.error(EngineError::Runtime {
+                loc: loc.clone(),
+                source: RuntimeError::WrongArgCount {
+                    name: name.0.to_owned(),
+                    expected: params,
+                    found: args,
+                },
+            });
+        }
+    }
+
+    fn mark_used(&mut self, name: &Symbol, kind: Kind, loc: &Loc) {
+        mark_used(
+            &self.kinds,
+            &mut self.definitions,
+            name,
+            kind,
+            loc,
+            self.report,
+        );
+    }
+}
+
+/// Every call that leads back to the `def` it's in is an error, since nothing can stop it
+pub(crate) fn check_recursion(cmds: &[(Loc, Command)], report: &mut Report) {
+    // the calls in the body of every `def`
+    let calls: HashMap<&Symbol, Vec<(&Loc, &Symbol)>> = cmds
+        .iter()
+        .filter_map(|(_, cmd)| match cmd {
+            Command::Def { name, body, .. } => Some((
+                name,
+                ast::flatten(body)
+                    .into_iter()
+                    .filter_map(|(loc, cmd)| match cmd {
+                        Command::Call { name, .. } => Some((loc, name)),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+            _ => None,
+        })
+        .collect();
+
+    for (def, def_calls) in calls.iter() {
+        for (loc, callee) in def_calls {
+            // defs reached from `callee`
+            let mut seen = vec![*callee];
+            let mut next = vec![*callee];
+            while let Some(name) = next.pop() {
+                for (_, callee) in calls.get(name).into_iter().flatten() {
+                    if !seen.contains(callee) {
+                        seen.push(callee);
+                        next.push(callee);
+                    }
+                }
+            }
+            if seen.contains(def) {
+                report.error(EngineError::Runtime {
+                    loc: (*loc).clone(),
+                    source: RuntimeError::RecursiveCall(def.0.to_owned()),
+                });
+            }
+        }
+    }
+}
+
+/// Every `pop` with nothing pushed is an error, and every `push` that's still open at the end of the script is a warning
+///
+/// Still images and frames both start with an empty stack, so this holds for every frame.
+/// A `call` is followed into its `def`, and a `repeat` is followed as many times as it runs if its count is a number,
+/// otherwise once.
+pub(crate) fn check_stack(cmds: &[(Loc, Command)], report: &mut Report) {
+    let mut stack = Stack {
+        defs: cmds
+            .iter()
+            .filter_map(|(_, cmd)| match cmd {
+                Command::Def { name, body, .. } => Some((name, body.as_slice())),
+                _ => None,
+            })
+            .collect(),
+        pushes: vec![],
+        calls: vec![],
+        underflows: vec![],
+    };
+    stack.run(cmds);
+
+    // a line in a block or a `def` can be reached more than once
+    let Stack {
+        mut pushes,
+        mut underflows,
+        ..
+    } = stack;
+    underflows.sort();
+    underflows.dedup();
+    for loc in underflows {
+        report.error(EngineError::Runtime {
+            loc: loc.clone(),
+            source: RuntimeError::StackUnderflow(StackUnderflow),
+        });
+    }
+    pushes.sort();
+    pushes.dedup();
+    for loc in pushes {
+        report.warn(
+            Some(loc.clone()),
+            "`push` is never popped, so it's still open at the end of the script",
+        );
+    }
+}
+
+/// The coordinate stack while going through the commands in the order they run
+struct Stack<'a> {
+    defs: HashMap<&'a Symbol, &'a [(Loc, Command)]>,
+    /// Where every level of the stack is pushed
+    pushes: Vec<&'a Loc>,
+    /// `def`s that are running, so a recursive one is only followed once, it's reported by `check_recursion`
+    calls: Vec<&'a Symbol>,
+    /// `pop`s with nothing pushed, every time they run
+    underflows: Vec<&'a Loc>,
+}
+
+impl<'a> Stack<'a> {
+    fn run(&mut self, cmds: &'a [(Loc, Command)]) {
+        for (loc, cmd) in cmds {
+            match cmd {
+                Command::Push => self.pushes.push(loc),
+                Command::Pop => {
+                    let popped = self.pushes.pop();
+                    // the script stops there, so the stack is left as it is
+                    if popped.is_none() {
+                        self.underflows.push(loc);
+                    }
+                }
+                Command::Repeat { count, body, .. } => self.repeat(count, body),
+                Command::Call { name, .. } => {
+                    if let Some(body) = self.defs.get(name).copied() {
+                        if !self.calls.contains(&name) {
+                            self.calls.push(name);
+                            self.run(body);
+                            self.calls.pop();
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// After the first run, a body that doesn't take more from the stack than it adds does the same thing every time,
+    /// so only a body that shrinks the stack needs to be followed for every run, until it pops with nothing pushed.
+    fn repeat(&mut self, count: &Expr, body: &'a [(Loc, Command)]) {
+        let nothing = SymTable::new();
+        let env = Env {
+            vars: &nothing,
+            knobs: &nothing,
+            frame: None,
+        };
+        let runs = match count.eval(&env) {
+            Ok(count) if count >= 0. && count.fract() == 0. => count as u64,
+            // a bad count is reported when the script runs
+            _ => 1,
+        };
+        for run in 0..runs {
+            let (depth, underflows) = (self.pushes.len(), self.underflows.len());
+            self.run(body);
+            if self.underflows.len() > underflows || (run > 0 && self.pushes.len() >= depth) {
+                break;
+            }
+        }
+    }
+}
+
+/// Names in expressions are variables defined on an earlier line, or knobs
+fn check_expr_names(
+    cmd: &Command,
+    loc: &Loc,
+    kinds: &SymTable<Kind>,
+    definitions: &mut HashMap<Symbol, Definition>,
+    defined_vars: &[&Symbol],
+    report: &mut Report,
+) {
+    let mut names: Vec<&Symbol> = vec![];
+    for name in cmd.exprs().into_iter().flat_map(|e| e.names()) {
+        if !names.contains(&name) && !BUILTIN_NAMES.contains(&name.0.as_str()) {
+            names.push(name);
+        }
+    }
+
+    for name in names {
+        let source = match kinds.get(name) {
+            Some(Kind::Knob) => {
+                mark_used(kinds, definitions, name, Kind::Knob, loc, report);
+                continue;
+            }
+            Some(Kind::Var) if defined_vars.contains(&name) => {
+                mark_used(kinds, definitions, name, Kind::Var, loc, report);
+                continue;
+            }
+            Some(Kind::Var) => RuntimeError::UsedBeforeDefined {
+                name: name.0.to_owned(),
+                kind: Kind::Var,
+            },
+            Some(&kind) => RuntimeError::SymbolTypeMismatch {
+                name: name.0.to_owned(),
+                expected: kind,
+                found: Kind::Var,
+            },
+            None => RuntimeError::UndefinedSymbol {
+                name: name.0.to_owned(),
+                kind: Kind::Var,
+            },
+        };
+        report.error(EngineError::Runtime {
+            loc: loc.clone(),
+            source,
+        });
+    }
+}
+
+/// Add `name` to the table, it's an error if it's already something else
+fn define(
+    kinds: &mut SymTable<Kind>,
+    definitions: &mut HashMap<Symbol, Definition>,
+    name: &Symbol,
+    kind: Kind,
+    loc: &Loc,
+    report: &mut Report,
+) {
+    if let Some(&defined) = kinds.get(name) {
+        if defined != kind {
+            report.error(EngineError::Runtime {
+                loc: loc.clone(),
+                source: RuntimeError::SymbolTypeMismatch {
+                    name: name.0.to_owned(),
+                    expected: defined,
+                    found: kind,
+                },
+            });
+        }
+        return;
+    }
+    kinds.insert(name.clone(), kind);
+    definitions.insert(
+        name.clone(),
+        Definition {
+            loc: loc.clone(),
+            used: false,
+        },
+    );
+}
+
+fn mark_used(
+    kinds: &SymTable<Kind>,
+    definitions: &mut HashMap<Symbol, Definition>,
+    name: &Symbol,
+    kind: Kind,
+    loc: &Loc,
+    report: &mut Report,
+) {
+    match kinds.check(&Some(name.clone()), kind) {
+        Ok(()) => {
+            if let Some(d) = definitions.get_mut(name) {
+                d.used = true;
+            }
+        }
+        Err(source) => report.error(EngineError::Runtime {
+            loc: loc.clone(),
+            source,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mdl::ast::{parse_line, Line};
+
+    /// Check lines without blocks
+    fn check(lines: &[&str]) -> Report {
+        let cmds: Vec<(Loc, Command)> = lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| match parse_line(line).unwrap().1 {
+                Some(Line::Cmd(cmd)) => Some((Loc::line(i + 1), *cmd)),
+                _ => None,
+            })
+            .collect();
+        let mut report = Report::default();
+        check_symbols(&cmds, &mut report);
+        report
+    }
+
+    #[test]
+    fn test_undefined_and_mismatched() {
+        let report = check(&[
+            "constants shiny 1 1 1 1 1 1 1 1 1",
+            "set k 1",
+            "sphere dull 0 0 0 10",
+            "sphere k 0 0 0 10",
+            "box shiny 0 0 0 1 1 1 later",
+            "save_coord_system later",
+            "rotate x 90 shiny",
+        ]);
+        let errors: Vec<String> = report
+            .errors
+            .iter()
+            .map(|e| match e {
+                EngineError::Runtime { loc, source } => format!("{}: {}", loc.line, source),
+                other => panic!("unexpected error {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "3: constants dull is not defined",
+                "4: k is defined as knob but used as constants",
+                "5: coord_system later is used before it's defined",
+                "7: shiny is defined as constants but used as knob",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_unused_definitions() {
+        let report = check(&[
+            "constants shiny 1 1 1 1 1 1 1 1 1",
+            "constants dull 1 1 1 1 1 1 1 1 1",
+            "light l 255 255 255 0 0 1",
+            "vary spin 0 9 0 1",
+            "save_coord_system c",
+            "sphere shiny 0 0 0 10",
+        ]);
+        assert!(report.errors.is_empty());
+        let warnings: Vec<Option<usize>> = report
+            .warnings
+            .iter()
+            .map(|w| w.loc.as_ref().map(|loc| loc.line))
+            .collect();
+        assert_eq!(vec![Some(2), Some(4), Some(5)], warnings);
+    }
+
+    #[test]
+    fn test_expression_names() {
+        let report = check(&[
+            "let r = 10 * k",
+            "set k 1",
+            "sphere 0 0 0 r*2",
+            "move later 0 frame",
+            "let later = pi",
+            "box 0 0 0 1 1 1*nothing",
+            "constants c 1 1 1 1 1 1 1 1 1",
+            "scale c 1 1",
+        ]);
+        let errors: Vec<String> = report.errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "runtime error on line 4: variable later is used before it's defined",
+                "runtime error on line 6: variable nothing is not defined",
+                "runtime error on line 8: c is defined as constants but used as variable",
+            ],
+            errors
+        );
+        // `later` and `c` are only used where it's an error
+        let warnings: Vec<Option<usize>> = report
+            .warnings
+            .iter()
+            .map(|w| w.loc.as_ref().map(|loc| loc.line))
+            .collect();
+        assert_eq!(vec![Some(5), Some(7)], warnings);
+    }
+
+    #[test]
+    fn test_setknobs_defines_every_knob() {
+        let report = check(&["setknobs 1", "move 1 0 0 k", "rotate y 90 k"]);
+        assert!(report.errors.is_empty());
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_blocks() {
+        let mut report = Report::default();
+        let name = |s: &str| Symbol(s.to_owned());
+        let block = |count: f64, var: &str, body| Command::Repeat {
+            count: crate::mdl::expr::Expr::Num(count),
+            var: Some(name(var)),
+            body,
+        };
+        let call = |def: &str, args: usize| Command::Call {
+            name: name(def),
+            args: vec![crate::mdl::expr::Expr::Num(1.); args],
+        };
+        let sphere = |line: &str| match parse_line(line).unwrap().1 {
+            Some(Line::Cmd(cmd)) => *cmd,
+            other => panic!("expected a command, got {:?}", other),
+        };
+        let cmds = vec![
+            (
+                Loc::line(1),
+                block(
+                    3.,
+                    "i",
+                    vec![
+                        (Loc::line(2), sphere("sphere 0 0 i 10")),
+                        (Loc::line(3), call("a", 1)),
+                    ],
+                ),
+            ),
+            (Loc::line(4), sphere("sphere 0 0 i 10")),
+            (
+                Loc::line(5),
+                Command::Def {
+                    name: name("a"),
+                    params: vec![name("x")],
+                    body: vec![
+                        (Loc::line(6), sphere("sphere 0 0 x 10")),
+                        (Loc::line(7), call("b", 0)),
+                    ],
+                },
+            ),
+            (
+                Loc::line(8),
+                Command::Def {
+                    name: name("b"),
+                    params: vec![],
+                    body: vec![(
+                        Loc::line(9),
+                        block(2., "j", vec![(Loc::line(10), call("a", 1))]),
+                    )],
+                },
+            ),
+            (Loc::line(11), call("a", 2)),
+            (Loc::line(12), call("c", 0)),
+        ];
+        check_symbols(&cmds, &mut report);
+        report.errors.sort_by(|a, b| a.loc().cmp(&b.loc()));
+        let errors: Vec<String> = report.errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "runtime error on line 4: variable i is used before it's defined",
+                "runtime error on line 7: a ends up calling itself, so it would never finish",
+                "runtime error on line 10: b ends up calling itself, so it would never finish",
+                "runtime error on line 11: a takes 1 arguments, but is called with 2",
+                "runtime error on line 12: def c is not defined",
+            ],
+            errors
+        );
+        // the loop variable of `b` is never used
+        let warnings: Vec<Option<usize>> = report
+            .warnings
+            .iter()
+            .map(|w| w.loc.as_ref().map(|loc| loc.line))
+            .collect();
+        assert_eq!(vec![Some(9)], warnings);
+    }
+
+    #[test]
+    fn test_stack_depth() {
+        let script: Vec<String> = [
+            "let n = 3",
+            "push",
+            "def arm() {",
+            "  push",
+            "  rotate z 30",
+            "  box 0 0 0 1 1 1",
+            "}",
+            "repeat 2 {",
+            "  call arm()",
+            "  pop",
+            "}",
+            "pop",
+            "pop",
+            "push",
+            "repeat n {",
+            "  push",
+            "}",
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+        match crate::mdl::parser::parse_script(script, None) {
+            Err(EngineError::Diagnostics(report)) => {
+                // the pop on line 10 takes what `arm` pushes, so only the second pop at the end is past the base
+                let errors: Vec<String> = report.errors.iter().map(|e| e.to_string()).collect();
+                assert_eq!(
+                    vec!["runtime error on line 13: pop with nothing pushed, so there's no coordinate system to go back to"],
+                    errors
+                );
+                let warnings: Vec<Option<usize>> = report
+                    .warnings
+                    .iter()
+                    .map(|w| w.loc.as_ref().map(|loc| loc.line))
+                    .collect();
+                assert_eq!(vec![Some(14), Some(16)], warnings);
+            }
+            other => panic!("expected diagnostics, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Readable syntax errors, with the line, a caret under the problem and the usage of the command,
+//! and the report that gathers every error and warning of a script
+
+use std::fmt;
+
+use nom::error::ErrorKind;
+
+use super::{
+    ast::{Loc, ParseFailure},
+    result::EngineError,
+};
+
+/// Every command keyword of MDL
+const KEYWORDS: [&str; 36] = [
+    "push",
+    "pop",
+    "move",
+    "scale",
+    "rotate",
+    "sphere",
+    "torus",
+    "box",
+    "line",
+    "mesh",
+    "basename",
+    "set",
+    "save_knobs",
+    "tween",
+    "frames",
+    "vary",
+    "setknobs",
+    "fps",
+    "loop",
+    "output",
+    "light",
+    "ambient",
+    "constants",
+    "shading",
+    "save_coord_system",
+    "camera",
+    "save",
+    "generate_rayfiles",
+    "focal",
+    "display",
+    "screen",
+    "let",
+    "repeat",
+    "def",
+    "call",
+    "include",
+];
+
+const SPEC: &str = include_str!("../../scripts/MDL.spec");
+
+/// A syntax error in a line of a script
+#[derive(Debug, PartialEq, Clone)]
+pub struct SyntaxError {
+    pub loc: Loc,
+    /// Column where parsing failed, starting from 1
+    pub column: usize,
+    /// Text of the whole line
+    pub source_line: String,
+    pub message: String,
+    /// Command the line starts with, if it's a known one
+    pub command: Option<&'static str>,
+    /// Usage of `command`, as written in MDL.spec
+    pub usage: Option<&'static str>,
+    /// Closest keyword to a misspelled one
+    pub suggestion: Option<&'static str>,
+    /// Number of characters to underline
+    span: usize,
+}
+
+impl SyntaxError {
+    pub(crate) fn new(loc: Loc, source_line: &str, failure: &ParseFailure) -> Self {
+        let offset = source_line.len() - failure.input.len();
+        let first_word = source_line.split_whitespace().next().unwrap_or("");
+        let command = KEYWORDS.iter().copied().find(|k| *k == first_word);
+        let usage = command.and_then(usage);
+
+        let (offset, message, suggestion) = match command {
+            None => (
+                source_line.len() - source_line.trim_start().len(),
+                format!("unknown command `{}`", first_word),
+                closest(first_word, KEYWORDS.iter().copied()),
+            ),
+            Some(_) => {
+                let token = failure.input.split_whitespace().next().unwrap_or("");
+                // some arguments are keywords, like the axis of `rotate` or the mode of `shading`,
+                // and they're only choices for the argument that failed
+                let slot = source_line[..offset].split_whitespace().count();
+                let choices: Vec<&'static str> = usage
+                    .and_then(|usage| usage.split_whitespace().nth(slot))
+                    .filter(|arg| arg.contains('|'))
+                    .into_iter()
+                    .flat_map(|arg| arg.trim_matches(|c| c == '[' || c == ']').split('|'))
+                    .collect();
+                let message = match (failure.context, failure.kind) {
+                    (Some(wanted), _) => found(wanted, token),
+                    (None, ErrorKind::Tag) | (None, ErrorKind::OneOf) if !choices.is_empty() => {
+                        found(&format!("one of {}", choices.join(", ")), token)
+                    }
+                    (None, kind) => expected(kind, token),
+                };
+                (offset, message, closest(token, choices.into_iter()))
+            }
+        };
+
+        let rest = &source_line[offset..];
+        let span = rest
+            .split_whitespace()
+            .next()
+            .filter(|_| !rest.starts_with(char::is_whitespace))
+            .map_or(1, |token| token.chars().count());
+
+        SyntaxError {
+            loc,
+            column: source_line[..offset].chars().count() + 1,
+            source_line: source_line.to_owned(),
+            message,
+            command,
+            usage,
+            suggestion,
+            span,
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "syntax error on {}, column {}", self.loc, self.column)?;
+        if let Some(command) = self.command {
+            write!(f, " in `{}`", command)?;
+        }
+        writeln!(f, ": {}", self.message)?;
+
+        let number = self.loc.line.to_string();
+        let gutter = " ".repeat(number.len());
+        // keep tabs, so the caret lines up with the text above it
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        writeln!(f, " {} | {}", number, self.source_line)?;
+        write!(f, " {} | {}{}", gutter, indent, "^".repeat(self.span))?;
+
+        if let Some(usage) = self.usage {
+            write!(f, "\n {} = usage: {}", gutter, usage)?;
+        }
+        if let Some(suggestion) = self.suggestion {
+            write!(f, "\n {} = help: did you mean `{}`?", gutter, suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// A problem that doesn't stop the script from running
+#[derive(Debug, PartialEq, Clone)]
+pub struct Warning {
+    /// None if it's about the whole script
+    pub loc: Option<Loc>,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.loc {
+            Some(loc) => write!(f, "warning on {}: {}", loc, self.message),
+            None => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+/// Every error and warning found before running a script
+#[derive(Debug, Default)]
+pub struct Report {
+    pub errors: Vec<EngineError>,
+    pub warnings: Vec<Warning>,
+}
+
+impl Report {
+    pub(crate) fn error(&mut self, error: EngineError) {
+        self.errors.push(error);
+    }
+
+    pub(crate) fn warn(&mut self, loc: Option<Loc>, message: impl Into<String>) {
+        self.warnings.push(Warning {
+            loc,
+            message: message.into(),
+        });
+    }
+
+    /// `value` and the warnings if nothing went wrong, otherwise the whole report as an error
+    pub(crate) fn finish<T>(mut self, value: T) -> Result<(T, Vec<Warning>), EngineError> {
+        // problems found by later checks can be on earlier lines
+        self.warnings.sort_by(|a, b| a.loc.cmp(&b.loc));
+        if self.errors.is_empty() {
+            Ok((value, self.warnings))
+        } else {
+            self.errors.sort_by(|a, b| a.loc().cmp(&b.loc()));
+            Err(EngineError::Diagnostics(self))
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for error in self.errors.iter() {
+            writeln!(f, "{}", error)?;
+        }
+        for warning in self.warnings.iter() {
+            writeln!(f, "{}", warning)?;
+        }
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        write!(
+            f,
+            "{} error{}, {} warning{}",
+            self.errors.len(),
+            plural(self.errors.len()),
+            self.warnings.len(),
+            plural(self.warnings.len())
+        )
+    }
+}
+
+/// Describe what the parser wanted to find in place of `token`
+fn expected(kind: ErrorKind, token: &str) -> String {
+    let wanted = match kind {
+        ErrorKind::Float => "a number",
+        ErrorKind::TakeWhile1 => "a whole number",
+        ErrorKind::MapRes => "a whole number in range",
+        ErrorKind::Alpha => "a name",
+        ErrorKind::IsNot => "a file name",
+        ErrorKind::OneOf | ErrorKind::Tag => "a keyword",
+        ErrorKind::Char => "`)`",
+        ErrorKind::Verify => "a function with the right number of arguments",
+        _ if token.is_empty() => "more arguments",
+        _ => return format!("unexpected `{}`", token),
+    };
+    found(wanted, token)
+}
+
+fn found(wanted: &str, token: &str) -> String {
+    if token.is_empty() {
+        format!("expected {}, found the end of the line", wanted)
+    } else {
+        format!("expected {}, found `{}`", wanted, token)
+    }
+}
+
+/// Usage of `keyword` from MDL.spec
+///
+/// That's the last line that starts with the keyword, up to its description.
+fn usage(keyword: &str) -> Option<&'static str> {
+    SPEC.lines()
+        .rfind(|line| {
+            line.strip_prefix(keyword)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+        })
+        .map(|line| line.split('\t').next().unwrap_or(line).trim_end())
+}
+
+/// The choice closest to `word`, if it's close enough to be a typo
+fn closest<'a>(word: &str, choices: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    if word.is_empty() {
+        return None;
+    }
+    let len = word.chars().count();
+    let max_distance = (len / 3).max(1);
+    choices
+        .filter(|choice| *choice != word)
+        .map(|choice| (edit_distance(word, choice), choice))
+        // a one letter word is one edit away from every other one
+        .filter(|(distance, _)| *distance <= max_distance && *distance < len)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, choice)| choice)
+}
+
+/// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + (ca != *cb) as usize)
+                .min(row[j] + 1)
+                .min(above + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mdl::ast::parse_line;
+
+    fn error(line: &str) -> SyntaxError {
+        match parse_line(line) {
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                SyntaxError::new(Loc::line(3), line, &e)
+            }
+            other => panic!("\"{}\" should not parse: {:?}", line, other),
+        }
+    }
+
+    #[test]
+    fn test_every_keyword_has_usage() {
+        for keyword in KEYWORDS.iter() {
+            assert!(usage(keyword).is_some(), "{} is not in MDL.spec", keyword);
+        }
+        assert_eq!(
+            Some("sphere [constants] x y z r [coord_system]"),
+            usage("sphere")
+        );
+        assert_eq!(Some("rotate x|y|z degrees [knob]"), usage("rotate"));
+    }
+
+    #[test]
+    fn test_points_at_bad_argument() {
+        let e = error("sphere 0 0 #1 50");
+        assert_eq!(12, e.column);
+        assert_eq!(Some("sphere"), e.command);
+        assert_eq!(
+            "syntax error on line 3, column 12 in `sphere`: expected a number, found `#1`\n \
+             3 | sphere 0 0 #1 50\n   \
+             |            ^^\n   \
+             = usage: sphere [constants] x y z r [coord_system]",
+            e.to_string()
+        );
+
+        let e = error("move 1 2");
+        assert_eq!(9, e.column);
+        assert_eq!("expected a number, found the end of the line", e.message);
+
+        let e = error("box 0 0 0 1 1 1 c1 c2 // comment");
+        assert_eq!("unexpected `c2`", e.message);
+
+        let e = error("repeat 3 as i");
+        assert_eq!("expected `{`, found the end of the line", e.message);
+        assert_eq!(Some("repeat count [as var] {"), e.usage);
+    }
+
+    #[test]
+    fn test_suggests_keyword() {
+        let e = error("  sphre 0 0 0 50");
+        assert_eq!(3, e.column);
+        assert_eq!(None, e.command);
+        assert_eq!("unknown command `sphre`", e.message);
+        assert_eq!(Some("sphere"), e.suggestion);
+
+        assert_eq!(Some("gouraud"), error("shading gourad").suggestion);
+        let e = error("rotate q 30");
+        assert_eq!("expected one of x, y, z, found `q`", e.message);
+        assert_eq!(None, e.suggestion);
+        assert_eq!(None, error("bogus 1 2 3").suggestion);
+        // one character, but two bytes
+        assert_eq!(None, error("rotate é 30").suggestion);
+    }
+
+    #[test]
+    fn test_choices_of_failing_argument() {
+        let e = error("rotate x 30 é");
+        assert_eq!(13, e.column);
+        assert_eq!("unexpected `é`", e.message);
+        assert_eq!(None, e.suggestion);
+    }
+}
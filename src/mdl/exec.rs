@@ -1,126 +1,558 @@
+use std::{collections::HashMap, rc::Rc};
+
 use indicatif::ProgressBar;
 
-use crate::{light::LightProps, matrix::transform as tr, Drawer, Matrix, PPMImg};
+use crate::{
+    camera::{self, Camera},
+    light::{LightProps, Shading},
+    matrix::{obj::Obj, transform as tr},
+    vector::Vec3,
+    Canvas, Drawer, Matrix,
+};
 
 use super::{
-    ast::{self, Command, Symbol},
+    ast::{self, Command, Loc, Symbol},
+    diagnostics::Report,
+    expr::{Env, EvalError},
     parser::SymTable,
-    result::{EngineError, EngineResult},
-    utils::{warn_disabled_in_animation, warn_unimpl},
+    result::{EngineError, EngineResult, RuntimeError},
+    save_frame,
+    types::Kind,
+    utils::frame_path,
 };
 
-pub(crate) fn exec_no_animation(
-    commands: Vec<(usize, Command)>,
-    script: &[String],
+pub(crate) fn exec_no_animation<C: Canvas>(
+    commands: &[(Loc, Command)],
+    knobs: &SymTable<f64>,
     light_props: &SymTable<LightProps>,
-    drawer: &mut Drawer<PPMImg>,
-    pgbar: &ProgressBar,
+    meshes: &Meshes,
+    drawer: &mut Drawer<C>,
+    output: Output,
 ) -> EngineResult<()> {
-    // let mut magick = pipe_to_magick(vec!["ppm:-", &format!("{}.png", basename)]);
-    // let magick_in = magick.stdin.take().unwrap();
-
-    for (line, cmd) in commands {
+    if let Output::Files(Some(pgbar)) = output {
         pgbar.set_message("Rendering image");
+    }
+    let mut state = State::new();
+    state.define(commands);
+    Run::new(drawer, knobs, light_props, meshes, &mut state, None, output).run(commands)
+}
+
+pub(crate) fn exec_once_with_animation<C: Canvas>(
+    commands: &[(Loc, Command)],
+    knobs: &SymTable<f64>,
+    drawer: &mut Drawer<C>,
+    light_props: &SymTable<LightProps>,
+    meshes: &Meshes,
+    // the frame and the number of frames
+    animation: (u32, u32),
+    output: Output,
+) -> EngineResult<()> {
+    let mut state = State::new();
+    state.define(commands);
+    Run::new(
+        drawer,
+        knobs,
+        light_props,
+        meshes,
+        &mut state,
+        Some(animation),
+        output,
+    )
+    .run(commands)
+}
+
+/// What `save` and `display` do
+#[derive(Clone, Copy)]
+pub(crate) enum Output<'a> {
+    /// Write files and show images, and say so on the progress bar if there is one
+    Files(Option<&'a ProgressBar>),
+    /// Keep the image in memory, so `save` and `display` do nothing
+    Memory,
+}
+
+/// Parameters and body of a `def`
+#[derive(Debug)]
+pub(crate) struct Def {
+    pub(crate) params: Vec<Symbol>,
+    pub(crate) body: Vec<(Loc, Command)>,
+}
+
+/// What commands leave behind for the commands after them
+///
+/// Every image and every frame starts from a new one, the repl keeps the same one between lines.
+#[derive(Debug)]
+pub(crate) struct State {
+    pub(crate) defs: SymTable<Rc<Def>>,
+    /// Coordinate systems saved with `save_coord_system`
+    pub(crate) coords: SymTable<Matrix>,
+    /// `let` variables, loop variables and parameters
+    pub(crate) vars: SymTable<f64>,
+    pub(crate) focal: f64,
+}
+
+impl State {
+    pub(crate) fn new() -> Self {
+        Self {
+            defs: SymTable::new(),
+            coords: SymTable::new(),
+            vars: SymTable::new(),
+            focal: camera::DEFAULT_FOCAL,
+        }
+    }
+
+    /// Remember the `def`s in `commands`, which can be called before the line they're on
+    pub(crate) fn define(&mut self, commands: &[(Loc, Command)]) {
+        for (_, cmd) in commands {
+            if let Command::Def { name, params, body } = cmd {
+                let def = Def {
+                    params: params.to_owned(),
+                    body: body.to_owned(),
+                };
+                self.defs.insert(name.to_owned(), Rc::new(def));
+            }
+        }
+    }
+}
+
+/// Everything that's needed to run the commands of an image, or of one frame
+pub(crate) struct Run<'a, C: Canvas> {
+    drawer: &'a mut Drawer<C>,
+    knobs: &'a SymTable<f64>,
+    light_props: &'a SymTable<LightProps>,
+    meshes: &'a Meshes,
+    state: &'a mut State,
+    /// The frame being rendered and the number of frames, None for a still image
+    animation: Option<(u32, u32)>,
+    output: Output<'a>,
+}
+
+impl<'a, C: Canvas> Run<'a, C> {
+    pub(crate) fn new(
+        drawer: &'a mut Drawer<C>,
+        knobs: &'a SymTable<f64>,
+        light_props: &'a SymTable<LightProps>,
+        meshes: &'a Meshes,
+        state: &'a mut State,
+        animation: Option<(u32, u32)>,
+        output: Output<'a>,
+    ) -> Self {
+        Self {
+            drawer,
+            knobs,
+            light_props,
+            meshes,
+            state,
+            animation,
+            output,
+        }
+    }
+
+    fn run(&mut self, commands: &[(Loc, Command)]) -> EngineResult<()> {
+        for (loc, cmd) in commands {
+            self.exec(loc, cmd)?;
+        }
+        Ok(())
+    }
+
+    /// Run `body` with `vars` set, blocks don't change the variables outside of them
+    fn run_block(
+        &mut self,
+        body: &[(Loc, Command)],
+        vars: impl Iterator<Item = (Symbol, f64)>,
+    ) -> EngineResult<()> {
+        let outer = self.state.vars.clone();
+        self.state.vars.extend(vars);
+        let result = self.run(body);
+        self.state.vars = outer;
+        result
+    }
+
+    pub(crate) fn exec(&mut self, loc: &Loc, cmd: &Command) -> EngineResult<()> {
+        let drawer = &mut *self.drawer;
+        // a still image is frame 0
+        let env = Env {
+            vars: &self.state.vars,
+            knobs: self.knobs,
+            frame: Some(self.animation.map_or(0, |(frame, _)| frame)),
+        };
         match cmd {
             Command::Push => drawer.push_matrix(),
-            Command::Pop => drawer.pop_matrix(),
-            Command::TransformCmd(transform) => drawer.transform_by(&match transform {
-                ast::Transform::Move { values, knob: _ } => tr::mv(values.0, values.1, values.2),
-                ast::Transform::Scale { values, knob: _ } => {
-                    tr::scale(values.0, values.1, values.2)
+            Command::Pop => drawer.try_pop_matrix().map_err(|e| EngineError::Runtime {
+                loc: loc.clone(),
+                source: RuntimeError::StackUnderflow(e),
+            })?,
+            Command::TransformCmd(transform) => {
+                drawer.transform_by(&transform_matrix(transform, &env, loc)?)
+            }
+            Command::ShapeCmd(shape) => draw_shape(
+                shape,
+                &env,
+                drawer,
+                self.light_props,
+                self.meshes,
+                &self.state.coords,
+                loc,
+            )?,
+            // all animation commands are handled before execution
+            Command::AnimateCmd(_) => {
+                return Err(EngineError::Runtime {
+                    loc: loc.clone(),
+                    source: RuntimeError::Unsupported("animation commands"),
+                })
+            }
+            Command::LightingCmd(lighting) => set_shading(lighting, drawer, loc)?,
+            Command::Let { name, value } => {
+                let value = eval(value.eval(&env), loc)?;
+                self.state.vars.insert(name.to_owned(), value);
+            }
+            Command::Repeat { count, var, body } => {
+                let count = eval(count.eval(&env), loc)?;
+                if !(count >= 0. && count.fract() == 0.) {
+                    return Err(EngineError::Runtime {
+                        loc: loc.clone(),
+                        source: RuntimeError::RepeatCount(count),
+                    });
                 }
-                ast::Transform::Rotate {
-                    axis,
-                    degrees,
-                    knob: _,
-                } => match axis {
-                    'x' => tr::rotatex(degrees),
-                    'y' => tr::rotatey(degrees),
-
-                    'z' => tr::rotatez(degrees),
-                    _ => unreachable!(),
-                },
-            }),
-            Command::ShapeCmd(shape) => match shape {
-                ast::Shape::Sphere {
-                    constants,
-                    center,
-                    r,
-                    coord,
-                } => drawer.add_sphere(center.into(), r, light_props.find(&constants)?),
-                ast::Shape::Torus {
-                    constants,
-                    center,
-                    r0,
-                    r1,
-                    coord,
-                } => drawer.add_torus(center.into(), r0, r1, light_props.find(&constants)?),
-                ast::Shape::Box {
-                    constants,
-                    corner,
-                    height,
-                    width,
-                    depth,
-                    coord,
-                } => drawer.add_box(
-                    corner.into(),
-                    width,
-                    height,
-                    depth,
-                    light_props.find(&constants)?,
-                ),
-                ast::Shape::Line {
-                    constants,
-                    point0,
-                    coord0,
-                    point1,
-                    coord1,
-                } => drawer.draw_line(point0.into(), point1.into()),
-                ast::Shape::Mesh {
-                    constants: _,
-                    filename: _,
-                    coord: _,
-                } => warn_unimpl("mesh", line),
-            },
-            Command::AnimateCmd(a) => match a {
-                ast::Animate::Basename(_) => unreachable!(),
-                ast::Animate::SetKnob { name: _, value: _ } => warn_unimpl("set_knob", line),
-                ast::Animate::SetAllKnobs(_) => warn_unimpl("set_knobs", line),
-                ast::Animate::Tween {
-                    start_frame: _,
-                    end_frame: _,
-                    knoblist0: _,
-                    knoblist1: _,
-                } => warn_unimpl("tween", line),
-                ast::Animate::Frames(_) => unreachable!(),
-                ast::Animate::Vary(_) => unreachable!(),
-                ast::Animate::SaveKnobList(_) => warn_unimpl("save_knoblist", line),
-            },
-            Command::LightingCmd(_) => unreachable!(),
+                for i in 0..count as u64 {
+                    let var = var.iter().map(|var| (var.to_owned(), i as f64));
+                    self.run_block(body, var)?;
+                }
+            }
+            // they are collected before running
+            Command::Def { .. } => {}
+            Command::Call { name, args } => {
+                // calls in a script are checked before running, but not the ones typed in the repl
+                let def = match self.state.defs.get(name) {
+                    Some(def) => Rc::clone(def),
+                    None => {
+                        return Err(EngineError::Runtime {
+                            loc: loc.clone(),
+                            source: RuntimeError::UndefinedSymbol {
+                                name: name.0.to_owned(),
+                                kind: Kind::Def,
+                            },
+                        })
+                    }
+                };
+                if def.params.len() != args.len() {
+                    return Err(EngineError::Runtime {
+                        loc: loc.clone(),
+                        source: RuntimeError::WrongArgCount {
+                            name: name.0.to_owned(),
+                            expected: def.params.len(),
+                            found: args.len(),
+                        },
+                    });
+                }
+                let args = eval(
+                    args.iter()
+                        .map(|arg| arg.eval(&env))
+                        .collect::<Result<Vec<f64>, EvalError>>(),
+                    loc,
+                )?;
+                self.run_block(&def.body, def.params.iter().cloned().zip(args))?;
+            }
             Command::MiscCmd(cmd) => match cmd {
-                ast::Misc::SaveCoord(_) => warn_unimpl("save_coord_system", line),
-                ast::Misc::Camera { eye: _, aim: _ } => warn_unimpl("camera", line),
-                ast::Misc::Save(filepath) => {
-                    pgbar.set_message("Saving image with magick");
-                    let status = drawer.save(&filepath).map_err(|e| EngineError::Runtime {
-                        line,
-                        source: e.into(),
-                    })?;
-                    pgbar.println(&format!("File \"{}\" saved. `magick` {}", filepath, status));
+                ast::Misc::SaveCoord(name) => {
+                    self.state
+                        .coords
+                        .insert(name.to_owned(), drawer.get_top_matrix().clone());
+                }
+                ast::Misc::Camera { eye, aim } => {
+                    set_camera(drawer, eye, aim, &env, self.state.focal, loc)?
+                }
+                ast::Misc::Save(template) => {
+                    if let Output::Files(pgbar) = self.output {
+                        let path = match self.animation {
+                            Some((frame, frames)) => frame_path(template, frame, frames),
+                            None => template.to_owned(),
+                        };
+                        if let Some(pgbar) = pgbar {
+                            pgbar.set_message("Saving image");
+                        }
+                        save_frame(drawer, &path).map_err(|e| match e {
+                            EngineError::Io(e) => EngineError::Runtime {
+                                loc: loc.clone(),
+                                source: e.into(),
+                            },
+                            EngineError::ExternalTool(e) => EngineError::Runtime {
+                                loc: loc.clone(),
+                                source: RuntimeError::ExternalTool(e),
+                            },
+                            e => e,
+                        })?;
+                        if let Some(pgbar) = pgbar {
+                            pgbar.println(format!("File \"{}\" saved", path));
+                        }
+                    }
+                }
+                // unimplemented, which is reported as a warning before running
+                ast::Misc::GenerateRayfiles => {}
+                ast::Misc::Focal(value) => {
+                    let value = eval(value.eval(&env), loc)?;
+                    set_focal(drawer, &mut self.state.focal, value, loc)?
+                }
+                ast::Misc::Screen(_) => {
+                    return Err(EngineError::Runtime {
+                        loc: loc.clone(),
+                        source: RuntimeError::Unsupported("`screen`"),
+                    })
                 }
-                ast::Misc::GenerateRayfiles => warn_unimpl("generate_rayfiles", line),
-                ast::Misc::Focal(_) => warn_unimpl("focal", line),
+                // disabled in animations, which is reported as a warning before running
                 ast::Misc::Display => {
-                    pgbar.set_message("Displaying image");
-                    drawer.display();
+                    if let (None, Output::Files(pgbar)) = (self.animation, self.output) {
+                        if let Some(pgbar) = pgbar {
+                            pgbar.set_message("Displaying image");
+                        }
+                        drawer.display();
+                    }
                 }
             },
         }
+        Ok(())
+    }
+}
+
+/// Error for an expression without a value, on the line it's on
+fn eval<T>(result: Result<T, EvalError>, loc: &Loc) -> EngineResult<T> {
+    result.map_err(|e| EngineError::Runtime {
+        loc: loc.clone(),
+        source: e.into(),
+    })
+}
+
+/// Look through a camera at `eye`, pointed at `aim`
+fn set_camera<C: Canvas>(
+    drawer: &mut Drawer<C>,
+    eye: &ast::Point,
+    aim: &ast::Point,
+    env: &Env,
+    focal: f64,
+    loc: &Loc,
+) -> EngineResult<()> {
+    let eye = Vec3::from_pt(eval(eye.eval(env), loc)?);
+    let aim = Vec3::from_pt(eval(aim.eval(env), loc)?);
+    if eye == aim {
+        return Err(EngineError::Runtime {
+            loc: loc.clone(),
+            source: RuntimeError::CameraAimsAtEye,
+        });
+    }
+    drawer.camera = Some(Camera::new(eye, aim, focal));
+    Ok(())
+}
+
+/// `focal` applies to the current camera and to every camera set after it
+fn set_focal<C: Canvas>(
+    drawer: &mut Drawer<C>,
+    focal: &mut f64,
+    value: f64,
+    loc: &Loc,
+) -> EngineResult<()> {
+    if value <= 0. {
+        return Err(EngineError::Runtime {
+            loc: loc.clone(),
+            source: RuntimeError::Focal(value),
+        });
+    }
+    *focal = value;
+    if let Some(camera) = drawer.camera.as_mut() {
+        camera.focal = value;
+    }
+    Ok(())
+}
+
+/// Meshes drawn by a script, read from their files once for every frame
+#[derive(Debug, Default)]
+pub struct Meshes(HashMap<String, Obj>);
+
+impl Meshes {
+    /// Read the meshes that `commands` draw and that haven't been read yet
+    pub(crate) fn load(&mut self, commands: &[(Loc, Command)], report: &mut Report) {
+        for (loc, cmd) in ast::flatten(commands) {
+            if let Command::ShapeCmd(ast::Shape::Mesh { filename, .. }) = cmd {
+                if self.0.contains_key(filename) {
+                    continue;
+                }
+                match Obj::load(filename) {
+                    Ok(obj) => {
+                        self.0.insert(filename.to_owned(), obj);
+                    }
+                    Err(e) => report.error(EngineError::Runtime {
+                        loc: loc.clone(),
+                        source: RuntimeError::Mesh {
+                            path: filename.to_owned(),
+                            source: e,
+                        },
+                    }),
+                }
+            }
+        }
     }
+}
 
+/// Draw a shape, transformed by the coordinate system it names or by the top of the stack
+fn draw_shape<C: Canvas>(
+    shape: &ast::Shape,
+    env: &Env,
+    drawer: &mut Drawer<C>,
+    light_props: &SymTable<LightProps>,
+    meshes: &Meshes,
+    coords: &SymTable<Matrix>,
+    loc: &Loc,
+) -> EngineResult<()> {
+    let mut polygons = Matrix::new_polygon_matrix();
+    let mut normals = None;
+    let (constants, coord) = match shape {
+        ast::Shape::Sphere {
+            constants,
+            center,
+            r,
+            coord,
+        } => {
+            polygons.add_sphere(eval(center.eval(env), loc)?, eval(r.eval(env), loc)?);
+            (constants, coord)
+        }
+        ast::Shape::Torus {
+            constants,
+            center,
+            r0,
+            r1,
+            coord,
+        } => {
+            polygons.add_torus(
+                eval(center.eval(env), loc)?,
+                eval(r0.eval(env), loc)?,
+                eval(r1.eval(env), loc)?,
+            );
+            (constants, coord)
+        }
+        ast::Shape::Box {
+            constants,
+            corner,
+            height,
+            width,
+            depth,
+            coord,
+        } => {
+            polygons.add_box(
+                eval(corner.eval(env), loc)?,
+                eval(width.eval(env), loc)?,
+                eval(height.eval(env), loc)?,
+                eval(depth.eval(env), loc)?,
+            );
+            (constants, coord)
+        }
+        ast::Shape::Line {
+            constants: _,
+            point0,
+            coord0,
+            point1,
+            coord1,
+        } => {
+            let top = drawer.get_top_matrix().clone();
+            let coord0 = coords.find(coord0)?.unwrap_or(&top);
+            let coord1 = coords.find(coord1)?.unwrap_or(&top);
+            let point0 = eval(point0.eval(env), loc)?;
+            let point1 = eval(point1.eval(env), loc)?;
+            drawer.draw_line_with(point0, coord0, point1, coord1);
+            return Ok(());
+        }
+        ast::Shape::Mesh {
+            constants,
+            filename,
+            coord,
+        } => {
+            // meshes are read before running, and a script that can't read one doesn't run
+            normals = polygons.add_mesh(&meshes.0[filename]);
+            (constants, coord)
+        }
+    };
+
+    let props = light_props.find(constants)?;
+    match coords.find(coord)? {
+        Some(coord) => drawer.render_polygons_with(&polygons, normals.as_deref(), props, coord),
+        None => drawer.render_polygons_with_stack(&polygons, normals.as_deref(), props),
+    }
+    .map_err(|e| EngineError::Runtime {
+        loc: loc.clone(),
+        source: RuntimeError::MalformedGeometry(e),
+    })
+}
+
+/// Only `shading` is left in the command list after the first pass; other lighting commands are handled there
+fn set_shading<C: Canvas>(
+    lighting: &ast::Lighting,
+    drawer: &mut Drawer<C>,
+    loc: &Loc,
+) -> EngineResult<()> {
+    match lighting {
+        ast::Lighting::Shading(mode) => match mode {
+            ast::ShadingMode::Wireframe => drawer.shading = Shading::Wireframe,
+            ast::ShadingMode::Flat => drawer.shading = Shading::Flat,
+            ast::ShadingMode::Gouraud => drawer.shading = Shading::Gouraud,
+            ast::ShadingMode::Phong => drawer.shading = Shading::Phong,
+            // unimplemented, which is reported as a warning before running
+            ast::ShadingMode::Raytrace => {}
+        },
+        _ => {
+            return Err(EngineError::Runtime {
+                loc: loc.clone(),
+                source: RuntimeError::Unsupported("`light`, `ambient` and `constants`"),
+            })
+        }
+    }
     Ok(())
 }
 
+/// Matrix for a transformation, scaled by the value of its knob if it has one
+fn transform_matrix(transform: &ast::Transform, env: &Env, loc: &Loc) -> EngineResult<Matrix> {
+    match transform {
+        ast::Transform::Move { values, knob } => {
+            let (x, y, z) = eval(values.eval(env), loc)?;
+            transform_with_knob(
+                env.knobs,
+                knob,
+                |knob| tr::mv(x * knob, y * knob, z * knob),
+                || tr::mv(x, y, z),
+            )
+        }
+        ast::Transform::Scale { values, knob } => {
+            let (x, y, z) = eval(values.eval(env), loc)?;
+            transform_with_knob(
+                env.knobs,
+                knob,
+                |knob| tr::scale(x * knob, y * knob, z * knob),
+                || tr::scale(x, y, z),
+            )
+        }
+        ast::Transform::Rotate {
+            axis,
+            degrees,
+            knob,
+        } => {
+            let degrees = eval(degrees.eval(env), loc)?;
+            let rotate = match axis {
+                'x' => tr::rotatex,
+                'y' => tr::rotatey,
+                'z' => tr::rotatez,
+                _ => {
+                    return Err(EngineError::Runtime {
+                        loc: loc.clone(),
+                        source: RuntimeError::Axis(*axis),
+                    })
+                }
+            };
+            transform_with_knob(
+                env.knobs,
+                knob,
+                |knob| rotate(knob * degrees),
+                || rotate(degrees),
+            )
+        }
+    }
+}
+
 fn transform_with_knob(
     knobs: &SymTable<f64>,
     op_symbol: &Option<Symbol>,
@@ -135,132 +567,151 @@ fn transform_with_knob(
                 run_without_knob()
             }
         }
-        Err(e) => match e {
-            EngineError::SymbolNotFound { name } => {
-                eprintln!(
-                    "knob {} not found, ignoring this knob and applying static transformation",
-                    name
-                );
-                run_without_knob()
-            }
-            other => return Err(other),
-        },
+        // a knob that's never set is reported before running, and one that's only varied
+        // in some frames has no value in the others, so the transformation is applied without it
+        Err(EngineError::SymbolNotFound { .. }) => run_without_knob(),
+        Err(other) => return Err(other),
     })
 }
 
-pub(crate) fn exec_once_with_animation(
-    commands: &[(usize, Command)],
-    script: &[String],
-    knobs: &SymTable<f64>,
-    drawer: &mut Drawer<PPMImg>,
-    light_props: &SymTable<LightProps>,
-) -> EngineResult<()> {
-    for (line, cmd) in commands {
-        match cmd {
-            Command::Push => drawer.push_matrix(),
-            Command::Pop => drawer.pop_matrix(),
-            Command::TransformCmd(transform) => drawer.transform_by(&match transform {
-                ast::Transform::Move { values, knob } => transform_with_knob(
-                    knobs,
-                    knob,
-                    |knob| tr::mv(values.0 * knob, values.1 * knob, values.2 * knob),
-                    || tr::mv(values.0, values.1, values.2),
-                )?,
-                ast::Transform::Scale { values, knob } => transform_with_knob(
-                    knobs,
-                    knob,
-                    |knob| tr::scale(values.0 * knob, values.1 * knob, values.2 * knob),
-                    || tr::scale(values.0, values.1, values.2),
-                )?,
-                ast::Transform::Rotate {
-                    axis,
-                    degrees,
-                    knob,
-                } => transform_with_knob(
-                    knobs,
-                    knob,
-                    |knob| match axis {
-                        'x' => tr::rotatex(knob * degrees),
-                        'y' => tr::rotatey(knob * degrees),
-
-                        'z' => tr::rotatez(knob * degrees),
-                        _ => unreachable!(),
-                    },
-                    || match axis {
-                        'x' => tr::rotatex(*degrees),
-                        'y' => tr::rotatey(*degrees),
-
-                        'z' => tr::rotatez(*degrees),
-                        _ => unreachable!(),
-                    },
-                )?,
-            }),
-            Command::ShapeCmd(shape) => match shape {
-                ast::Shape::Sphere {
-                    constants,
-                    center,
-                    r,
-                    coord,
-                } => drawer.add_sphere(center.into(), *r, light_props.find(&constants)?),
-                ast::Shape::Torus {
-                    constants,
-                    center,
-                    r0,
-                    r1,
-                    coord,
-                } => drawer.add_torus(center.into(), *r0, *r1, light_props.find(&constants)?),
-                ast::Shape::Box {
-                    constants,
-                    corner,
-                    height,
-                    width,
-                    depth,
-                    coord,
-                } => drawer.add_box(
-                    corner.into(),
-                    *width,
-                    *height,
-                    *depth,
-                    light_props.find(&constants)?,
-                ),
-                ast::Shape::Line {
-                    constants,
-                    point0,
-                    coord0,
-                    point1,
-                    coord1,
-                } => drawer.draw_line(point0.into(), point1.into()),
-                ast::Shape::Mesh {
-                    constants: _,
-                    filename: _,
-                    coord: _,
-                } => warn_unimpl("mesh", *line),
-            },
-            Command::AnimateCmd(a) => match a {
-                ast::Animate::Basename(_) => unreachable!(),
-                ast::Animate::SetKnob { name: _, value: _ } => warn_unimpl("set_knob", *line),
-                ast::Animate::SetAllKnobs(_) => warn_unimpl("set_knobs", *line),
-                ast::Animate::Tween {
-                    start_frame: _,
-                    end_frame: _,
-                    knoblist0: _,
-                    knoblist1: _,
-                } => warn_unimpl("tween", *line),
-                ast::Animate::Frames(_) => unreachable!(),
-                ast::Animate::Vary(_) => unreachable!(),
-                ast::Animate::SaveKnobList(_) => warn_unimpl("save_knoblist", *line),
-            },
-            Command::LightingCmd(_) => unreachable!(),
-            Command::MiscCmd(cmd) => match cmd {
-                ast::Misc::SaveCoord(_) => warn_unimpl("save_coord_system", *line),
-                ast::Misc::Camera { eye: _, aim: _ } => warn_unimpl("camera", *line),
-                ast::Misc::Save(_) => warn_disabled_in_animation("save"),
-                ast::Misc::GenerateRayfiles => warn_unimpl("generate_rayfiles", *line),
-                ast::Misc::Focal(_) => warn_unimpl("focal", *line),
-                ast::Misc::Display => warn_disabled_in_animation("display"),
-            },
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        drawer::DrawerBuilder,
+        mdl::{parser::parse_script, ExecContext},
+        PPMImg, RGB,
+    };
+
+    /// Run the script as a still image, and return the result and the pixels
+    fn run(lines: &[&str]) -> (EngineResult<()>, Vec<RGB>) {
+        let script: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        match parse_script(script, None).unwrap().0 {
+            ExecContext::NoAnimation {
+                cmd_list,
+                knobs,
+                light_props,
+                meshes,
+                env_lights,
+                ..
+            } => {
+                let mut drawer = DrawerBuilder::new(PPMImg::new(60, 60, 255))
+                    .with_lights(env_lights)
+                    .build();
+                let result = exec_once_with_animation(
+                    &cmd_list,
+                    &knobs,
+                    &mut drawer,
+                    &light_props,
+                    &meshes,
+                    (0, 1),
+                    Output::Memory,
+                );
+                (result, drawer.canvas().pixels().to_vec())
+            }
+            _ => panic!("expected a still image"),
         }
     }
 
-    Ok(())
+    #[test]
+    fn test_blocks_render_like_unrolled_commands() {
+        let (result, looped) = run(&[
+            "move 30 30 0",
+            "def arm(angle, size) {",
+            "  push",
+            "  rotate z angle",
+            "  box 5 2 0 size 4 4",
+            "  pop",
+            "}",
+            "repeat 3 as i {",
+            "  call arm(i*120, 10 + i*5)",
+            "}",
+            "sphere 0 0 0 4",
+        ]);
+        result.unwrap();
+        let (_, unrolled) = run(&[
+            "move 30 30 0",
+            "push",
+            "rotate z 0",
+            "box 5 2 0 10 4 4",
+            "pop",
+            "push",
+            "rotate z 120",
+            "box 5 2 0 15 4 4",
+            "pop",
+            "push",
+            "rotate z 240",
+            "box 5 2 0 20 4 4",
+            "pop",
+            "sphere 0 0 0 4",
+        ]);
+        assert!(looped == unrolled);
+        assert!(looped.iter().any(|p| *p != looped[0]));
+    }
+
+    #[test]
+    fn test_bad_repeat_count() {
+        match run(&["let n = 2.5", "repeat n {", "}"]).0 {
+            Err(EngineError::Runtime {
+                loc,
+                source: RuntimeError::RepeatCount(count),
+            }) => {
+                assert_eq!(Loc::line(2), loc);
+                assert_eq!(2.5, count);
+            }
+            other => panic!("expected a bad repeat count, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_values_must_be_finite() {
+        for line in &[
+            "sphere 0 0 0 1e308*10",
+            "sphere 0 0 0 1e400",
+            "move 0/0 0 0",
+        ] {
+            match run(&["push", line]).0 {
+                Err(EngineError::Runtime {
+                    loc,
+                    source: RuntimeError::NotFinite(_),
+                }) => assert_eq!(Loc::line(2), loc),
+                other => panic!("expected a value that isn't finite, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pop_past_base() {
+        // the count isn't known before running, so the check only follows the block once
+        match run(&["let n = 2", "push", "repeat n {", "  pop", "}"]).0 {
+            Err(EngineError::Runtime {
+                loc,
+                source: RuntimeError::StackUnderflow(_),
+            }) => assert_eq!(Loc::line(4), loc),
+            other => panic!("expected a stack underflow, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_bad_camera() {
+        match run(&["push", "camera 0 0 100 0 0 100"]).0 {
+            Err(EngineError::Runtime {
+                loc,
+                source: RuntimeError::CameraAimsAtEye,
+            }) => assert_eq!(Loc::line(2), loc),
+            other => panic!("expected a camera aiming at its eye, got {:?}", other),
+        }
+        for (line, value) in &[("focal 0", 0.), ("focal -2", -2.)] {
+            match run(&["push", line]).0 {
+                Err(EngineError::Runtime {
+                    loc,
+                    source: RuntimeError::Focal(focal),
+                }) => {
+                    assert_eq!(Loc::line(2), loc);
+                    assert_eq!(*value, focal);
+                }
+                other => panic!("expected a bad focal length, got {:?}", other),
+            }
+        }
+    }
 }
@@ -0,0 +1,616 @@
+//! Arithmetic expressions in the numeric arguments of commands
+//!
+//! An argument can't have whitespace outside of parentheses, since arguments are separated
+//! by whitespace: `move 1 -2 3` is three numbers, `move (1 - 2) 3 4` and `move 1-2 3 4` are the same move.
+//! The value of `let` can have whitespace anywhere.
+
+use std::{f64::consts::PI, fmt};
+
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{alpha1, alphanumeric1, char, multispace0, one_of},
+    combinator::{not, recognize},
+    error::ErrorKind,
+    multi::{many0, separated_list},
+    number::complete::double,
+    sequence::{delimited, pair, terminated},
+};
+
+use super::{
+    ast::{PResult, ParseFailure, Symbol},
+    parser::SymTable,
+    result::RuntimeError,
+};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Num(f64),
+    /// A `let` variable, a knob, `frame` or `pi`
+    Var(Symbol),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+/// Built in functions, angles are in radians
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Func {
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Sqrt,
+    Abs,
+    Floor,
+    Ceil,
+    Round,
+    Exp,
+    Ln,
+    Min,
+    Max,
+}
+
+/// Names that always have a value, and can't be defined by a script
+pub(crate) const BUILTIN_NAMES: [&str; 2] = ["pi", "frame"];
+
+const FUNCS: [(&str, Func); 15] = [
+    ("sin", Func::Sin),
+    ("cos", Func::Cos),
+    ("tan", Func::Tan),
+    ("asin", Func::Asin),
+    ("acos", Func::Acos),
+    ("atan", Func::Atan),
+    ("sqrt", Func::Sqrt),
+    ("abs", Func::Abs),
+    ("floor", Func::Floor),
+    ("ceil", Func::Ceil),
+    ("round", Func::Round),
+    ("exp", Func::Exp),
+    ("ln", Func::Ln),
+    ("min", Func::Min),
+    ("max", Func::Max),
+];
+
+/// How tightly an expression holds together when it's printed, from the loosest
+///
+/// These are the levels of the parser: `sum`, `product`, `unary`, `power` and `atom`.
+#[derive(PartialEq, PartialOrd, Copy, Clone)]
+enum Prec {
+    Sum,
+    Product,
+    Unary,
+    Power,
+    Atom,
+}
+
+impl fmt::Display for Expr {
+    /// The expression as it's written in MDL, which parses back into the same expression
+    ///
+    /// It only has the parentheses it needs. There's no whitespace, so it can be an argument of a command,
+    /// unless it's printed with `{:#}`, which puts spaces around operators.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, Prec::Sum)
+    }
+}
+
+impl Expr {
+    fn prec(&self) -> Prec {
+        match self {
+            Expr::Num(v) if v.is_sign_negative() => Prec::Unary,
+            Expr::Num(_) | Expr::Var(_) | Expr::Call(..) => Prec::Atom,
+            Expr::Neg(_) => Prec::Unary,
+            Expr::Binary(Op::Add, ..) | Expr::Binary(Op::Sub, ..) => Prec::Sum,
+            Expr::Binary(Op::Mul, ..) | Expr::Binary(Op::Div, ..) => Prec::Product,
+            Expr::Binary(Op::Pow, ..) => Prec::Power,
+        }
+    }
+
+    /// Write the expression where it has to hold together at least as tightly as `outer`
+    fn write(&self, f: &mut fmt::Formatter<'_>, outer: Prec) -> fmt::Result {
+        if self.prec() < outer {
+            write!(f, "(")?;
+            self.write(f, Prec::Sum)?;
+            return write!(f, ")");
+        }
+        match self {
+            // the parser turns numbers too big for an f64 into infinity
+            Expr::Num(v) if v.is_infinite() => write!(f, "{}1e999", if *v < 0. { "-" } else { "" }),
+            // `{}` never uses an exponent, so it would write 1e300 with 300 zeros,
+            // and make up digits past the 17 that an f64 has, like 123456789012345680
+            Expr::Num(v) if *v != 0. && !(1e-5..1e16).contains(&v.abs()) => write!(f, "{:e}", v),
+            Expr::Num(v) => write!(f, "{}", v),
+            Expr::Var(name) => write!(f, "{}", name.0),
+            Expr::Neg(e) => {
+                write!(f, "-")?;
+                e.write(f, Prec::Unary)
+            }
+            Expr::Binary(op, a, b) => {
+                // `-` and `/` are left associative, `^` is right associative
+                let (left, right) = match op {
+                    Op::Add | Op::Sub => (Prec::Sum, Prec::Product),
+                    Op::Mul | Op::Div => (Prec::Product, Prec::Unary),
+                    Op::Pow => (Prec::Atom, Prec::Unary),
+                };
+                a.write(f, left)?;
+                if f.alternate() && *op != Op::Pow {
+                    write!(f, " {} ", op.symbol())?;
+                } else {
+                    write!(f, "{}", op.symbol())?;
+                }
+                b.write(f, right)
+            }
+            Expr::Call(func, args) => {
+                write!(f, "{}(", func.name())?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "{}", if f.alternate() { ", " } else { "," })?;
+                    }
+                    arg.write(f, Prec::Sum)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Values of the names in an expression
+pub(crate) struct Env<'a> {
+    /// `let` variables
+    pub vars: &'a SymTable<f64>,
+    pub knobs: &'a SymTable<f64>,
+    /// None before rendering, where only values that are the same in every frame are known
+    pub frame: Option<u32>,
+}
+
+impl Env<'_> {
+    fn get(&self, name: &Symbol) -> Option<f64> {
+        match name.0.as_str() {
+            "pi" => Some(PI),
+            "frame" => self.frame.map(f64::from),
+            _ => self
+                .vars
+                .get(name)
+                .or_else(|| self.knobs.get(name))
+                .copied(),
+        }
+    }
+}
+
+/// Why an expression has no value
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum EvalError {
+    /// A name that has no value in the `Env`
+    NoValue(Symbol),
+    /// A number, or part of the expression, that's infinite or not a number, like `1e400` or `0/0`
+    NotFinite(f64),
+}
+
+impl From<EvalError> for RuntimeError {
+    fn from(e: EvalError) -> Self {
+        match e {
+            EvalError::NoValue(name) => RuntimeError::NoValue(name.0),
+            EvalError::NotFinite(v) => RuntimeError::NotFinite(v),
+        }
+    }
+}
+
+impl Expr {
+    /// Value of the expression, or why it has none
+    ///
+    /// Every part of the expression has to be finite, since shapes with huge sizes take forever to draw.
+    pub(crate) fn eval(&self, env: &Env) -> Result<f64, EvalError> {
+        let v = match self {
+            Expr::Num(v) => *v,
+            Expr::Var(name) => env
+                .get(name)
+                .ok_or_else(|| EvalError::NoValue(name.clone()))?,
+            Expr::Neg(e) => -e.eval(env)?,
+            Expr::Binary(op, a, b) => op.apply(a.eval(env)?, b.eval(env)?),
+            Expr::Call(func, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| a.eval(env))
+                    .collect::<Result<Vec<f64>, EvalError>>()?;
+                func.apply(&args)
+            }
+        };
+        if v.is_finite() {
+            Ok(v)
+        } else {
+            Err(EvalError::NotFinite(v))
+        }
+    }
+
+    /// Every name used in the expression, including the built in ones
+    pub(crate) fn names(&self) -> Vec<&Symbol> {
+        let mut names = vec![];
+        self.collect_names(&mut names);
+        names
+    }
+
+    fn collect_names<'a>(&'a self, names: &mut Vec<&'a Symbol>) {
+        match self {
+            Expr::Num(_) => {}
+            Expr::Var(name) => names.push(name),
+            Expr::Neg(e) => e.collect_names(names),
+            Expr::Binary(_, a, b) => {
+                a.collect_names(names);
+                b.collect_names(names);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|a| a.collect_names(names)),
+        }
+    }
+}
+
+impl Op {
+    fn symbol(self) -> char {
+        match self {
+            Op::Add => '+',
+            Op::Sub => '-',
+            Op::Mul => '*',
+            Op::Div => '/',
+            Op::Pow => '^',
+        }
+    }
+
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            Op::Add => a + b,
+            Op::Sub => a - b,
+            Op::Mul => a * b,
+            Op::Div => a / b,
+            Op::Pow => a.powf(b),
+        }
+    }
+}
+
+impl Func {
+    fn name(self) -> &'static str {
+        FUNCS
+            .iter()
+            .find(|(_, func)| *func == self)
+            .map_or("", |(name, _)| name)
+    }
+
+    /// Whether the function takes `n` arguments
+    fn takes(self, n: usize) -> bool {
+        match self {
+            Func::Min | Func::Max => n >= 2,
+            _ => n == 1,
+        }
+    }
+
+    fn apply(self, args: &[f64]) -> f64 {
+        let x = args[0];
+        match self {
+            Func::Sin => x.sin(),
+            Func::Cos => x.cos(),
+            Func::Tan => x.tan(),
+            Func::Asin => x.asin(),
+            Func::Acos => x.acos(),
+            Func::Atan => x.atan(),
+            Func::Sqrt => x.sqrt(),
+            Func::Abs => x.abs(),
+            Func::Floor => x.floor(),
+            Func::Ceil => x.ceil(),
+            Func::Round => x.round(),
+            Func::Exp => x.exp(),
+            Func::Ln => x.ln(),
+            Func::Min => args.iter().copied().fold(x, f64::min),
+            Func::Max => args.iter().copied().fold(x, f64::max),
+        }
+    }
+}
+
+/// An expression that's a single argument of a command, see the module docs
+pub(crate) fn arg(i: &str) -> PResult<'_, Expr> {
+    sum(i, false)
+}
+
+/// An expression that can have whitespace anywhere
+pub(crate) fn expr(i: &str) -> PResult<'_, Expr> {
+    sum(i, true)
+}
+
+/// A name of a variable, which unlike other symbols can't have `-` or `.` in it
+pub(crate) fn ident(i: &str) -> PResult<'_, &str> {
+    recognize(pair(alpha1, many0(alt((alphanumeric1, tag("_"))))))(i)
+}
+
+fn space(i: &str, spaced: bool) -> &str {
+    if spaced {
+        i.trim_start()
+    } else {
+        i
+    }
+}
+
+/// One of the characters in `ops`, but not the start of a comment
+fn operator<'a>(i: &'a str, ops: &'static str, spaced: bool) -> PResult<'a, char> {
+    let (i, op) = terminated(one_of(ops), not(char('/')))(space(i, spaced))?;
+    Ok((space(i, spaced), op))
+}
+
+fn binary(op: char, a: Expr, b: Expr) -> Expr {
+    let op = match op {
+        '+' => Op::Add,
+        '-' => Op::Sub,
+        '*' => Op::Mul,
+        '/' => Op::Div,
+        _ => Op::Pow,
+    };
+    Expr::Binary(op, Box::new(a), Box::new(b))
+}
+
+fn sum(i: &str, spaced: bool) -> PResult<'_, Expr> {
+    let (mut i, mut lhs) = product(i, spaced)?;
+    while let Ok((rest, op)) = operator(i, "+-", spaced) {
+        let (rest, rhs) = product(rest, spaced)?;
+        lhs = binary(op, lhs, rhs);
+        i = rest;
+    }
+    Ok((i, lhs))
+}
+
+fn product(i: &str, spaced: bool) -> PResult<'_, Expr> {
+    let (mut i, mut lhs) = unary(i, spaced)?;
+    while let Ok((rest, op)) = operator(i, "*/", spaced) {
+        let (rest, rhs) = unary(rest, spaced)?;
+        lhs = binary(op, lhs, rhs);
+        i = rest;
+    }
+    Ok((i, lhs))
+}
+
+/// Unary minus binds looser than `^`, so `-2^2` is -4
+fn unary(i: &str, spaced: bool) -> PResult<'_, Expr> {
+    match char::<_, ParseFailure>('-')(i) {
+        Ok((rest, _)) => {
+            let (rest, e) = unary(space(rest, spaced), spaced)?;
+            Ok((
+                rest,
+                match e {
+                    Expr::Num(v) => Expr::Num(-v),
+                    e => Expr::Neg(Box::new(e)),
+                },
+            ))
+        }
+        Err(_) => power(i, spaced),
+    }
+}
+
+/// `^` is right associative, `2^3^2` is 2^9
+fn power(i: &str, spaced: bool) -> PResult<'_, Expr> {
+    let (i, base) = atom(i)?;
+    match operator(i, "^", spaced) {
+        Ok((rest, op)) => {
+            let (rest, exponent) = unary(rest, spaced)?;
+            Ok((rest, binary(op, base, exponent)))
+        }
+        Err(_) => Ok((i, base)),
+    }
+}
+
+/// A number without a sign, which is parsed by `unary`
+fn number(i: &str) -> PResult<'_, f64> {
+    let (i, _) = not(one_of("+-"))(i)?;
+    double(i)
+}
+
+fn atom(i: &str) -> PResult<'_, Expr> {
+    let error = match number(i) {
+        Ok((i, v)) => return Ok((i, Expr::Num(v))),
+        Err(nom::Err::Error(e)) => e,
+        Err(e) => return Err(e),
+    };
+
+    if let Ok((rest, _)) = tag::<_, _, ParseFailure>("(")(i) {
+        let (rest, e) = delimited(multispace0, expr, multispace0)(rest)?;
+        let (rest, _) = char(')')(rest)?;
+        return Ok((rest, e));
+    }
+
+    let (rest, name) = ident(i).map_err(|_| nom::Err::Error(error))?;
+    if let Ok((args_start, _)) = tag::<_, _, ParseFailure>("(")(rest) {
+        let (rest, args) = separated_list(
+            delimited(multispace0, char(','), multispace0),
+            delimited(multispace0, expr, multispace0),
+        )(args_start)?;
+        let (rest, _) = char(')')(rest)?;
+        let func = FUNCS
+            .iter()
+            .find(|(f, _)| *f == name)
+            .map(|(_, func)| *func)
+            .filter(|func| func.takes(args.len()));
+        return match func {
+            Some(func) => Ok((rest, Expr::Call(func, args))),
+            None => Err(nom::Err::Error(ParseFailure {
+                input: i,
+                kind: ErrorKind::Verify,
+                context: None,
+            })),
+        };
+    }
+    Ok((rest, Expr::Var(Symbol(name.to_owned()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str) -> f64 {
+        let (rest, e) = expr(s).unwrap();
+        assert_eq!("", rest, "{} wasn't all parsed", s);
+        let mut vars = SymTable::new();
+        vars.insert(Symbol(String::from("x")), 3.);
+        let mut knobs = SymTable::new();
+        knobs.insert(Symbol(String::from("k")), 0.5);
+        e.eval(&Env {
+            vars: &vars,
+            knobs: &knobs,
+            frame: Some(7),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(7., eval("1 + 2 * 3"));
+        assert_eq!(9., eval("(1 + 2) * 3"));
+        assert_eq!(-4., eval("-2^2"));
+        assert_eq!(512., eval("2^3^2"));
+        assert_eq!(0.5, eval("2^-1"));
+        assert_eq!(1., eval("8 / 4 / 2"));
+        assert_eq!(1., eval("4 - 2 - 1"));
+        assert_eq!(5., eval("- -5"));
+    }
+
+    #[test]
+    fn test_values_must_be_finite() {
+        let env = Env {
+            vars: &SymTable::new(),
+            knobs: &SymTable::new(),
+            frame: None,
+        };
+        let value = |s: &str| expr(s).unwrap().1.eval(&env);
+        assert_eq!(Err(EvalError::NotFinite(f64::INFINITY)), value("1e400"));
+        assert_eq!(
+            Err(EvalError::NotFinite(f64::INFINITY)),
+            value("1e308 * 10")
+        );
+        // even if the whole expression is finite
+        assert_eq!(Err(EvalError::NotFinite(f64::INFINITY)), value("1 / 1e400"));
+        assert!(matches!(value("0 / 0"), Err(EvalError::NotFinite(v)) if v.is_nan()));
+        assert_eq!(
+            Err(EvalError::NoValue(Symbol(String::from("frame")))),
+            value("frame")
+        );
+        assert_eq!(Ok(1e300), value("1e300"));
+    }
+
+    #[test]
+    fn test_names_and_functions() {
+        assert_eq!(10., eval("x + frame"));
+        assert_eq!(1.5, eval("k * x"));
+        assert!((eval("sin(pi / 2)") - 1.).abs() < 1e-12);
+        assert_eq!(2., eval("sqrt( 4 )"));
+        assert_eq!(-1., eval("min(3, x - 4, 2)"));
+        assert_eq!(3., eval("max(k,x)"));
+
+        let (_, e) = expr("x * sin(k + frame)").unwrap();
+        let names: Vec<&str> = e.names().iter().map(|s| s.0.as_str()).collect();
+        assert_eq!(vec!["x", "k", "frame"], names);
+    }
+
+    #[test]
+    fn test_arguments_end_at_whitespace() {
+        assert_eq!(Ok((" -2", Expr::Num(1.))), arg("1 -2"));
+        assert_eq!(
+            Ok((
+                " 3",
+                Expr::Binary(Op::Sub, Box::new(Expr::Num(1.)), Box::new(Expr::Num(2.)))
+            )),
+            arg("(1 - 2) 3")
+        );
+        // not a division by a comment
+        assert_eq!(Ok(("//c", Expr::Num(4.))), arg("4//c"));
+        assert!(arg("foo(1)").is_err());
+        assert!(arg("sin(1, 2)").is_err());
+        assert!(arg("(1 + 2").is_err());
+    }
+
+    /// Random expressions from a fixed seed, so failures can be reproduced
+    struct Gen(u64);
+
+    impl Gen {
+        fn next(&mut self, n: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+
+        fn expr(&mut self, depth: u32) -> Expr {
+            let leaf = depth == 0 || self.next(3) == 0;
+            match if leaf { self.next(2) } else { 2 + self.next(4) } {
+                0 => {
+                    let nums = [0., 1., 2.5, -3., 0.125, 1e-7, 1e21, -400.];
+                    Expr::Num(nums[self.next(nums.len() as u64) as usize])
+                }
+                1 => {
+                    let names = ["x", "k", "frame", "pi"];
+                    Expr::Var(Symbol(names[self.next(4) as usize].to_owned()))
+                }
+                2 => match self.expr(depth - 1) {
+                    // the parser folds these into a number
+                    e @ Expr::Num(_) | e @ Expr::Neg(_) => e,
+                    e => Expr::Neg(Box::new(e)),
+                },
+                3 => {
+                    let ops = [Op::Add, Op::Sub, Op::Mul, Op::Div, Op::Pow];
+                    Expr::Binary(
+                        ops[self.next(5) as usize],
+                        Box::new(self.expr(depth - 1)),
+                        Box::new(self.expr(depth - 1)),
+                    )
+                }
+                _ => {
+                    let (func, n) = if self.next(2) == 0 {
+                        (Func::Sin, 1)
+                    } else {
+                        (Func::Max, 2 + self.next(2))
+                    };
+                    Expr::Call(func, (0..n).map(|_| self.expr(depth - 1)).collect())
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_printed_expressions_parse_back() {
+        assert_eq!(
+            "1+2*(x-3)",
+            format!("{}", expr("1 + 2 * (x - 3)").unwrap().1)
+        );
+        assert_eq!("(-2)^2", format!("{}", expr("(-2)^2").unwrap().1));
+        assert_eq!("-2^-2", format!("{}", expr("-(2^-2)").unwrap().1));
+        assert_eq!("4-(2-1)", format!("{}", expr("4 - (2 - 1)").unwrap().1));
+        assert_eq!("1e300", format!("{}", expr("1e300").unwrap().1));
+        assert_eq!(
+            "1.2345678901234568e17",
+            format!("{}", expr("123456789012345678").unwrap().1)
+        );
+        assert_eq!("-2.5e-7", format!("{}", expr("-0.00000025").unwrap().1));
+        assert_eq!("0.0001 9999999999999998", {
+            let small = expr("0.0001").unwrap().1;
+            let large = expr("9999999999999998").unwrap().1;
+            format!("{} {}", small, large)
+        });
+        assert_eq!(
+            "max(k, x * -1) / 2",
+            format!("{:#}", expr("max(k,x*-1)/2").unwrap().1)
+        );
+
+        let mut gen = Gen(25);
+        for _ in 0..2000 {
+            let e = gen.expr(5);
+            let printed = format!("{}", e);
+            assert_eq!(Ok(("", e.clone())), arg(&printed), "{}", printed);
+            let printed = format!("{:#}", e);
+            assert_eq!(Ok(("", e.clone())), expr(&printed), "{}", printed);
+        }
+    }
+}
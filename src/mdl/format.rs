@@ -0,0 +1,304 @@
+//! `mdl fmt`, which writes scripts the same way every time
+//!
+//! Every command is printed in canonical MDL, indented two spaces for each block it's in.
+//! The arguments of consecutive commands with the same keyword and the same arguments are put in columns,
+//! and so are their comments. Comments are kept, and blank lines are collapsed into one.
+
+use std::{fs, path::Path};
+
+use super::{
+    ast::{self, Command, Line, Loc, Shape},
+    diagnostics::{Report, SyntaxError},
+    result::{EngineError, EngineResult},
+    utils,
+};
+
+/// A line of the formatted script, before it's aligned with its neighbors
+enum Formatted {
+    Code {
+        depth: usize,
+        words: Vec<String>,
+        /// Which optional arguments are given, see [`layout`]
+        layout: Vec<bool>,
+        comment: Option<String>,
+    },
+    Comment {
+        depth: usize,
+        text: String,
+    },
+    Blank,
+}
+
+/// Format the text of a script, which is an error if a line doesn't parse
+pub fn format(source: &str) -> EngineResult<String> {
+    let mut report = Report::default();
+    let mut lines = vec![];
+    let mut depth = 0;
+    for (n, line) in source.lines().enumerate() {
+        match ast::parse_line(line) {
+            Ok((_, Some(parsed))) => {
+                if parsed == Line::End {
+                    depth = usize::saturating_sub(depth, 1);
+                }
+                lines.push(Formatted::Code {
+                    depth,
+                    words: parsed.words(),
+                    layout: layout(&parsed),
+                    comment: comment(line, &parsed),
+                });
+                if let Line::Repeat { .. } | Line::Def { .. } = parsed {
+                    depth += 1;
+                }
+            }
+            Ok((_, None)) if line.trim().is_empty() => lines.push(Formatted::Blank),
+            Ok((_, None)) => lines.push(Formatted::Comment {
+                depth,
+                text: line.trim().to_owned(),
+            }),
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                report.error(EngineError::Syntax(Box::new(SyntaxError::new(
+                    Loc::line(n + 1),
+                    line,
+                    &e,
+                ))));
+            }
+        }
+    }
+    report.finish(())?;
+    Ok(write(&lines))
+}
+
+/// Format a script in place, or only check it with `check`, and tell whether it's changed
+pub fn format_file(path: &Path, check: bool) -> EngineResult<bool> {
+    let source = fs::read_to_string(path)?;
+    let formatted = format(&source)?;
+    if formatted == source {
+        return Ok(false);
+    }
+    if !check {
+        utils::write_atomically(path, |partial| Ok(fs::write(partial, &formatted)?))?;
+    }
+    Ok(true)
+}
+
+/// Comment at the end of a line, which is the first `//` that the line parses the same without
+///
+/// `//` can be in a file name, as in `save a//b.png`.
+fn comment(line: &str, parsed: &Line) -> Option<String> {
+    line.match_indices("//")
+        .map(|(start, _)| start)
+        .find(|&start| match ast::parse_line(&line[..start]) {
+            Ok((_, Some(before))) => before == *parsed,
+            _ => false,
+        })
+        .map(|start| line[start..].trim_end().to_owned())
+}
+
+/// Which of the optional arguments of a shape are given
+///
+/// They come before other arguments, so lines of a shape only share columns when they have the same ones.
+/// The optional arguments of other commands are at the end, where the number of words tells them apart.
+fn layout(parsed: &Line) -> Vec<bool> {
+    match parsed {
+        Line::Cmd(cmd) => match &**cmd {
+            Command::ShapeCmd(Shape::Line {
+                constants,
+                coord0,
+                coord1,
+                ..
+            }) => vec![constants.is_some(), coord0.is_some(), coord1.is_some()],
+            Command::ShapeCmd(Shape::Sphere {
+                constants, coord, ..
+            })
+            | Command::ShapeCmd(Shape::Torus {
+                constants, coord, ..
+            })
+            | Command::ShapeCmd(Shape::Box {
+                constants, coord, ..
+            })
+            | Command::ShapeCmd(Shape::Mesh {
+                constants, coord, ..
+            }) => vec![constants.is_some(), coord.is_some()],
+            _ => vec![],
+        },
+        _ => vec![],
+    }
+}
+
+fn write(lines: &[Formatted]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        match &lines[i] {
+            Formatted::Code { .. } => {
+                let run = run(&lines[i..]);
+                write_run(&mut out, run);
+                i += run.len();
+                continue;
+            }
+            Formatted::Comment { depth, text } => {
+                out.push_str(&"  ".repeat(*depth));
+                out.push_str(text);
+                out.push('\n');
+            }
+            // one blank line at most, and none at the start
+            Formatted::Blank => {
+                if !out.is_empty() && !out.ends_with("\n\n") {
+                    out.push('\n');
+                }
+            }
+        }
+        i += 1;
+    }
+    // nor at the end
+    let len = out.trim_end().len();
+    out.truncate(len);
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+/// Lines at the start of `lines` that are aligned together
+fn run(lines: &[Formatted]) -> &[Formatted] {
+    fn key(line: &Formatted) -> Option<(usize, &str, usize, &[bool])> {
+        match line {
+            Formatted::Code {
+                depth,
+                words,
+                layout,
+                ..
+            } => Some((*depth, &words[0], words.len(), layout)),
+            _ => None,
+        }
+    }
+    let first = key(&lines[0]);
+    let len = lines.iter().take_while(|line| key(line) == first).count();
+    &lines[..len]
+}
+
+/// Write lines of code with the same keyword and arguments, with the words and the comments in columns
+fn write_run(out: &mut String, run: &[Formatted]) {
+    let lines: Vec<(usize, &[String], &Option<String>)> = run
+        .iter()
+        .filter_map(|line| match line {
+            Formatted::Code {
+                depth,
+                words,
+                comment,
+                ..
+            } => Some((*depth, words.as_slice(), comment)),
+            _ => None,
+        })
+        .collect();
+
+    let columns = lines[0].1.len();
+    let widths: Vec<usize> = (0..columns)
+        .map(|c| {
+            lines
+                .iter()
+                .map(|(_, words, _)| words[c].len())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let codes: Vec<String> = lines
+        .iter()
+        .map(|(_, words, _)| {
+            let mut code = String::new();
+            for (c, word) in words.iter().enumerate() {
+                if c + 1 < columns {
+                    code.push_str(&format!("{:width$} ", word, width = widths[c]));
+                } else {
+                    code.push_str(word);
+                }
+            }
+            code
+        })
+        .collect();
+    let code_width = codes.iter().map(String::len).max().unwrap_or(0);
+
+    for ((depth, _, comment), code) in lines.iter().zip(codes) {
+        out.push_str(&"  ".repeat(*depth));
+        match comment {
+            Some(comment) => {
+                out.push_str(&format!("{:width$} {}", code, comment, width = code_width))
+            }
+            None => out.push_str(&code),
+        }
+        out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let source =
+            "\n\n// shapes\nsphere 0 0   0 100 // big\nsphere 250 -0.50 250 5   //small\n\n\n\
+                      repeat 2 as i {\n  move 10 0 0\nrotate y 90*i\n}   \nsave a//b.png\n\n";
+        assert_eq!(
+            "// shapes\n\
+             sphere 0   0    0   100 // big\n\
+             sphere 250 -0.5 250 5   //small\n\
+             \n\
+             repeat 2 as i {\n  move 10 0 0\n  rotate y 90*i\n}\n\
+             save a//b.png\n",
+            format(source).unwrap()
+        );
+        assert_eq!("", format("\n  \n").unwrap());
+        assert_eq!(
+            "sphere 0 0 0 1e300\nmove 1.2345678901234568e17 0 0\n",
+            format("sphere 0 0 0 1e300\nmove 123456789012345678 0 0").unwrap()
+        );
+
+        match format("push\nsphre 0 0 0 1\nscale 1 1 1 k\nmove 1 2\n") {
+            Err(EngineError::Diagnostics(report)) => {
+                let lines: Vec<usize> = report
+                    .errors
+                    .iter()
+                    .map(|e| e.loc().unwrap().line)
+                    .collect();
+                assert_eq!(vec![2, 4], lines);
+            }
+            other => panic!("expected syntax errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_only_same_arguments_share_columns() {
+        assert_eq!(
+            "box bcons 1 2 3 10 20 30\nbox 1 2 3 10 20 30 acs\n",
+            format("box bcons 1 2 3 10 20 30\nbox 1 2 3 10 20 30 acs").unwrap()
+        );
+        assert_eq!(
+            "sphere a 10 10 10 5\nsphere 10 10 10 5 sys\n",
+            format("sphere a 10 10 10 5\nsphere 10 10 10 5 sys").unwrap()
+        );
+        assert_eq!(
+            "line 0 0 0 c 1 1 1\nline 0 0 0 1 1 1 c\n",
+            format("line 0 0 0 c 1 1 1\nline 0 0 0 1 1 1 c").unwrap()
+        );
+        assert_eq!(
+            "sphere a  10 10 10 5\nsphere bb 1  1  1  50\n",
+            format("sphere a 10 10 10 5\nsphere bb 1 1 1 50").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_keeps_commands() {
+        let source = include_str!("../../scripts/test.mdl");
+        let formatted = format(source).unwrap();
+        assert_eq!(formatted, format(&formatted).unwrap());
+
+        let commands = |text: &str| -> Vec<Line> {
+            text.lines()
+                .filter_map(|line| ast::parse_line(line).unwrap().1)
+                .collect()
+        };
+        assert_eq!(commands(source), commands(&formatted));
+    }
+}
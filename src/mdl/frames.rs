@@ -0,0 +1,226 @@
+//! Render the frames of an animation on several threads
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+use crate::{
+    drawer::{Drawer, DrawerBuilder},
+    light::{Light, LightProps, Shading},
+    Canvas,
+};
+
+use super::{
+    ast::{Command, FrameOutput, Loc, Screen},
+    exec::{exec_once_with_animation, Meshes, Output},
+    parser::SymTable,
+    result::EngineResult,
+    save_frame, utils,
+};
+
+/// Everything needed to render any frame of an animation
+pub(crate) struct Animation<'a> {
+    pub cmd_list: &'a [(Loc, Command)],
+    /// Knob values of every frame
+    pub knob_states: &'a [SymTable<f64>],
+    pub light_props: &'a SymTable<LightProps>,
+    pub meshes: &'a Meshes,
+    pub env_lights: &'a [Light],
+    pub screen: Screen,
+    pub basename: &'a str,
+    pub output: &'a FrameOutput,
+    /// Give every frame to `write_frame`, without writing files or running anything else
+    pub in_memory: bool,
+}
+
+impl Animation<'_> {
+    /// Render every frame, each thread with its own drawer on a canvas made by `new_canvas`
+    ///
+    /// With gif output or in memory, what `keep` takes from every frame is given to `write_frame`, always in order.
+    /// Otherwise every frame is saved to its own file.
+    /// `progress` is called once for each finished frame.
+    pub(crate) fn render<C: Canvas, T: Send>(
+        &self,
+        new_canvas: impl Fn(Screen) -> C + Sync,
+        keep: impl Fn(&C) -> EngineResult<T> + Sync,
+        mut write_frame: impl FnMut(T) -> EngineResult<()>,
+        progress: impl Fn(),
+    ) -> EngineResult<()> {
+        let frames = self.knob_states.len() as u32;
+        let workers = thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(frames as usize)
+            .max(1);
+
+        // frames are handed out in order, so that the ones waiting to be written stay few
+        let next_frame = &AtomicU32::new(0);
+        let failed = &AtomicBool::new(false);
+        let (tx, rx) = mpsc::sync_channel::<(u32, EngineResult<Option<T>>)>(workers);
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let tx = tx.clone();
+                let (new_canvas, keep) = (&new_canvas, &keep);
+                scope.spawn(move || {
+                    let mut drawer = self.new_drawer(new_canvas(self.screen));
+                    while !failed.load(Ordering::Relaxed) {
+                        let frame = next_frame.fetch_add(1, Ordering::Relaxed);
+                        if frame >= frames {
+                            break;
+                        }
+                        let rendered = self.render_frame(&mut drawer, frame, keep);
+                        // the receiver is gone if writing failed
+                        if tx.send((frame, rendered)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+
+            // frames can finish out of order, so hold on to them until it's their turn
+            let mut pending = BTreeMap::new();
+            let mut next_write = 0;
+            for (frame, rendered) in rx {
+                let written = rendered.and_then(|kept| {
+                    pending.insert(frame, kept);
+                    while let Some(kept) = pending.remove(&next_write) {
+                        if let Some(kept) = kept {
+                            write_frame(kept)?;
+                        }
+                        next_write += 1;
+                        progress();
+                    }
+                    Ok(())
+                });
+                if written.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                    return written;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn new_drawer<C: Canvas>(&self, canvas: C) -> Drawer<C> {
+        DrawerBuilder::new(canvas)
+            .with_lights(self.env_lights.to_vec())
+            .build()
+    }
+
+    /// Render one frame, and return what `keep` takes from it if it goes in the gif or stays in memory
+    fn render_frame<C: Canvas, T>(
+        &self,
+        drawer: &mut Drawer<C>,
+        frame: u32,
+        keep: impl Fn(&C) -> EngineResult<T>,
+    ) -> EngineResult<Option<T>> {
+        let frames = self.knob_states.len() as u32;
+        exec_once_with_animation(
+            self.cmd_list,
+            &self.knob_states[frame as usize],
+            drawer,
+            self.light_props,
+            self.meshes,
+            (frame, frames),
+            if self.in_memory {
+                Output::Memory
+            } else {
+                Output::Files(None)
+            },
+        )?;
+
+        let kept = match self.output {
+            FrameOutput::Files { extension } if !self.in_memory => {
+                let path =
+                    utils::frame_path(&format!("{}.{}", self.basename, extension), frame, frames);
+                save_frame(drawer, &path)?;
+                None
+            }
+            _ => Some(keep(drawer.canvas())?),
+        };
+
+        drawer.reset_stack();
+        drawer.clear();
+        // every frame starts with the default shading and no camera, like the first one
+        drawer.shading = Shading::Flat;
+        drawer.camera = None;
+        Ok(kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mdl::{ast, ast::Symbol};
+    use crate::PPMImg;
+
+    #[test]
+    fn test_frames_written_in_order() {
+        let script: Vec<String> = vec![
+            String::from("move 30 0 0 k"),
+            String::from("box 0 10 0 5 5 5"),
+        ];
+        let cmd_list: Vec<(Loc, Command)> = script
+            .iter()
+            .enumerate()
+            .map(|(i, line)| match ast::parse_line(line).unwrap().1 {
+                Some(ast::Line::Cmd(cmd)) => (Loc::line(i + 1), *cmd),
+                other => panic!("expected a command, got {:?}", other),
+            })
+            .collect();
+        let knob_states: Vec<SymTable<f64>> = (0..12)
+            .map(|frame| {
+                let mut knobs = SymTable::new();
+                knobs.insert(Symbol(String::from("k")), frame as f64 / 12.);
+                knobs
+            })
+            .collect();
+        let animation = Animation {
+            cmd_list: &cmd_list,
+            knob_states: &knob_states,
+            light_props: &SymTable::new(),
+            meshes: &Meshes::default(),
+            env_lights: &[],
+            screen: Screen {
+                width: 40,
+                height: 20,
+                depth: 255,
+            },
+            basename: "test",
+            output: &FrameOutput::Gif,
+            in_memory: false,
+        };
+
+        let new_canvas = |screen: Screen| PPMImg::new(screen.width, screen.height, screen.depth);
+        let keep = |img: &PPMImg| Ok(img.clone());
+        let mut parallel = vec![];
+        animation
+            .render(
+                new_canvas,
+                keep,
+                |img| {
+                    parallel.push(img);
+                    Ok(())
+                },
+                || {},
+            )
+            .unwrap();
+
+        let mut drawer = animation.new_drawer(new_canvas(animation.screen));
+        let sequential: Vec<PPMImg> = (0..12)
+            .map(|frame| {
+                animation
+                    .render_frame(&mut drawer, frame, keep)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect();
+        assert!(parallel == sequential);
+    }
+}
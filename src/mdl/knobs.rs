@@ -0,0 +1,319 @@
+//! Knob values for every frame, from `set`, `setknobs`, `save_knobs`, `tween` and `vary`
+
+use std::{collections::HashSet, f64::consts::PI};
+
+use super::{
+    ast::{self, Command, Easing, Loc, Symbol, VaryInfo},
+    diagnostics::Report,
+    parser::SymTable,
+    result::{EngineError, RuntimeError},
+};
+
+/// `vary` commands with their locations
+type VaryList = Vec<(Loc, VaryInfo)>;
+
+/// `set`, `setknobs`, `save_knobs` and `tween`, with the values of `set` and `setknobs` evaluated
+#[derive(Debug, Clone)]
+pub(crate) enum KnobCmd {
+    Set {
+        name: Symbol,
+        value: f64,
+    },
+    SetAll(f64),
+    Save(Symbol),
+    Tween {
+        start_frame: u32,
+        end_frame: u32,
+        knoblist0: Symbol,
+        knoblist1: Symbol,
+    },
+}
+
+/// Every knob named in the script, by transformations, `vary`, `set` or `tween`
+pub(crate) fn knob_names(
+    cmd_list: &[(Loc, Command)],
+    vary_list: &[(Loc, VaryInfo)],
+    knob_cmds: &[(Loc, KnobCmd)],
+) -> HashSet<Symbol> {
+    let mut names = HashSet::new();
+    for (_, cmd) in ast::flatten(cmd_list) {
+        if let Command::TransformCmd(transform) = cmd {
+            names.extend(transform.knob().cloned());
+        }
+    }
+    names.extend(vary_list.iter().map(|(_, v)| v.knob.clone()));
+    for (_, cmd) in knob_cmds {
+        if let KnobCmd::Set { name, .. } = cmd {
+            names.insert(name.clone());
+        }
+    }
+    names
+}
+
+/// Run `set`, `setknobs`, `save_knobs` and `tween` in script order
+///
+/// Returns the knob values left by `set` and `setknobs`, which every frame starts from,
+/// and each `tween` split into one `vary` per knob, so they can be computed together.
+/// `setknobs` changes every knob in `names`. Errors are added to `report`, and the bad `tween` is skipped.
+pub(crate) fn run_knob_cmds(
+    knob_cmds: &[(Loc, KnobCmd)],
+    names: &HashSet<Symbol>,
+    report: &mut Report,
+) -> (SymTable<f64>, VaryList) {
+    let mut knobs: SymTable<f64> = SymTable::new();
+    let mut knob_lists: SymTable<SymTable<f64>> = SymTable::new();
+    let mut tweens = vec![];
+
+    for (loc, cmd) in knob_cmds {
+        match cmd {
+            KnobCmd::Set { name, value } => {
+                knobs.insert(name.clone(), *value);
+            }
+            KnobCmd::SetAll(value) => {
+                for name in names {
+                    knobs.insert(name.clone(), *value);
+                }
+            }
+            KnobCmd::Save(name) => {
+                knob_lists.insert(name.clone(), knobs.clone());
+            }
+            KnobCmd::Tween {
+                start_frame,
+                end_frame,
+                knoblist0,
+                knoblist1,
+            } => {
+                if start_frame >= end_frame {
+                    report.error(EngineError::Runtime {
+                        loc: loc.clone(),
+                        source: RuntimeError::Semantics("start_frame of tween must be < end_frame"),
+                    });
+                }
+                let mut find_list = |name: &Symbol| {
+                    let list = knob_lists.get(name);
+                    if list.is_none() {
+                        report.error(EngineError::Runtime {
+                            loc: loc.clone(),
+                            source: RuntimeError::KnobListNotFound(name.0.to_owned()),
+                        });
+                    }
+                    list
+                };
+                let (list0, list1) = match (find_list(knoblist0), find_list(knoblist1)) {
+                    (Some(list0), Some(list1)) if start_frame < end_frame => (list0, list1),
+                    _ => continue,
+                };
+
+                // sorted, so knobs are always computed in the same order
+                let mut tweened: Vec<&Symbol> = list0.keys().chain(list1.keys()).collect();
+                tweened.sort_by(|a, b| a.0.cmp(&b.0));
+                tweened.dedup();
+                for knob in tweened {
+                    // a knob saved in only one of the lists keeps that value
+                    let (start_val, end_val) = match (list0.get(knob), list1.get(knob)) {
+                        (Some(v0), Some(v1)) => (*v0, *v1),
+                        (Some(v), None) | (None, Some(v)) => (*v, *v),
+                        (None, None) => unreachable!(),
+                    };
+                    tweens.push((
+                        loc.clone(),
+                        VaryInfo {
+                            knob: knob.clone(),
+                            start_frame: *start_frame,
+                            end_frame: *end_frame,
+                            start_val,
+                            end_val,
+                            easing: Easing::Linear,
+                        },
+                    ));
+                }
+            }
+        }
+    }
+
+    (knobs, tweens)
+}
+
+/// Compute the value of every knob for every frame
+///
+/// Each frame starts with `base`, then every `vary` that covers the frame is applied.
+/// Where `vary` commands overlap, the last one wins.
+pub(crate) fn knob_states(
+    frames: u32,
+    base: &SymTable<f64>,
+    vary_list: &[(Loc, VaryInfo)],
+) -> Vec<SymTable<f64>> {
+    let mut knob_states: Vec<SymTable<f64>> = vec![];
+
+    for cur_frame in 0..frames {
+        let mut table = base.clone();
+        for (_, v) in vary_list.iter() {
+            if v.start_frame <= cur_frame && cur_frame <= v.end_frame {
+                let t = (cur_frame - v.start_frame) as f64 / (v.end_frame - v.start_frame) as f64;
+                let val = v.start_val + (v.end_val - v.start_val) * v.easing.ease(t);
+
+                table.insert(v.knob.to_owned(), val);
+            }
+        }
+        knob_states.push(table);
+    }
+
+    knob_states
+}
+
+impl Easing {
+    /// Map `t` in [0, 1] to how far along the knob is, which is 0 at `t = 0` and 1 at `t = 1`
+    ///
+    /// `bounce` and `elastic` ease out, the others without a direction ease in and out.
+    /// Curves are from <https://easings.net>.
+    pub fn ease(self, t: f64) -> f64 {
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t * t,
+            Easing::EaseOut => 1. - (1. - t).powi(3),
+            Easing::EaseInOut => {
+                if t < 0.5 {
+                    4. * t * t * t
+                } else {
+                    1. - (-2. * t + 2.).powi(3) / 2.
+                }
+            }
+            Easing::Exponential => {
+                if t <= 0. {
+                    0.
+                } else if t >= 1. {
+                    1.
+                } else if t < 0.5 {
+                    2f64.powf(20. * t - 10.) / 2.
+                } else {
+                    (2. - 2f64.powf(-20. * t + 10.)) / 2.
+                }
+            }
+            Easing::Sine => -((PI * t).cos() - 1.) / 2.,
+            Easing::Bounce => {
+                const N: f64 = 7.5625;
+                const D: f64 = 2.75;
+                if t < 1. / D {
+                    N * t * t
+                } else if t < 2. / D {
+                    let t = t - 1.5 / D;
+                    N * t * t + 0.75
+                } else if t < 2.5 / D {
+                    let t = t - 2.25 / D;
+                    N * t * t + 0.9375
+                } else {
+                    let t = t - 2.625 / D;
+                    N * t * t + 0.984375
+                }
+            }
+            Easing::Elastic => {
+                if t <= 0. {
+                    0.
+                } else if t >= 1. {
+                    1.
+                } else {
+                    2f64.powf(-10. * t) * ((t * 10. - 0.75) * (2. * PI / 3.)).sin() + 1.
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sym(name: &str) -> Symbol {
+        Symbol(String::from(name))
+    }
+
+    #[test]
+    fn test_tween_between_saved_lists() {
+        let cmds = vec![
+            (Loc::line(1), KnobCmd::SetAll(0.)),
+            (Loc::line(2), KnobCmd::Save(sym("start"))),
+            (
+                Loc::line(3),
+                KnobCmd::Set {
+                    name: sym("spin"),
+                    value: 1.,
+                },
+            ),
+            (Loc::line(4), KnobCmd::Save(sym("end"))),
+            (
+                Loc::line(5),
+                KnobCmd::Tween {
+                    start_frame: 0,
+                    end_frame: 4,
+                    knoblist0: sym("start"),
+                    knoblist1: sym("end"),
+                },
+            ),
+        ];
+        let names: HashSet<Symbol> = vec![sym("spin"), sym("grow")].into_iter().collect();
+
+        let mut report = Report::default();
+        let (base, tweens) = run_knob_cmds(&cmds, &names, &mut report);
+        assert!(report.errors.is_empty());
+        assert_eq!(Some(&1.), base.get(&sym("spin")));
+        assert_eq!(Some(&0.), base.get(&sym("grow")));
+        assert_eq!(2, tweens.len());
+
+        let states = knob_states(6, &base, &tweens);
+        let spin: Vec<f64> = states.iter().map(|s| s[&sym("spin")]).collect();
+        assert_eq!(vec![0., 0.25, 0.5, 0.75, 1., 1.], spin);
+        assert!(states.iter().all(|s| s[&sym("grow")] == 0.));
+    }
+
+    #[test]
+    fn test_easing_endpoints() {
+        let easings = [
+            Easing::Linear,
+            Easing::EaseIn,
+            Easing::EaseOut,
+            Easing::EaseInOut,
+            Easing::Exponential,
+            Easing::Sine,
+            Easing::Bounce,
+            Easing::Elastic,
+        ];
+        for easing in easings.iter() {
+            assert!(easing.ease(0.).abs() < 1e-9, "{:?} at 0", easing);
+            assert!((easing.ease(1.) - 1.).abs() < 1e-9, "{:?} at 1", easing);
+        }
+        assert!(Easing::EaseIn.ease(0.25) < 0.25);
+        assert!(Easing::EaseOut.ease(0.25) > 0.25);
+        assert!((Easing::EaseInOut.ease(0.5) - 0.5).abs() < 1e-9);
+        // elastic overshoots the end value
+        assert!((0..100).any(|i| Easing::Elastic.ease(i as f64 / 100.) > 1.));
+    }
+
+    #[test]
+    fn test_tween_unknown_list() {
+        let cmds = vec![(
+            Loc::line(7),
+            KnobCmd::Tween {
+                start_frame: 0,
+                end_frame: 4,
+                knoblist0: sym("a"),
+                knoblist1: sym("b"),
+            },
+        )];
+        let mut report = Report::default();
+        let (_, tweens) = run_knob_cmds(&cmds, &HashSet::new(), &mut report);
+        assert!(tweens.is_empty());
+        // both lists are reported
+        let names: Vec<String> = report
+            .errors
+            .iter()
+            .map(|e| match e {
+                EngineError::Runtime {
+                    loc,
+                    source: RuntimeError::KnobListNotFound(name),
+                } if loc.line == 7 => name.to_owned(),
+                other => panic!("unexpected error: {:?}", other),
+            })
+            .collect();
+        assert_eq!(vec!["a", "b"], names);
+    }
+}
@@ -1,21 +1,32 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     io::{self, prelude::*, BufReader},
     ops::{Deref, DerefMut},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use crate::light::LightProps;
+use crate::{
+    gif,
+    light::{self, Light, LightProps},
+    vector::Vec3,
+    RGB,
+};
 
 use super::{
-    ast::{self, Command, Symbol, VaryInfo},
+    ast::{self, Command, Loc, Symbol, VaryInfo},
+    check,
+    diagnostics::{Report, SyntaxError, Warning},
+    exec::Meshes,
+    expr::{Env, EvalError, Expr},
+    knobs::{self, KnobCmd},
     result::{EngineError, EngineResult, RuntimeError},
     types::Kind,
-    utils::warn_unimpl,
     ExecContext,
 };
 
+#[derive(Debug, Clone)]
 pub struct SymTable<T>(HashMap<Symbol, T>);
 
 impl<T> SymTable<T> {
@@ -24,14 +35,20 @@ impl<T> SymTable<T> {
     }
 }
 
+impl<T> Default for SymTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SymTable<Kind> {
     /// Error if sym is not in table or if type doesn't match
-    fn check(&self, sym: &Option<Symbol>, kind: Kind) -> EngineResult<()> {
+    pub(crate) fn check(&self, sym: &Option<Symbol>, kind: Kind) -> Result<(), RuntimeError> {
         if let Some(ref s) = sym {
             match self.get(s) {
                 Some(got) => {
                     if *got != kind {
-                        return Err(EngineError::SymbolTypeMismatch {
+                        return Err(RuntimeError::SymbolTypeMismatch {
                             name: s.0.to_owned(),
                             expected: *got,
                             found: kind,
@@ -39,8 +56,9 @@ impl SymTable<Kind> {
                     }
                 }
                 None => {
-                    return Err(EngineError::SymbolNotFound {
+                    return Err(RuntimeError::UndefinedSymbol {
                         name: s.0.to_owned(),
+                        kind,
                     });
                 }
             }
@@ -78,139 +96,1006 @@ impl<T> DerefMut for SymTable<T> {
 }
 
 /// Parse file into ast and report errors
-pub(crate) fn parse_file<T: AsRef<Path>>(path: T) -> EngineResult<ExecContext> {
+///
+/// Every error of the script is gathered into one `EngineError::Diagnostics`,
+/// instead of stopping at the first one. Returns the warnings when there are no errors.
+pub(crate) fn parse_file<T: AsRef<Path>>(path: T) -> EngineResult<(ExecContext, Vec<Warning>)> {
     let fin = BufReader::new(File::open(path.as_ref())?);
+    let script = fin.lines().collect::<io::Result<Vec<String>>>()?;
+    parse_script(script, Some(path.as_ref()))
+}
 
-    let mut cmd_list: Vec<(usize, Command)> = vec![];
+/// Parse the lines of a script, see [`parse_file`]
+///
+/// `include` and meshes are relative to the directory of `path`, or to the current directory without one.
+pub(crate) fn parse_script(
+    script: Vec<String>,
+    path: Option<&Path>,
+) -> EngineResult<(ExecContext, Vec<Warning>)> {
+    let mut report = Report::default();
+    let mut cmd_list: Vec<(Loc, Command)> = vec![];
+    // every command, for checking symbols after this pass
+    let mut all_cmds: Vec<(Loc, Command)> = vec![];
 
-    let mut frames: Option<u32> = None;
+    // location of `frames` and the number of frames
+    let mut frames: Option<(Loc, u32)> = None;
     let mut basename: Option<String> = None;
-    let mut vary_list: Vec<(usize, VaryInfo)> = vec![];
+    let mut screen: Option<ast::Screen> = None;
+    let mut fps: Option<f64> = None;
+    let mut loops = 0;
+    let mut output = ast::FrameOutput::Gif;
+    let mut vary_list: Vec<(Loc, VaryInfo)> = vec![];
+    let mut knob_cmds: Vec<(Loc, KnobCmd)> = vec![];
+    let mut constants = Constants::default();
+    // `repeat` and `def` blocks that are still open, innermost last
+    let mut blocks: Vec<Block> = vec![];
+    // the script, and the files it's including, innermost last
+    let mut sources = vec![Source {
+        file: None,
+        dir: path
+            .and_then(Path::parent)
+            .map_or_else(PathBuf::new, Path::to_path_buf),
+        canonical: path
+            .map(|path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf()))
+            .unwrap_or_default(),
+        lines: script,
+        next: 0,
+    }];
+    // every file that's been included, which are only included once
+    let mut included: HashSet<PathBuf> = HashSet::new();
 
     let mut constants_table: SymTable<LightProps> = SymTable::new();
+    let mut lights_table: SymTable<Light> = SymTable::new();
+    let mut ambient: Option<RGB> = None;
 
-    let script = fin.lines().collect::<io::Result<Vec<String>>>()?;
+    let semantics = |loc, msg| EngineError::Runtime {
+        loc,
+        source: RuntimeError::Semantics(msg),
+    };
 
     // This is the first pass
-    // Deals with `frames`, `basename`, `vary` and `constants` commands
-    for (lnum, line) in script.iter().enumerate() {
-        let lnum = lnum + 1;
-        if let (_, Some(cmd)) = ast::parse_line(line).map_err(|nom_err| match nom_err {
-            nom::Err::Incomplete(_) => unreachable!(),
-            nom::Err::Error(e) | nom::Err::Failure(e) => EngineError::Syntax {
-                line: lnum,
-                input: e.0.to_owned(),
-                kind: e.1,
+    // Deals with animation, knob, `screen`, `constants`, `light` and `ambient` commands
+    while let Some(source) = sources.last_mut() {
+        let line = match source.lines.get(source.next) {
+            Some(line) => line.to_owned(),
+            None => {
+                // blocks end in the file they start in, and `include` can't be in one
+                for block in blocks.drain(..) {
+                    report.error(semantics(block.loc, "block is never closed with `}`"));
+                }
+                sources.pop();
+                continue;
+            }
+        };
+        source.next += 1;
+        let loc = Loc {
+            file: source.file.clone(),
+            line: source.next,
+        };
+
+        let (loc, cmd) = match ast::parse_line(&line) {
+            Ok((_, Some(ast::Line::Cmd(mut cmd)))) => {
+                resolve_mesh(&mut cmd, &source.dir);
+                (loc, *cmd)
+            }
+            Ok((_, Some(ast::Line::End))) => match blocks.pop() {
+                Some(block) => block.close(),
+                None => {
+                    report.error(semantics(loc, "`}` doesn't close any block"));
+                    continue;
+                }
             },
-        })? {
-            if let Command::AnimateCmd(animate_cmd) = cmd {
-                match animate_cmd {
-                    ast::Animate::Basename(name) => basename = Some(name),
-                    // ast::Animate::SetKnob { name, value } => {}
-                    // ast::Animate::SetAllKnobs(_) => {}
-                    // ast::Animate::Tween {
-                    //     start_frame,
-                    //     end_frame,
-                    //     knoblist0,
-                    //     knoblist1,
-                    // } => {}
-                    ast::Animate::Frames(f) => {
-                        if frames.is_some() {
-                            return Err(EngineError::Runtime {
-                                line: lnum,
-                                source: RuntimeError::MultipleFrameNumber,
-                            });
-                        } else {
-                            frames = Some(f)
+            Ok((_, Some(ast::Line::Include(path)))) => {
+                if !blocks.is_empty() {
+                    report.error(semantics(loc, "`include` can't be inside a block"));
+                    continue;
+                }
+                let path = source.dir.join(path);
+                match Source::open(&path) {
+                    Ok(source) if sources.iter().any(|s| s.canonical == source.canonical) => report
+                        .error(EngineError::Runtime {
+                            loc,
+                            source: RuntimeError::IncludeCycle(path.display().to_string()),
+                        }),
+                    Ok(source) => {
+                        if included.insert(source.canonical.clone()) {
+                            sources.push(source);
                         }
                     }
-                    ast::Animate::Vary(vary_info) => {
-                        if vary_info.start_frame >= vary_info.end_frame {
-                            return Err(EngineError::Runtime {
-                                line: lnum,
-                                source: RuntimeError::Semantics(
-                                    "start_frame of vary must be < end_frame",
+                    Err(e) => report.error(EngineError::Runtime {
+                        loc,
+                        source: RuntimeError::Include {
+                            path: path.display().to_string(),
+                            source: e,
+                        },
+                    }),
+                }
+                continue;
+            }
+            Ok((_, Some(header))) => {
+                blocks.push(Block {
+                    loc,
+                    header,
+                    body: vec![],
+                });
+                continue;
+            }
+            Ok((_, None)) => continue,
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                report.error(EngineError::Syntax(Box::new(SyntaxError::new(
+                    loc, &line, &e,
+                ))));
+                continue;
+            }
+        };
+        if let Some(block) = blocks.last_mut() {
+            if allowed_in_block(&cmd) {
+                block.body.push((loc, cmd));
+            } else {
+                report.error(semantics(loc, NOT_IN_BLOCK));
+            }
+            continue;
+        }
+        all_cmds.push((loc.clone(), cmd.clone()));
+
+        if let Command::AnimateCmd(animate_cmd) = cmd {
+            match animate_cmd {
+                ast::Animate::Basename(name) => basename = Some(name),
+                ast::Animate::Frames(f) => {
+                    if frames.is_some() {
+                        report.error(EngineError::Runtime {
+                            loc,
+                            source: RuntimeError::MultipleFrameNumber,
+                        });
+                    } else {
+                        frames = Some((loc, f))
+                    }
+                }
+                ast::Animate::Vary(vary) => {
+                    let values = constants.eval(&loc, |env| {
+                        Ok((vary.start_val.eval(env)?, vary.end_val.eval(env)?))
+                    });
+                    if vary.start_frame >= vary.end_frame {
+                        report.error(semantics(loc, "start_frame of vary must be < end_frame"));
+                    } else if let Some((start_val, end_val)) = values {
+                        vary_list.push((
+                            loc,
+                            VaryInfo {
+                                knob: vary.knob,
+                                start_frame: vary.start_frame,
+                                end_frame: vary.end_frame,
+                                start_val,
+                                end_val,
+                                easing: vary.easing,
+                            },
+                        ));
+                    }
+                }
+                ast::Animate::Fps(value) => match constants.eval(&loc, |env| value.eval(env)) {
+                    Some(value) if value <= 0. => report.error(semantics(loc, "fps must be > 0")),
+                    Some(value) => {
+                        if value > 100. / f64::from(gif::MIN_DELAY) {
+                            report.warn(
+                                Some(loc),
+                                format!(
+                                    "gifs play at most {} fps, so the animation is slowed down",
+                                    100 / gif::MIN_DELAY
                                 ),
-                            });
+                            );
                         }
-                        vary_list.push((lnum, vary_info));
+                        fps = Some(value)
+                    }
+                    None => {}
+                },
+                ast::Animate::Loop(count) => loops = count,
+                ast::Animate::Output(o) => output = o,
+                // `set`, `setknobs`, `save_knobs` and `tween` depend on each other, so they run in order after this pass
+                ast::Animate::SetKnob { name, value } => {
+                    if let Some(value) = constants.eval(&loc, |env| value.eval(env)) {
+                        knob_cmds.push((loc, KnobCmd::Set { name, value }));
                     }
-                    // ast::Animate::SaveKnobList(_) => {}
-                    _ => cmd_list.push((lnum, Command::AnimateCmd(animate_cmd))),
                 }
-            } else if let Command::LightingCmd(lighting_cmd) = cmd {
-                match lighting_cmd {
-                    ast::Lighting::Light {
-                        name: _,
-                        color: _,
-                        location: _,
-                    } => warn_unimpl("light", lnum),
-                    ast::Lighting::Ambient(_) => warn_unimpl("ambient", lnum),
-                    ast::Lighting::Constants { name, value } => {
-                        constants_table.insert(name, value.into());
+                ast::Animate::SetAllKnobs(value) => {
+                    if let Some(value) = constants.eval(&loc, |env| value.eval(env)) {
+                        knob_cmds.push((loc, KnobCmd::SetAll(value)));
                     }
-                    ast::Lighting::Shading(_) => {}
                 }
-            } else {
-                cmd_list.push((lnum, cmd));
+                ast::Animate::SaveKnobList(name) => knob_cmds.push((loc, KnobCmd::Save(name))),
+                ast::Animate::Tween {
+                    start_frame,
+                    end_frame,
+                    knoblist0,
+                    knoblist1,
+                } => knob_cmds.push((
+                    loc,
+                    KnobCmd::Tween {
+                        start_frame,
+                        end_frame,
+                        knoblist0,
+                        knoblist1,
+                    },
+                )),
             }
-        }
-    }
-    if !vary_list.is_empty() {
-        // animation mode enabled
-        let frames = match frames {
-            Some(f) => {
-                if f <= 1 {
-                    return Err(EngineError::Runtime {
-                        source: RuntimeError::Other(
-                            "Animation can't be enalbed (cannot use vary) when total number of frames <= 1",
-                        ),
-                        line: 0,
-                    });
-                } else {
-                    f
+        } else if let Command::LightingCmd(lighting_cmd) = cmd {
+            match lighting_cmd {
+                ast::Lighting::Light {
+                    name,
+                    color,
+                    location,
+                } => {
+                    let light =
+                        constants.eval(&loc, |env| Ok((color.eval(env)?, location.eval(env)?)));
+                    if let Some((color, (x, y, z))) = light {
+                        lights_table.insert(
+                            name,
+                            Light::Point {
+                                color,
+                                location: Vec3(x, y, z),
+                                fatt: light::fatt::no_effect,
+                            },
+                        );
+                    }
+                }
+                ast::Lighting::Ambient(color) => {
+                    if let Some(color) = constants.eval(&loc, |env| color.eval(env)) {
+                        ambient = Some(color);
+                    }
+                }
+                ast::Lighting::Constants { name, value } => {
+                    if let Some(props) = constants.eval(&loc, |env| value.eval(env)) {
+                        constants_table.insert(name, props);
+                    }
+                }
+                // shading can change anywhere in the script, so it's executed in order with the rest
+                ast::Lighting::Shading(mode) => {
+                    if mode == ast::ShadingMode::Raytrace {
+                        report.warn(Some(loc.clone()), "`shading raytrace` is not implemented");
+                    }
+                    cmd_list.push((loc, Command::LightingCmd(ast::Lighting::Shading(mode))))
                 }
             }
-            None => {
-                return Err(EngineError::Runtime {
-                    line: 0, // I don't want to change this field, so set to a convenient value
-                    source: RuntimeError::FramesUndefined,
+        } else if let Command::MiscCmd(ast::Misc::Screen(size)) = cmd {
+            if screen.is_some() {
+                report.error(EngineError::Runtime {
+                    loc,
+                    source: RuntimeError::MultipleScreen,
                 });
+            } else if let Err(source) = size.check() {
+                report.error(EngineError::Runtime { loc, source });
+            } else {
+                screen = Some(size);
             }
-        };
-
-        for (line, v) in vary_list.iter() {
-            if v.end_frame > frames {
-                return Err(EngineError::Runtime {
-                    line: *line,
-                    source: RuntimeError::Semantics(
-                        "end_frame of vary must be <= total number of frames",
-                    ),
-                });
+        } else {
+            if let Command::MiscCmd(ast::Misc::GenerateRayfiles) = cmd {
+                report.warn(Some(loc.clone()), "`generate_rayfiles` is not implemented");
             }
+            if let Command::Let { name, value } = &cmd {
+                constants.define(&loc, name, value);
+            }
+            cmd_list.push((loc, cmd));
         }
-
-        let basename = basename.unwrap_or_else(|| {
-            eprintln!(
-                "Animation enabled by frames > 1, but basename not given. Set to `output.gif`"
-            );
-            String::from("output.gif")
-        });
-        Ok(ExecContext::Animation {
-            script,
-            cmd_list,
-            basename,
-            frames,
-            vary_list,
-            light_props: constants_table,
+    }
+    let constants_names: HashSet<Symbol> = all_cmds
+        .iter()
+        .filter_map(|(_, cmd)| match cmd {
+            Command::LightingCmd(ast::Lighting::Constants { name, .. }) => Some(name.clone()),
+            _ => None,
         })
-    } else {
+        .collect();
+    let is_constants = |name: &Symbol| constants_names.contains(name);
+    check::resolve_constants(&mut all_cmds, &is_constants);
+    check::resolve_constants(&mut cmd_list, &is_constants);
+    let kinds = check::check_symbols(&all_cmds, &mut report);
+    check::check_stack(&all_cmds, &mut report);
+    constants.report(&kinds, &mut report);
+    let env_lights = collect_env_lights(lights_table, ambient);
+    let mut meshes = Meshes::default();
+    meshes.load(&cmd_list, &mut report);
+    let screen = screen.unwrap_or(ast::Screen::DEFAULT);
+
+    let names = knobs::knob_names(&cmd_list, &vary_list, &knob_cmds);
+    let (base_knobs, tweens) = knobs::run_knob_cmds(&knob_cmds, &names, &mut report);
+    vary_list.extend(tweens);
+
+    if vary_list.is_empty() {
         // no animation
-        Ok(ExecContext::NoAnimation {
-            script,
+        let context = ExecContext::NoAnimation {
             cmd_list,
-            basename: basename.unwrap_or_else(|| String::from("output.png")),
+            knobs: base_knobs,
             light_props: constants_table,
+            meshes,
+            env_lights,
+            screen,
+        };
+        return report.finish(context);
+    }
+
+    // animation mode enabled
+    let frames = match frames {
+        Some((_, f)) if f > 1 => f,
+        Some((loc, _)) => {
+            report.error(EngineError::Runtime {
+                source: RuntimeError::Other(
+                    "Animation can't be enalbed (cannot use vary) when total number of frames <= 1",
+                ),
+                loc,
+            });
+            0
+        }
+        None => {
+            report.error(EngineError::Runtime {
+                // the first `vary` or `tween`, which is what needs the frames
+                loc: vary_list
+                    .iter()
+                    .map(|(loc, _)| loc)
+                    .min()
+                    .cloned()
+                    .unwrap_or_else(|| Loc::line(0)),
+                source: RuntimeError::FramesUndefined,
+            });
+            0
+        }
+    };
+
+    if frames > 1 {
+        for (loc, v) in vary_list.iter() {
+            if v.end_frame > frames {
+                report.error(semantics(
+                    loc.clone(),
+                    "end_frame of vary or tween must be <= total number of frames",
+                ));
+            }
+        }
+    }
+    warn_overlapping_vary(&vary_list, &mut report);
+
+    let basename = basename.unwrap_or_else(|| {
+        report.warn(
+            None,
+            "Animation enabled by frames > 1, but basename not given. Set to `output`",
+        );
+        String::from("output")
+    });
+    for (loc, cmd) in ast::flatten(&cmd_list) {
+        if let Command::MiscCmd(ast::Misc::Display) = cmd {
+            report.warn(Some(loc.clone()), "`display` is disabled in animation mode");
+        }
+    }
+
+    report.finish(ExecContext::Animation {
+        cmd_list,
+        basename,
+        frames,
+        vary_list,
+        base_knobs,
+        fps,
+        loops,
+        output,
+        light_props: constants_table,
+        meshes,
+        env_lights,
+        screen,
+    })
+}
+
+pub(crate) const NOT_IN_BLOCK: &str =
+    "animation commands, `light`, `ambient`, `constants`, `screen` and `def` can't be inside a block";
+
+/// A `repeat` or `def` that's still open
+pub(crate) struct Block {
+    /// Location of the header, which is the location of the whole block
+    pub(crate) loc: Loc,
+    pub(crate) header: ast::Line,
+    pub(crate) body: Vec<(Loc, Command)>,
+}
+
+impl Block {
+    pub(crate) fn close(self) -> (Loc, Command) {
+        let body = self.body;
+        let cmd = match self.header {
+            ast::Line::Repeat { count, var } => Command::Repeat { count, var, body },
+            ast::Line::Def { name, params } => Command::Def { name, params, body },
+            ast::Line::Cmd(_) | ast::Line::End | ast::Line::Include(_) => unreachable!(),
+        };
+        (self.loc, cmd)
+    }
+}
+
+/// A file whose lines are being parsed
+struct Source {
+    /// Path of an included file, as shown in errors
+    file: Option<Arc<Path>>,
+    /// Directory that `include` is relative to
+    dir: PathBuf,
+    /// Path to compare files with, to find `include` cycles
+    canonical: PathBuf,
+    lines: Vec<String>,
+    /// Index of the next line to parse
+    next: usize,
+}
+
+impl Source {
+    fn open(path: &Path) -> io::Result<Self> {
+        let lines = BufReader::new(File::open(path)?)
+            .lines()
+            .collect::<io::Result<Vec<String>>>()?;
+        Ok(Source {
+            file: Some(Arc::from(path)),
+            dir: path.parent().map_or_else(PathBuf::new, Path::to_path_buf),
+            canonical: path.canonicalize()?,
+            lines,
+            next: 0,
         })
     }
 }
+
+/// Blocks can only have commands that run while rendering, except `def`
+///
+/// Everything else is handled before rendering, once for the whole script.
+pub(crate) fn allowed_in_block(cmd: &Command) -> bool {
+    match cmd {
+        Command::AnimateCmd(_) | Command::Def { .. } => false,
+        Command::LightingCmd(lighting) => matches!(lighting, ast::Lighting::Shading(_)),
+        Command::MiscCmd(misc) => !matches!(misc, ast::Misc::Screen(_)),
+        _ => true,
+    }
+}
+
+/// Evaluates the values of commands that run before rendering, which are the same in every frame
+#[derive(Default)]
+struct Constants {
+    /// `let` variables that don't depend on knobs or the frame
+    vars: SymTable<f64>,
+    /// `let` variables that do, at least once
+    per_frame: HashSet<Symbol>,
+    /// Names that values couldn't be evaluated without, and the location of the value
+    missing: Vec<(Loc, Symbol)>,
+    /// Values that aren't finite
+    not_finite: Vec<(Loc, f64)>,
+}
+
+impl Constants {
+    fn eval<T>(&mut self, loc: &Loc, f: impl FnOnce(&Env) -> Result<T, EvalError>) -> Option<T> {
+        let knobs = SymTable::new();
+        let env = Env {
+            vars: &self.vars,
+            knobs: &knobs,
+            frame: None,
+        };
+        match f(&env) {
+            Ok(value) => Some(value),
+            Err(EvalError::NoValue(name)) => {
+                self.missing.push((loc.clone(), name));
+                None
+            }
+            Err(EvalError::NotFinite(v)) => {
+                self.not_finite.push((loc.clone(), v));
+                None
+            }
+        }
+    }
+
+    /// `let`, which is still run again in every frame
+    fn define(&mut self, loc: &Loc, name: &Symbol, value: &Expr) {
+        let knobs = SymTable::new();
+        let env = Env {
+            vars: &self.vars,
+            knobs: &knobs,
+            frame: None,
+        };
+        match value.eval(&env) {
+            Ok(value) => {
+                self.vars.insert(name.clone(), value);
+            }
+            Err(EvalError::NoValue(_)) => {
+                self.vars.remove(name);
+                self.per_frame.insert(name.clone());
+            }
+            Err(EvalError::NotFinite(v)) => {
+                self.vars.remove(name);
+                self.not_finite.push((loc.clone(), v));
+            }
+        }
+    }
+
+    /// Report the values that aren't finite, and the names that change from frame to frame
+    ///
+    /// Names that aren't defined, or are used before they are, are reported by `check`.
+    fn report(self, kinds: &SymTable<Kind>, report: &mut Report) {
+        for (loc, v) in self.not_finite {
+            report.error(EngineError::Runtime {
+                loc,
+                source: RuntimeError::NotFinite(v),
+            });
+        }
+        for (loc, name) in self.missing {
+            if name.0 == "frame"
+                || self.per_frame.contains(&name)
+                || kinds.get(&name) == Some(&Kind::Knob)
+            {
+                report.error(EngineError::Runtime {
+                    loc,
+                    source: RuntimeError::NotConstant(name.0),
+                });
+            }
+        }
+    }
+}
+
+/// Warn about `vary` commands, or tweens, that change the same knob in the same frames
+///
+/// The one that comes last in the script wins where they overlap.
+fn warn_overlapping_vary(vary_list: &[(Loc, VaryInfo)], report: &mut Report) {
+    for (i, (loc0, v0)) in vary_list.iter().enumerate() {
+        for (loc1, v1) in vary_list[i + 1..].iter() {
+            if v0.knob == v1.knob
+                && v0.start_frame <= v1.end_frame
+                && v1.start_frame <= v0.end_frame
+            {
+                report.warn(
+                    Some(loc1.clone()),
+                    format!(
+                        "vary of knob {} overlaps the one on {}, using this one where they overlap",
+                        v1.knob.0, loc0
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Make the file of a mesh relative to `dir`, the directory of the script it's in, like `include`
+pub(crate) fn resolve_mesh(cmd: &mut Command, dir: &Path) {
+    if let Command::ShapeCmd(ast::Shape::Mesh { filename, .. }) = cmd {
+        *filename = dir.join(&filename).to_string_lossy().into_owned();
+    }
+}
+
+/// Gather the lights defined in the script into the list used by `Drawer` as `env_lights`
+///
+/// Lights are sorted by name so that a script always renders the same way.
+/// An empty list means that the script has no lighting commands, so `DrawerBuilder` falls back to the default lights.
+pub(crate) fn collect_env_lights(
+    lights_table: SymTable<Light>,
+    ambient: Option<RGB>,
+) -> Vec<Light> {
+    let mut named_lights: Vec<(Symbol, Light)> = lights_table.0.into_iter().collect();
+    named_lights.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+
+    ambient
+        .map(Light::Ambient)
+        .into_iter()
+        .chain(named_lights.into_iter().map(|(_, light)| light))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_all_errors_reported() {
+        let result = parse_script(
+            script(&[
+                "frames 10",
+                "sphre 0 0 0 50",
+                "frames 20",
+                "vary k 5 2 0 1",
+                "move 1 2 three",
+                "tween 0 5 nope nada",
+            ]),
+            None,
+        );
+        match result {
+            Err(EngineError::Diagnostics(report)) => {
+                let lines: Vec<Option<usize>> = report
+                    .errors
+                    .iter()
+                    .map(|e| e.loc().map(|loc| loc.line))
+                    .collect();
+                assert_eq!(
+                    vec![Some(2), Some(3), Some(4), Some(5), Some(6), Some(6)],
+                    lines
+                );
+            }
+            other => panic!("expected diagnostics, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_warnings() {
+        let (_, warnings) = parse_script(
+            script(&[
+                "frames 10",
+                "vary k 0 5 0 1",
+                "vary k 3 9 1 0",
+                "rotate y 90 k",
+                "move 0 0 1 nothing",
+                "display",
+                "fps 60",
+            ]),
+            None,
+        )
+        .unwrap();
+        let lines: Vec<Option<usize>> = warnings
+            .iter()
+            .map(|w| w.loc.as_ref().map(|loc| loc.line))
+            .collect();
+        // basename, overlapping vary, unset knob, display in animation, fps faster than a gif
+        assert_eq!(vec![None, Some(3), Some(5), Some(6), Some(7)], lines);
+    }
+
+    #[test]
+    fn test_values_must_be_finite() {
+        let result = parse_script(
+            script(&[
+                "let x = 1e400",
+                "sphere 0 0 0 x",
+                "ambient 1e308*10 0 0",
+                "constants c 0 0 0 0 0 0 0 0 ln(0)",
+            ]),
+            None,
+        );
+        match result {
+            Err(EngineError::Diagnostics(report)) => {
+                let errors: Vec<String> = report.errors.iter().map(|e| e.to_string()).collect();
+                assert_eq!(
+                    vec![
+                        "runtime error on line 1: value is inf, which is too large or not a number",
+                        "runtime error on line 3: value is inf, which is too large or not a number",
+                        "runtime error on line 4: value is -inf, which is too large or not a number",
+                    ],
+                    errors
+                );
+            }
+            other => panic!("expected diagnostics, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_screen_size() {
+        let result = parse_script(script(&["screen 70000 70000", "screen 0 10"]), None);
+        match result {
+            Err(EngineError::Diagnostics(report)) => {
+                let errors: Vec<String> = report.errors.iter().map(|e| e.to_string()).collect();
+                assert_eq!(
+                    vec![
+                        "runtime error on line 1: screen of 70000x70000 has more than 33554432 pixels",
+                        "runtime error on line 2: semantics error: width, height and depth of screen must be > 0",
+                    ],
+                    errors
+                );
+            }
+            other => panic!("expected diagnostics, got {:?}", other.map(|_| ())),
+        }
+        assert!(parse_script(script(&["screen 7680 4320"]), None).is_ok());
+    }
+
+    #[test]
+    fn test_constant_values() {
+        let (context, _) = parse_script(
+            script(&[
+                "let half = 255 / 2",
+                "ambient half half*2 0",
+                "set k half/255",
+                "let spin = k * 360",
+                "rotate y spin",
+            ]),
+            None,
+        )
+        .unwrap();
+        match context {
+            ExecContext::NoAnimation {
+                knobs, env_lights, ..
+            } => {
+                assert_eq!(Some(&0.5), knobs.get(&Symbol(String::from("k"))));
+                match env_lights[0] {
+                    Light::Ambient(color) => assert_eq!(RGB::new(127, 255, 0), color),
+                    _ => panic!("expected ambient light"),
+                }
+            }
+            _ => panic!("expected a still image"),
+        }
+
+        let result = parse_script(
+            script(&[
+                "frames 10",
+                "vary k 0 9 0 1",
+                "let size = k * 10",
+                "set other size",
+                "ambient frame 0 0",
+                "fps k",
+            ]),
+            None,
+        );
+        match result {
+            Err(EngineError::Diagnostics(report)) => {
+                let errors: Vec<String> = report.errors.iter().map(|e| e.to_string()).collect();
+                assert_eq!(
+                    vec![
+                        "runtime error on line 4: size can change from frame to frame, so it can't be used here",
+                        "runtime error on line 5: frame can change from frame to frame, so it can't be used here",
+                        "runtime error on line 6: k can change from frame to frame, so it can't be used here",
+                    ],
+                    errors
+                );
+            }
+            other => panic!("expected diagnostics, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_blocks() {
+        let (context, _) = parse_script(
+            script(&[
+                "def spoke(angle) {",
+                "  push",
+                "  rotate z angle",
+                "  box 0 0 0 1 1 1",
+                "  pop",
+                "}",
+                "repeat 4 as i {",
+                "  repeat 2 {",
+                "    call spoke(i * 90)",
+                "  }",
+                "}",
+            ]),
+            None,
+        )
+        .unwrap();
+        let cmd_list = match context {
+            ExecContext::NoAnimation { cmd_list, .. } => cmd_list,
+            _ => panic!("expected a still image"),
+        };
+        assert_eq!(
+            vec![1, 7],
+            cmd_list.iter().map(|(loc, _)| loc.line).collect::<Vec<_>>()
+        );
+        let lines: Vec<usize> = ast::flatten(&cmd_list)
+            .iter()
+            .map(|(loc, _)| loc.line)
+            .collect();
+        assert_eq!(vec![1, 2, 3, 4, 5, 7, 8, 9], lines);
+
+        let result = parse_script(
+            script(&[
+                "}",
+                "repeat 2 {",
+                "  frames 10",
+                "  def inner() {",
+                "  }",
+                "}",
+                "repeat 3 {",
+            ]),
+            None,
+        );
+        match result {
+            Err(EngineError::Diagnostics(report)) => {
+                let lines: Vec<Option<usize>> = report
+                    .errors
+                    .iter()
+                    .map(|e| e.loc().map(|loc| loc.line))
+                    .collect();
+                assert_eq!(vec![Some(1), Some(3), Some(4), Some(7)], lines);
+            }
+            other => panic!("expected diagnostics, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_variables_in_place_of_constants() {
+        let (context, _) = parse_script(
+            script(&[
+                "let a = 10",
+                "let r = 5",
+                "save_coord_system c",
+                "sphere a a a r c",
+                "sphere shiny a a a r",
+                "def ball(x) {",
+                "  box x x x r r r c",
+                "}",
+                "call ball(1)",
+                "constants shiny 0.2 0.5 0.8 0.2 0.5 0.8 0.2 0.5 0.8",
+            ]),
+            None,
+        )
+        .unwrap();
+        let cmd_list = match context {
+            ExecContext::NoAnimation { cmd_list, .. } => cmd_list,
+            _ => panic!("expected a still image"),
+        };
+        let shapes: Vec<(usize, Option<Symbol>, Option<Symbol>)> = ast::flatten(&cmd_list)
+            .into_iter()
+            .filter_map(|(loc, cmd)| match cmd {
+                Command::ShapeCmd(ast::Shape::Sphere {
+                    constants, coord, ..
+                })
+                | Command::ShapeCmd(ast::Shape::Box {
+                    constants, coord, ..
+                }) => Some((loc.line, constants.clone(), coord.clone())),
+                _ => None,
+            })
+            .collect();
+        let sym = |name: &str| Some(Symbol(name.to_owned()));
+        // constants can be defined after they're used
+        assert_eq!(
+            vec![
+                (4, None, sym("c")),
+                (5, sym("shiny"), None),
+                (7, None, sym("c"))
+            ],
+            shapes
+        );
+    }
+
+    #[test]
+    fn test_meshes_are_relative_to_their_script() {
+        let dir = std::env::temp_dir().join(format!("mdl-mesh-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("lib/tri.obj"),
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("lib/shapes.mdl"),
+            "mesh :tri.obj\nrepeat 2 {\n  mesh :tri.obj\n}\n",
+        )
+        .unwrap();
+        let main = dir.join("main.mdl");
+        std::fs::write(&main, "include :lib/shapes.mdl\nmesh :tri.obj\n").unwrap();
+
+        let result = parse_file(&main);
+        std::fs::write(&main, "include :lib/shapes.mdl\n").unwrap();
+        let filenames: Vec<String> = match parse_file(&main).unwrap().0 {
+            ExecContext::NoAnimation { cmd_list, .. } => ast::flatten(&cmd_list)
+                .into_iter()
+                .filter_map(|(_, cmd)| match cmd {
+                    Command::ShapeCmd(ast::Shape::Mesh { filename, .. }) => Some(filename.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => panic!("expected a still image"),
+        };
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let tri = dir.join("lib/tri.obj").to_string_lossy().into_owned();
+        assert_eq!(vec![tri.clone(), tri], filenames);
+        match result {
+            Err(EngineError::Diagnostics(report)) => {
+                assert_eq!(1, report.errors.len());
+                assert_eq!(Some(&Loc::line(2)), report.errors[0].loc());
+                assert!(report.errors[0]
+                    .to_string()
+                    .contains(&dir.join("tri.obj").display().to_string()));
+            }
+            other => panic!("expected diagnostics, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("mdl-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        let write = |name: &str, lines: &[&str]| {
+            std::fs::write(dir.join(name), lines.join("\n")).unwrap();
+            dir.join(name)
+        };
+        let main = write(
+            "main.mdl",
+            &[
+                "include :lib/materials.mdl",
+                "call ball(10)",
+                "include :lib/materials.mdl",
+            ],
+        );
+        let materials = write(
+            "lib/materials.mdl",
+            &[
+                "constants shiny 0.2 0.5 0.8 0.2 0.5 0.8 0.2 0.5 0.8",
+                "include :shapes.mdl",
+                "def ball(r) {",
+                "  sphere shiny 0 0 0 r",
+                "}",
+            ],
+        );
+        let shapes = write("lib/shapes.mdl", &["box shiny 0 0 0 1 1 1"]);
+
+        let loc = |file: &PathBuf, line| Loc {
+            file: Some(Arc::from(file.as_path())),
+            line,
+        };
+        match parse_file(&main).unwrap().0 {
+            ExecContext::NoAnimation {
+                cmd_list,
+                light_props,
+                ..
+            } => {
+                // the second include of materials.mdl does nothing
+                let locs: Vec<Loc> = cmd_list.into_iter().map(|(loc, _)| loc).collect();
+                assert_eq!(
+                    vec![loc(&shapes, 1), loc(&materials, 3), Loc::line(2)],
+                    locs
+                );
+                assert!(light_props.contains_key(&Symbol(String::from("shiny"))));
+            }
+            _ => panic!("expected a still image"),
+        }
+
+        write(
+            "lib/shapes.mdl",
+            &["include :../main.mdl", "box 0 0 0 1 1 1 nope"],
+        );
+        let result = parse_file(&main);
+        std::fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(EngineError::Diagnostics(report)) => {
+                let errors: Vec<(Loc, String)> = report
+                    .errors
+                    .iter()
+                    .map(|e| match e {
+                        EngineError::Runtime { loc, source } => (loc.clone(), source.to_string()),
+                        other => panic!("unexpected error {:?}", other),
+                    })
+                    .collect();
+                assert_eq!(
+                    vec![
+                        (
+                            loc(&shapes, 1),
+                            format!(
+                                "\"{}\" ends up including itself",
+                                dir.join("lib/../main.mdl").display()
+                            )
+                        ),
+                        (
+                            loc(&shapes, 2),
+                            String::from("coord_system nope is not defined")
+                        ),
+                    ],
+                    errors
+                );
+            }
+            other => panic!("expected diagnostics, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    fn point_light(x: f64) -> Light {
+        Light::Point {
+            color: RGB::WHITE,
+            location: Vec3(x, 0., 0.),
+            fatt: light::fatt::no_effect,
+        }
+    }
+
+    #[test]
+    fn test_no_lighting_commands_keeps_defaults() {
+        assert!(collect_env_lights(SymTable::new(), None).is_empty());
+    }
+
+    #[test]
+    fn test_env_lights_sorted_by_name() {
+        let mut table = SymTable::new();
+        table.insert(Symbol(String::from("b")), point_light(2.));
+        table.insert(Symbol(String::from("a")), point_light(1.));
+
+        let lights = collect_env_lights(table, Some(RGB::new(10, 20, 30)));
+        assert_eq!(3, lights.len());
+        match lights[0] {
+            Light::Ambient(color) => assert_eq!(RGB::new(10, 20, 30), color),
+            _ => panic!("ambient light should come first"),
+        }
+        let xs: Vec<f64> = lights[1..]
+            .iter()
+            .map(|l| match l {
+                Light::Point { location, .. } => location.x(),
+                _ => panic!("expected point light"),
+            })
+            .collect();
+        assert_eq!(vec![1., 2.], xs);
+    }
+}
@@ -0,0 +1,527 @@
+//! Interactive MDL, which runs one line at a time and keeps the image and the symbols between them
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    slice,
+    sync::Arc,
+};
+
+use crate::{
+    drawer::{Drawer, DrawerBuilder},
+    light::{Light, LightProps},
+    vector::Vec3,
+    PPMImg, RGB,
+};
+
+use super::{
+    ast::{self, Command, Line, Loc, Screen, Symbol},
+    check,
+    diagnostics::{Report, SyntaxError},
+    exec::{Meshes, Output, Run, State},
+    expr::{Env, EvalError},
+    parser::{allowed_in_block, collect_env_lights, resolve_mesh, Block, SymTable, NOT_IN_BLOCK},
+    result::{EngineError, EngineResult, RuntimeError},
+};
+
+/// Number of images kept for `:undo`
+const UNDO_LIMIT: usize = 20;
+
+const HELP: &str = "\
+Type MDL commands, one per line, see scripts/MDL.spec. `display` and `save` show the image so far.
+  :top      print the matrix at the top of the stack
+  :symbols  list knobs, constants, lights, coordinate systems, variables and defs
+  :undo     erase what the last shape, `repeat` or `call` drew
+  :help     print this
+  :quit     leave, so does the end of the input";
+
+/// Interpreter that keeps its state between lines, for trying out commands
+pub struct Repl {
+    drawer: Drawer<PPMImg>,
+    knobs: SymTable<f64>,
+    light_props: SymTable<LightProps>,
+    lights: SymTable<Light>,
+    ambient: Option<RGB>,
+    /// Meshes drawn so far, each read once
+    meshes: Meshes,
+    state: State,
+    /// `repeat` and `def` blocks that are still open, innermost last
+    blocks: Vec<Block>,
+    /// Images from before the latest drawing commands, most recent last
+    history: Vec<PPMImg>,
+    /// Number of lines typed so far
+    lines: usize,
+    /// Files being included, innermost last
+    including: Vec<PathBuf>,
+}
+
+impl Repl {
+    pub fn new(screen: Screen) -> Self {
+        Self {
+            drawer: DrawerBuilder::new(PPMImg::new(screen.width, screen.height, screen.depth))
+                .build(),
+            knobs: SymTable::new(),
+            light_props: SymTable::new(),
+            lights: SymTable::new(),
+            ambient: None,
+            meshes: Meshes::default(),
+            state: State::new(),
+            blocks: vec![],
+            history: vec![],
+            lines: 0,
+            including: vec![],
+        }
+    }
+
+    /// Read lines from `input` until it ends or `:quit`, and write prompts, results and errors to `output`
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            write!(output, "{}", self.prompt())?;
+            output.flush()?;
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+            let line = line.trim_end_matches(&['\n', '\r'][..]);
+            if line.trim() == ":quit" {
+                return Ok(());
+            }
+            match self.eval(line) {
+                Ok(Some(text)) => writeln!(output, "{}", text)?,
+                Ok(None) => {}
+                Err(e) => writeln!(output, "{}", e)?,
+            }
+        }
+    }
+
+    /// The prompt shows when a block is still open
+    pub fn prompt(&self) -> &'static str {
+        if self.blocks.is_empty() {
+            "mdl> "
+        } else {
+            "...> "
+        }
+    }
+
+    /// Run a line of MDL or a meta-command, and return the text a meta-command prints
+    pub fn eval(&mut self, line: &str) -> EngineResult<Option<String>> {
+        self.lines += 1;
+        match line.trim().strip_prefix(':') {
+            Some(meta) => Ok(Some(self.meta(meta))),
+            None => self.eval_line(Loc::line(self.lines), line).map(|()| None),
+        }
+    }
+
+    pub fn drawer(&self) -> &Drawer<PPMImg> {
+        &self.drawer
+    }
+
+    fn meta(&mut self, meta: &str) -> String {
+        match meta {
+            "top" => self.drawer.get_top_matrix().to_string(),
+            "symbols" => self.symbols(),
+            "undo" => match self.history.pop() {
+                Some(img) => {
+                    *self.drawer.canvas_mut() = img;
+                    format!("undone, {} more can be undone", self.history.len())
+                }
+                None => "nothing to undo".to_owned(),
+            },
+            "help" => HELP.to_owned(),
+            _ => format!("unknown command `:{}`, try :help", meta),
+        }
+    }
+
+    /// Every symbol, grouped by kind and sorted by name
+    fn symbols(&self) -> String {
+        fn sorted<T>(table: &SymTable<T>) -> Vec<(&Symbol, &T)> {
+            let mut symbols: Vec<(&Symbol, &T)> = table.iter().collect();
+            symbols.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+            symbols
+        }
+
+        let mut lines = vec![];
+        for (name, value) in sorted(&self.knobs) {
+            lines.push(format!("knob {} = {}", name.0, value));
+        }
+        for (name, _) in sorted(&self.light_props) {
+            lines.push(format!("constants {}", name.0));
+        }
+        for (name, _) in sorted(&self.lights) {
+            lines.push(format!("light {}", name.0));
+        }
+        for (name, _) in sorted(&self.state.coords) {
+            lines.push(format!("coord_system {}", name.0));
+        }
+        for (name, value) in sorted(&self.state.vars) {
+            lines.push(format!("let {} = {}", name.0, value));
+        }
+        for (name, def) in sorted(&self.state.defs) {
+            let params: Vec<&str> = def.params.iter().map(|param| &param.0[..]).collect();
+            lines.push(format!("def {}({})", name.0, params.join(", ")));
+        }
+        if lines.is_empty() {
+            "no symbols yet".to_owned()
+        } else {
+            lines.join("\n")
+        }
+    }
+
+    fn eval_line(&mut self, loc: Loc, line: &str) -> EngineResult<()> {
+        match ast::parse_line(line) {
+            Ok((_, Some(Line::Cmd(mut cmd)))) => {
+                // relative to the file that includes it, or to the working directory when typed
+                let dir = self.including.last().and_then(|file| file.parent());
+                resolve_mesh(&mut cmd, dir.unwrap_or_else(|| Path::new("")));
+                self.command(loc, *cmd)
+            }
+            Ok((_, Some(Line::End))) => match self.blocks.pop() {
+                Some(block) => {
+                    let (loc, cmd) = block.close();
+                    self.command(loc, cmd)
+                }
+                None => Err(semantics(loc, "`}` doesn't close any block")),
+            },
+            Ok((_, Some(Line::Include(path)))) => {
+                if self.blocks.is_empty() {
+                    self.include(loc, &path)
+                } else {
+                    Err(semantics(loc, "`include` can't be inside a block"))
+                }
+            }
+            Ok((_, Some(header))) => {
+                self.blocks.push(Block {
+                    loc,
+                    header,
+                    body: vec![],
+                });
+                Ok(())
+            }
+            Ok((_, None)) => Ok(()),
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(EngineError::Syntax(
+                Box::new(SyntaxError::new(loc, line, &e)),
+            )),
+        }
+    }
+
+    /// Run every line of a file, which is read again each time it's included
+    fn include(&mut self, loc: Loc, path: &str) -> EngineResult<()> {
+        // relative to the file that includes it, or to the working directory when typed
+        let path = match self.including.last().and_then(|file| file.parent()) {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        };
+        let include_error = |loc, e| EngineError::Runtime {
+            loc,
+            source: RuntimeError::Include {
+                path: path.display().to_string(),
+                source: e,
+            },
+        };
+        let canonical = match path.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(e) => return Err(include_error(loc, e)),
+        };
+        if self.including.contains(&canonical) {
+            return Err(EngineError::Runtime {
+                loc,
+                source: RuntimeError::IncludeCycle(path.display().to_string()),
+            });
+        }
+        let script = match fs::read_to_string(&path) {
+            Ok(script) => script,
+            Err(e) => return Err(include_error(loc, e)),
+        };
+
+        let file: Arc<Path> = Arc::from(path.as_path());
+        self.including.push(canonical);
+        let result = script.lines().enumerate().try_for_each(|(i, line)| {
+            let loc = Loc {
+                file: Some(file.clone()),
+                line: i + 1,
+            };
+            self.eval_line(loc, line)
+        });
+        self.including.pop();
+        // blocks end in the file they start in
+        let unclosed = self.blocks.drain(..).next();
+        result?;
+        match unclosed {
+            Some(block) => Err(semantics(block.loc, "block is never closed with `}`")),
+            None => Ok(()),
+        }
+    }
+
+    fn command(&mut self, loc: Loc, cmd: Command) -> EngineResult<()> {
+        if let Some(block) = self.blocks.last_mut() {
+            if !allowed_in_block(&cmd) {
+                return Err(semantics(loc, NOT_IN_BLOCK));
+            }
+            block.body.push((loc, cmd));
+            return Ok(());
+        }
+
+        let mut cmds = [(loc, cmd)];
+        // only the constants defined so far can be used
+        check::resolve_constants(&mut cmds, &|name| self.light_props.contains_key(name));
+        // a mesh is read the first time a command draws it or a def has it, and kept for the next ones
+        let mut report = Report::default();
+        self.meshes.load(&cmds, &mut report);
+        if let Some(e) = report.errors.into_iter().next() {
+            return Err(e);
+        }
+        let [(loc, cmd)] = cmds;
+
+        match cmd {
+            Command::AnimateCmd(ast::Animate::SetKnob { name, value }) => {
+                let value = self.value(&loc, |env| value.eval(env))?;
+                self.knobs.insert(name, value);
+            }
+            Command::AnimateCmd(ast::Animate::SetAllKnobs(value)) => {
+                let value = self.value(&loc, |env| value.eval(env))?;
+                for knob in self.knobs.values_mut() {
+                    *knob = value;
+                }
+            }
+            Command::AnimateCmd(_) => {
+                return Err(semantics(
+                    loc,
+                    "animation commands other than `set` and `setknobs` only work in a script",
+                ))
+            }
+            Command::LightingCmd(ast::Lighting::Light {
+                name,
+                color,
+                location,
+            }) => {
+                let (color, (x, y, z)) =
+                    self.value(&loc, |env| Ok((color.eval(env)?, location.eval(env)?)))?;
+                self.lights.insert(
+                    name,
+                    Light::Point {
+                        color,
+                        location: Vec3(x, y, z),
+                        fatt: crate::light::fatt::no_effect,
+                    },
+                );
+                self.update_lights();
+            }
+            Command::LightingCmd(ast::Lighting::Ambient(color)) => {
+                self.ambient = Some(self.value(&loc, |env| color.eval(env))?);
+                self.update_lights();
+            }
+            Command::LightingCmd(ast::Lighting::Constants { name, value }) => {
+                let props = self.value(&loc, |env| value.eval(env))?;
+                self.light_props.insert(name, props);
+            }
+            Command::MiscCmd(ast::Misc::Screen(screen)) => {
+                if let Err(source) = screen.check() {
+                    return Err(EngineError::Runtime { loc, source });
+                }
+                // a new, empty image, drawn the same way as the old one
+                let drawer =
+                    DrawerBuilder::new(PPMImg::new(screen.width, screen.height, screen.depth))
+                        .with_lights(self.drawer.env_lights.clone())
+                        .with_shading(self.drawer.shading)
+                        .build();
+                self.drawer = drawer;
+                self.history.clear();
+            }
+            Command::Def { .. } => {
+                // only the defs that exist so far can be checked, calls to later ones are checked when they run
+                let mut defs: Vec<(Loc, Command)> = self
+                    .state
+                    .defs
+                    .iter()
+                    .map(|(name, def)| {
+                        let def = Command::Def {
+                            name: name.to_owned(),
+                            params: def.params.to_owned(),
+                            body: def.body.to_owned(),
+                        };
+                        (loc.clone(), def)
+                    })
+                    .collect();
+                // a cycle the new def makes goes through one of its calls
+                let calls: Vec<Loc> = cmd
+                    .body()
+                    .map(ast::flatten)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(loc, _)| loc.clone())
+                    .collect();
+                let mut report = Report::default();
+                defs.push((loc, cmd));
+                check::check_recursion(&defs, &mut report);
+                if let Some(e) = report
+                    .errors
+                    .into_iter()
+                    .find(|e| e.loc().is_some_and(|loc| calls.contains(loc)))
+                {
+                    return Err(e);
+                }
+                self.state.define(slice::from_ref(defs.last().unwrap()));
+            }
+            cmd => {
+                let draws = matches!(
+                    cmd,
+                    Command::ShapeCmd(_) | Command::Repeat { .. } | Command::Call { .. }
+                );
+                let before = if draws {
+                    Some(self.drawer.canvas().clone())
+                } else {
+                    None
+                };
+                let result = Run::new(
+                    &mut self.drawer,
+                    &self.knobs,
+                    &self.light_props,
+                    &self.meshes,
+                    &mut self.state,
+                    None,
+                    Output::Files(None),
+                )
+                .exec(&loc, &cmd);
+                // a command that fails part way can still have drawn something
+                if let Some(before) = before.filter(|img| img != self.drawer.canvas()) {
+                    if self.history.len() == UNDO_LIMIT {
+                        self.history.remove(0);
+                    }
+                    self.history.push(before);
+                }
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluate the values of a command, which can use variables and knobs
+    fn value<T>(&self, loc: &Loc, f: impl FnOnce(&Env) -> Result<T, EvalError>) -> EngineResult<T> {
+        let env = Env {
+            vars: &self.state.vars,
+            knobs: &self.knobs,
+            frame: Some(0),
+        };
+        f(&env).map_err(|e| EngineError::Runtime {
+            loc: loc.clone(),
+            source: e.into(),
+        })
+    }
+
+    fn update_lights(&mut self) {
+        self.drawer.env_lights = collect_env_lights(self.lights.clone(), self.ambient);
+    }
+}
+
+fn semantics(loc: Loc, msg: &'static str) -> EngineError {
+    EngineError::Runtime {
+        loc,
+        source: RuntimeError::Semantics(msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mdl::types::Kind;
+
+    fn repl(lines: &[&str]) -> Repl {
+        let mut repl = Repl::new(Screen {
+            width: 60,
+            height: 60,
+            depth: 255,
+        });
+        for line in lines {
+            repl.eval(line).unwrap();
+        }
+        repl
+    }
+
+    fn is_blank(repl: &Repl) -> bool {
+        let pixels = repl.drawer().canvas().pixels();
+        pixels.iter().all(|p| *p == pixels[0])
+    }
+
+    #[test]
+    fn test_keeps_state_between_lines() {
+        let mut repl = repl(&[
+            "constants shiny 0.1 0.5 0.5 0.1 0.5 0.5 0.1 0.5 0.5",
+            "move 30 30 0",
+            "let r = 10",
+            "def ball(x) {",
+        ]);
+        assert_eq!("...> ", repl.prompt());
+        repl.eval("  sphere shiny x 0 0 r").unwrap();
+        repl.eval("}").unwrap();
+        assert_eq!("mdl> ", repl.prompt());
+        assert!(is_blank(&repl));
+
+        repl.eval("call ball(-5)").unwrap();
+        assert!(!is_blank(&repl));
+        assert_eq!(
+            Some("constants shiny\nlet r = 10\ndef ball(x)".to_owned()),
+            repl.eval(":symbols").unwrap()
+        );
+        assert!(repl.eval(":top").unwrap().unwrap().contains("30.00"));
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut repl = repl(&["move 30 30 0", "box 0 0 0 10 10 10"]);
+        let one_box = repl.drawer().canvas().clone();
+        // nothing is drawn this far off the screen, so it's not undone
+        repl.eval("sphere 1000 1000 0 5").unwrap();
+        repl.eval("sphere -10 -10 0 5").unwrap();
+
+        repl.eval(":undo").unwrap();
+        assert!(one_box == *repl.drawer().canvas());
+        repl.eval(":undo").unwrap();
+        assert!(is_blank(&repl));
+        assert_eq!(
+            Some("nothing to undo".to_owned()),
+            repl.eval(":undo").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_errors_are_checked_when_lines_run() {
+        let mut repl = repl(&["def a() {", "call b()", "}"]);
+        match repl.eval("call b()") {
+            Err(EngineError::Runtime {
+                loc,
+                source: RuntimeError::UndefinedSymbol { name, kind },
+            }) => {
+                assert_eq!(Loc::line(4), loc);
+                assert_eq!(("b", Kind::Def), (&name[..], kind));
+            }
+            other => panic!("expected an undefined def, got {:?}", other),
+        }
+        repl.eval("def b() {").unwrap();
+        repl.eval("call a()").unwrap();
+        match repl.eval("}") {
+            Err(EngineError::Runtime {
+                loc,
+                source: RuntimeError::RecursiveCall(name),
+            }) => {
+                assert_eq!(Loc::line(6), loc);
+                assert_eq!("b", name);
+            }
+            other => panic!("expected a recursive call, got {:?}", other),
+        }
+        assert!(matches!(
+            repl.eval("frames 10"),
+            Err(EngineError::Runtime {
+                source: RuntimeError::Semantics(_),
+                ..
+            })
+        ));
+        assert!(matches!(
+            repl.eval("sphre 0 0 0 1"),
+            Err(EngineError::Syntax(_))
+        ));
+    }
+}
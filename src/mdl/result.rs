@@ -2,7 +2,17 @@ use std::io;
 
 use thiserror::Error;
 
-use super::types::Kind;
+use crate::{
+    drawer::StackUnderflow,
+    matrix::{obj::ObjError, MalformedGeometry},
+    processes::ToolError,
+};
+
+use super::{
+    ast::{Loc, Screen},
+    diagnostics::{Report, SyntaxError},
+    types::Kind,
+};
 
 /// Result to wrap EngineError
 pub type EngineResult<T> = Result<T, EngineError>;
@@ -11,12 +21,8 @@ pub type EngineResult<T> = Result<T, EngineError>;
 pub enum EngineError {
     #[error("io error: {0}")]
     Io(#[from] io::Error),
-    #[error("syntax error on line {line}: input: {input}, type: {kind:?}")]
-    Syntax {
-        line: usize,
-        input: String,
-        kind: nom::error::ErrorKind,
-    },
+    #[error("{0}")]
+    Syntax(Box<SyntaxError>),
     #[error("symbol {name} is not found")]
     SymbolNotFound { name: String },
     #[error("symbol {name} defined as {expected} but here used as {found}")]
@@ -25,8 +31,40 @@ pub enum EngineError {
         expected: Kind,
         found: Kind,
     },
-    #[error("runtime error on line {line}: {source}")]
-    Runtime { line: usize, source: RuntimeError }, // Syntax(#[from] nom::Err),
+    #[error("runtime error on {loc}: {source}")]
+    Runtime { loc: Loc, source: RuntimeError }, // Syntax(#[from] nom::Err),
+    #[error("{0}")]
+    Diagnostics(Report),
+    #[error("can't render at this size: {0}")]
+    Screen(RuntimeError),
+    #[error("{0}")]
+    ExternalTool(ToolError),
+    #[error("a gif needs pixels, which this backend doesn't draw, use `output frames svg` to save every frame on its own")]
+    NoPixels,
+}
+
+impl EngineError {
+    /// Line of the script the error is on, if it's about one line
+    pub fn loc(&self) -> Option<&Loc> {
+        match self {
+            EngineError::Syntax(e) => Some(&e.loc),
+            EngineError::Runtime { loc, .. } => Some(loc),
+            _ => None,
+        }
+    }
+
+    /// An io error, or the `ToolError` inside it if it's from running imagemagick
+    pub(crate) fn from_io(e: io::Error) -> Self {
+        if !e.get_ref().is_some_and(|inner| inner.is::<ToolError>()) {
+            return EngineError::Io(e);
+        }
+        let kind = e.kind();
+        match e.into_inner().map(|inner| inner.downcast::<ToolError>()) {
+            Some(Ok(tool)) => EngineError::ExternalTool(*tool),
+            Some(Err(inner)) => EngineError::Io(io::Error::new(kind, inner)),
+            None => EngineError::Io(kind.into()),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -35,8 +73,63 @@ pub enum RuntimeError {
     Io(#[from] io::Error),
     #[error("multiple frame numbers defined")]
     MultipleFrameNumber,
+    #[error("multiple screen sizes defined")]
+    MultipleScreen,
+    #[error(
+        "screen of {width}x{height} has more than {} pixels",
+        Screen::MAX_PIXELS
+    )]
+    ScreenTooLarge { width: u32, height: u32 },
     #[error("`vary` is present but `frames` is undefined")]
     FramesUndefined,
+    #[error("can't load mesh \"{path}\": {source}")]
+    Mesh { path: String, source: ObjError },
+    #[error("knob list {0} is not defined")]
+    KnobListNotFound(String),
+    #[error("{kind} {name} is not defined")]
+    UndefinedSymbol { name: String, kind: Kind },
+    #[error("{name} is defined as {expected} but used as {found}")]
+    SymbolTypeMismatch {
+        name: String,
+        expected: Kind,
+        found: Kind,
+    },
+    #[error("{kind} {name} is used before it's defined")]
+    UsedBeforeDefined { name: String, kind: Kind },
+    #[error("{0} has no value in this frame")]
+    NoValue(String),
+    #[error("value is {0}, which is too large or not a number")]
+    NotFinite(f64),
+    #[error("{0} can change from frame to frame, so it can't be used here")]
+    NotConstant(String),
+    #[error("camera can't aim at the point its eye is at")]
+    CameraAimsAtEye,
+    #[error("focal length must be > 0, not {0}")]
+    Focal(f64),
+    #[error("repeat count must be a whole number >= 0, not {0}")]
+    RepeatCount(f64),
+    #[error("{name} takes {expected} arguments, but is called with {found}")]
+    WrongArgCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error("{0} ends up calling itself, so it would never finish")]
+    RecursiveCall(String),
+    #[error("can't include \"{path}\": {source}")]
+    Include { path: String, source: io::Error },
+    #[error("\"{0}\" ends up including itself")]
+    IncludeCycle(String),
+    #[error("{0}")]
+    ExternalTool(ToolError),
+    #[error("{0}")]
+    StackUnderflow(StackUnderflow),
+    #[error("can't draw it, {0}")]
+    MalformedGeometry(MalformedGeometry),
+    #[error("can't rotate about {0}, the axis must be x, y or z")]
+    Axis(char),
+    #[error("{0} can't run here")]
+    Unsupported(&'static str),
     #[error("semantics error: {0}")]
     Semantics(&'static str),
     #[error("{0}")]
@@ -28,6 +28,11 @@ pub enum Kind {
     Coord,
     Knob,
     KnobList,
+    Light,
+    /// `let` variable, loop variable or parameter
+    Var,
+    /// `def` block
+    Def,
 }
 
 impl From<&Type> for Kind {
@@ -45,7 +50,7 @@ impl fmt::Display for Kind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "type::{}",
+            "{}",
             match self {
                 Kind::Const => {
                     "constants"
@@ -59,6 +64,15 @@ impl fmt::Display for Kind {
                 Kind::KnobList => {
                     "knoblist"
                 }
+                Kind::Light => {
+                    "light"
+                }
+                Kind::Var => {
+                    "variable"
+                }
+                Kind::Def => {
+                    "def"
+                }
             }
         )
     }
@@ -1,7 +1,109 @@
-pub(crate) fn warn_unimpl(cmd: &str, line: usize) {
-    eprintln!("unimplemented: {}, line: {}", cmd, line);
+use std::{fs, path::Path};
+
+use super::result::EngineResult;
+
+/// Number of digits in frame numbers, so that files sort in order. At least 3
+pub(crate) fn frame_digits(frames: u32) -> usize {
+    frames.saturating_sub(1).to_string().len().max(3)
+}
+
+/// Put the frame number into a file name
+///
+/// `%d` or `%0Nd` in `template` is replaced by the frame number, like in printf.
+/// Without one, the frame number is added before the extension: `name.png` becomes `name-007.png`.
+pub(crate) fn frame_path(template: &str, frame: u32, frames: u32) -> String {
+    if let Some(start) = template.find('%') {
+        let spec = &template[start + 1..];
+        if let Some(end) = spec.find('d') {
+            let width = &spec[..end];
+            if width.chars().all(|c| c.is_ascii_digit()) {
+                let width = width.parse().unwrap_or(0);
+                return format!(
+                    "{}{:0width$}{}",
+                    &template[..start],
+                    frame,
+                    &spec[end + 1..],
+                    width = width
+                );
+            }
+        }
+    }
+
+    let number = format!("{:0width$}", frame, width = frame_digits(frames));
+    // the extension is in the file name, not in the directories before it
+    let name = template
+        .rfind(&['/', '\\'][..])
+        .map_or(0, |slash| slash + 1);
+    match template[name..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let dot = name + dot;
+            format!("{}-{}{}", &template[..dot], number, &template[dot..])
+        }
+        _ => format!("{}-{}", template, number),
+    }
 }
 
-pub(crate) fn warn_disabled_in_animation(cmd: &str) {
-    eprintln!("warning: command `{}` is disabled in animation mode", cmd);
+/// Write a file with `write`, which is given a path next to `path`, and then move it into place
+///
+/// Nothing sees the file half written, and a failed write leaves the file that was there before.
+/// The temporary file keeps the extension, since that's how magick picks the format.
+pub(crate) fn write_atomically<T>(
+    path: &Path,
+    write: impl FnOnce(&Path) -> EngineResult<T>,
+) -> EngineResult<T> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = path.with_file_name(format!(".partial-{}", name));
+    match write(&partial) {
+        Ok(value) => {
+            fs::rename(&partial, path)?;
+            Ok(value)
+        }
+        Err(e) => {
+            // it may not have been created
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_path() {
+        assert_eq!("frame-007.png", frame_path("frame-%03d.png", 7, 100));
+        assert_eq!("f7.png", frame_path("f%d.png", 7, 100));
+        assert_eq!("shot-007.png", frame_path("shot.png", 7, 100));
+        assert_eq!("shot-0007.png", frame_path("shot.png", 7, 1001));
+        assert_eq!("shot-007", frame_path("shot", 7, 10));
+        assert_eq!("../out/frame-007", frame_path("../out/frame", 7, 10));
+        assert_eq!("renders.v2/shot-007", frame_path("renders.v2/shot", 7, 10));
+        assert_eq!(
+            "renders.v2/shot-007.png",
+            frame_path("renders.v2/shot.png", 7, 10)
+        );
+        assert_eq!("out/.hidden-007", frame_path("out/.hidden", 7, 10));
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = std::env::temp_dir().join(format!("mdl-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.txt");
+        fs::write(&path, "old").unwrap();
+
+        let failed: EngineResult<()> = write_atomically(&path, |partial| {
+            assert_eq!(dir.join(".partial-out.txt"), partial);
+            fs::write(partial, "half")?;
+            Err(std::io::Error::other("failed").into())
+        });
+        assert!(failed.is_err());
+        assert_eq!("old", fs::read_to_string(&path).unwrap());
+        assert!(!dir.join(".partial-out.txt").exists());
+
+        write_atomically(&path, |partial| Ok(fs::write(partial, "new")?)).unwrap();
+        assert_eq!("new", fs::read_to_string(&path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,160 @@
+//! Polling the files a script uses, to run it again when one of them changes
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use super::ast::{self, Command, Line};
+
+/// Files of a script, and what they looked like when it was last run
+pub(crate) struct Watcher {
+    files: Vec<(PathBuf, Option<Stamp>)>,
+}
+
+/// Modification time and size, one of which changes when a file is written
+type Stamp = (Option<SystemTime>, u64);
+
+impl Watcher {
+    pub(crate) fn new(script: &Path) -> Self {
+        let references = References {
+            scripts: vec![script.to_path_buf()],
+            meshes: vec![],
+        };
+        Self::watching(references.follow())
+    }
+
+    /// Watcher for the files used by the text of a script, which includes files relative to the working directory
+    pub(crate) fn from_source(source: &str) -> Self {
+        let mut references = References::default();
+        references.scan(source, Path::new(""));
+        Self::watching(references.follow())
+    }
+
+    fn watching(files: Vec<PathBuf>) -> Self {
+        Self {
+            files: files
+                .into_iter()
+                .map(|path| {
+                    let stamp = stamp(&path);
+                    (path, stamp)
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether a file was written, created or removed since the watcher was made
+    pub(crate) fn changed(&self) -> bool {
+        self.files.iter().any(|(path, old)| stamp(path) != *old)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.files.len()
+    }
+}
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    fs::metadata(path)
+        .ok()
+        .map(|meta| (meta.modified().ok(), meta.len()))
+}
+
+/// Scripts and meshes used by a script
+#[derive(Default)]
+struct References {
+    scripts: Vec<PathBuf>,
+    meshes: Vec<PathBuf>,
+}
+
+impl References {
+    /// Follow the includes of every script, and return the scripts, the files they include and the meshes they load
+    ///
+    /// Lines that don't parse are skipped, so the files of a broken script are still watched,
+    /// and so are files that don't exist yet.
+    fn follow(mut self) -> Vec<PathBuf> {
+        let mut next = 0;
+        while let Some(file) = self.scripts.get(next).cloned() {
+            next += 1;
+            if let Ok(text) = fs::read_to_string(&file) {
+                self.scan(&text, file.parent().unwrap_or_else(|| Path::new("")));
+            }
+        }
+        self.scripts.extend(self.meshes);
+        self.scripts
+    }
+
+    /// Add what the lines of a script in `dir` use
+    fn scan(&mut self, text: &str, dir: &Path) {
+        for line in text.lines() {
+            match ast::parse_line(line) {
+                // includes and meshes are relative to the file they're in
+                Ok((_, Some(Line::Include(path)))) => {
+                    let path = dir.join(path);
+                    if !self.scripts.contains(&path) {
+                        self.scripts.push(path);
+                    }
+                }
+                Ok((_, Some(Line::Cmd(cmd)))) => {
+                    if let Command::ShapeCmd(ast::Shape::Mesh { filename, .. }) = *cmd {
+                        let path = dir.join(filename);
+                        if !self.meshes.contains(&path) {
+                            self.meshes.push(path);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watches_includes_and_meshes() {
+        let dir = std::env::temp_dir().join(format!("mdl-watch-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        let script = dir.join("main.mdl");
+        fs::write(
+            &script,
+            "include :lib/shapes.mdl\nsphre 0 0 0 1\nmesh :teapot.obj\ninclude :missing.mdl\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("lib/shapes.mdl"),
+            "repeat 2 {\n  mesh :teapot.obj\n  mesh :cube.obj\n}\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                script.clone(),
+                dir.join("lib/shapes.mdl"),
+                dir.join("missing.mdl"),
+                dir.join("teapot.obj"),
+                dir.join("lib/teapot.obj"),
+                dir.join("lib/cube.obj"),
+            ],
+            Watcher::new(&script)
+                .files
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>()
+        );
+
+        let watcher = Watcher::new(&script);
+        assert_eq!(6, watcher.len());
+        assert!(!watcher.changed());
+        // a different size, in case the clock is too coarse to see the change
+        fs::write(dir.join("lib/shapes.mdl"), "box 0 0 0 1 1 1\n").unwrap();
+        assert!(watcher.changed());
+
+        let watcher = Watcher::new(&script);
+        fs::write(dir.join("missing.mdl"), "").unwrap();
+        assert!(watcher.changed());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
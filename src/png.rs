@@ -0,0 +1,190 @@
+//! PNG encoder for RGB images, 8 or 16 bits per channel
+
+use std::io::{self, Write};
+
+use crate::RGB;
+
+mod zlib;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Write `pixels`, in rows from top to bottom, as a png
+///
+/// Colors are in [0, 255], or in [0, 65535] when `sixteen_bit`.
+pub fn write_png<W: Write>(
+    mut writer: W,
+    width: u32,
+    height: u32,
+    sixteen_bit: bool,
+    pixels: &[RGB],
+) -> io::Result<()> {
+    if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "png size must be between 1 and 2^31 - 1",
+        ));
+    }
+    assert_eq!(
+        width as usize * height as usize,
+        pixels.len(),
+        "wrong number of pixels"
+    );
+
+    let samples = samples(pixels, sixteen_bit);
+
+    writer.write_all(&SIGNATURE)?;
+
+    let mut header = vec![];
+    header.extend(&width.to_be_bytes());
+    header.extend(&height.to_be_bytes());
+    // bit depth, color type 2 (RGB), deflate, adaptive filtering, no interlace
+    header.extend(&[if sixteen_bit { 16 } else { 8 }, 2, 0, 0, 0]);
+    write_chunk(&mut writer, b"IHDR", &header)?;
+
+    let bytes_per_pixel = if sixteen_bit { 6 } else { 3 };
+    let filtered = filter(&samples, width as usize * bytes_per_pixel, bytes_per_pixel);
+    write_chunk(&mut writer, b"IDAT", &zlib::compress(&filtered))?;
+    write_chunk(&mut writer, b"IEND", &[])?;
+    writer.flush()
+}
+
+/// Channels of every pixel in order, 16 bit ones in big endian
+fn samples(pixels: &[RGB], sixteen_bit: bool) -> Vec<u8> {
+    let mut samples = Vec::with_capacity(pixels.len() * if sixteen_bit { 6 } else { 3 });
+    for p in pixels {
+        for c in [p.red, p.green, p.blue].iter() {
+            if sixteen_bit {
+                samples.extend(&c.to_be_bytes());
+            } else {
+                samples.push((*c).min(255) as u8);
+            }
+        }
+    }
+    samples
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data));
+    writer.write_all(&crc.to_be_bytes())
+}
+
+/// Filter every row with the filter that gives the smallest sum of differences
+///
+/// This is the heuristic suggested by the png spec. Each row starts with its filter type.
+fn filter(samples: &[u8], row_len: usize, bpp: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(samples.len() + samples.len() / row_len);
+    let zeros = vec![0; row_len];
+    let mut candidates = vec![vec![0u8; row_len]; 5];
+
+    for (y, row) in samples.chunks(row_len).enumerate() {
+        let above = if y == 0 {
+            &zeros[..]
+        } else {
+            &samples[(y - 1) * row_len..y * row_len]
+        };
+
+        for i in 0..row_len {
+            let a = if i >= bpp { row[i - bpp] } else { 0 };
+            let b = above[i];
+            let c = if i >= bpp { above[i - bpp] } else { 0 };
+            let x = row[i];
+            candidates[0][i] = x;
+            candidates[1][i] = x.wrapping_sub(a);
+            candidates[2][i] = x.wrapping_sub(b);
+            candidates[3][i] = x.wrapping_sub(((a as u16 + b as u16) / 2) as u8);
+            candidates[4][i] = x.wrapping_sub(paeth(a, b, c));
+        }
+
+        let cost = |filtered: &Vec<u8>| -> u64 {
+            filtered
+                .iter()
+                .map(|&v| (v as i8).unsigned_abs() as u64)
+                .sum()
+        };
+        let (kind, best) = candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, filtered)| cost(filtered))
+            .unwrap();
+        out.push(kind as u8);
+        out.extend(best);
+    }
+    out
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn crc32<'a>(bytes: impl Iterator<Item = &'a u8>) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0xcbf4_3926, crc32(b"123456789".iter()));
+    }
+
+    #[test]
+    fn test_png_layout() {
+        let pixels = vec![RGB::new(255, 0, 0), RGB::WHITE];
+        for &(sixteen_bit, depth) in [(false, 8), (true, 16)].iter() {
+            let mut png = vec![];
+            write_png(&mut png, 2, 1, sixteen_bit, &pixels).unwrap();
+            assert_eq!(&SIGNATURE, &png[..8]);
+            assert_eq!(b"IHDR", &png[12..16]);
+            assert_eq!(&[0, 0, 0, 2, 0, 0, 0, 1, depth, 2], &png[16..26]);
+            assert_eq!(b"IEND\xae\x42\x60\x82", &png[png.len() - 8..]);
+        }
+    }
+
+    #[test]
+    fn test_sixteen_bit_samples_keep_every_bit() {
+        let pixels = [RGB::new(25828, 0x0102, u16::MAX)];
+        assert_eq!(
+            vec![0x64, 0xe4, 0x01, 0x02, 0xff, 0xff],
+            samples(&pixels, true)
+        );
+        assert_eq!(vec![255, 0, 1], samples(&[RGB::new(300, 0, 1)], false));
+    }
+
+    #[test]
+    fn test_filter_picks_up_for_repeated_rows() {
+        let row: Vec<u8> = (0..30).map(|i| (i * 37 % 251) as u8).collect();
+        let samples = [row.clone(), row].concat();
+        let filtered = filter(&samples, 30, 3);
+        // the second row is the same as the one above it, so it's all zeros after filtering
+        assert_eq!(2, filtered[31]);
+        assert!(filtered[32..].iter().all(|&v| v == 0));
+    }
+}
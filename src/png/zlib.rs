@@ -0,0 +1,338 @@
+//! zlib stream with deflate compression
+//!
+//! Repeats are found with LZ77 over a 32K window, and written with the fixed Huffman codes
+//! of deflate, in a single block.
+
+/// Size of the LZ77 window
+const WINDOW: usize = 1 << 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash to try, before taking the best match so far
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+/// (base length, extra bits) of length codes 257 to 285
+const LENGTHS: [(u16, u8); 29] = [
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 1),
+    (13, 1),
+    (15, 1),
+    (17, 1),
+    (19, 2),
+    (23, 2),
+    (27, 2),
+    (31, 2),
+    (35, 3),
+    (43, 3),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 4),
+    (115, 4),
+    (131, 5),
+    (163, 5),
+    (195, 5),
+    (227, 5),
+    (258, 0),
+];
+
+/// (base distance, extra bits) of distance codes 0 to 29
+const DISTANCES: [(u16, u8); 30] = [
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 1),
+    (7, 1),
+    (9, 2),
+    (13, 2),
+    (17, 3),
+    (25, 3),
+    (33, 4),
+    (49, 4),
+    (65, 5),
+    (97, 5),
+    (129, 6),
+    (193, 6),
+    (257, 7),
+    (385, 7),
+    (513, 8),
+    (769, 8),
+    (1025, 9),
+    (1537, 9),
+    (2049, 10),
+    (3073, 10),
+    (4097, 11),
+    (6145, 11),
+    (8193, 12),
+    (12289, 12),
+    (16385, 13),
+    (24577, 13),
+];
+
+/// Compress `data` into a zlib stream
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    // 32K window, default compression level
+    let mut out = BitWriter::new(vec![0x78, 0x9c]);
+    // last block, fixed Huffman codes
+    out.write(1, 1);
+    out.write(1, 2);
+
+    // latest position of every hash, and the position before it with the same hash
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW];
+
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = if i + MIN_MATCH <= data.len() {
+            longest_match(data, i, head[hash(data, i)], &prev)
+        } else {
+            (0, 0)
+        };
+
+        if length >= MIN_MATCH {
+            out.write_length(length);
+            out.write_distance(distance);
+            for j in i..i + length {
+                insert(data, &mut head, &mut prev, j);
+            }
+            i += length;
+        } else {
+            out.write_literal(data[i]);
+            insert(data, &mut head, &mut prev, i);
+            i += 1;
+        }
+    }
+    out.write_code(256);
+
+    let mut out = out.finish();
+    out.extend(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Add position `i` to the hash chains
+fn insert(data: &[u8], head: &mut [usize], prev: &mut [usize], i: usize) {
+    if i + MIN_MATCH <= data.len() {
+        let h = hash(data, i);
+        prev[i % WINDOW] = head[h];
+        head[h] = i;
+    }
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let v = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Follow the hash chain from `candidate`, and return the (length, distance) of the longest match
+fn longest_match(data: &[u8], i: usize, mut candidate: usize, prev: &[usize]) -> (usize, usize) {
+    let max_len = MAX_MATCH.min(data.len() - i);
+    let (mut best_len, mut best_dist) = (0, 0);
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX || i - candidate > WINDOW {
+            break;
+        }
+        let len = data[candidate..]
+            .iter()
+            .zip(&data[i..i + max_len])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if len > best_len {
+            best_len = len;
+            best_dist = i - candidate;
+            if len == max_len {
+                break;
+            }
+        }
+        let next = prev[candidate % WINDOW];
+        // the slot was reused by a newer position, so the chain ends here
+        if next == usize::MAX || next >= candidate {
+            break;
+        }
+        candidate = next;
+    }
+    (best_len, best_dist)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // sums can't overflow within this many bytes
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+/// Packs bits into bytes, least significant bit first
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= value << self.bits;
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    /// Huffman codes are packed starting from their most significant bit
+    fn write_huffman(&mut self, code: u32, bits: u32) {
+        self.write(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    /// Write a literal/length code with the fixed Huffman table
+    fn write_code(&mut self, code: u16) {
+        let code = code as u32;
+        match code {
+            0..=143 => self.write_huffman(0x30 + code, 8),
+            144..=255 => self.write_huffman(0x190 + code - 144, 9),
+            256..=279 => self.write_huffman(code - 256, 7),
+            _ => self.write_huffman(0xc0 + code - 280, 8),
+        }
+    }
+
+    fn write_literal(&mut self, byte: u8) {
+        self.write_code(byte as u16);
+    }
+
+    fn write_length(&mut self, length: usize) {
+        let length = length as u16;
+        let index = LENGTHS
+            .iter()
+            .rposition(|&(base, _)| base <= length)
+            .unwrap();
+        let (base, extra) = LENGTHS[index];
+        self.write_code(257 + index as u16);
+        self.write((length - base) as u32, extra as u32);
+    }
+
+    fn write_distance(&mut self, distance: usize) {
+        let distance = distance as u16;
+        let index = DISTANCES
+            .iter()
+            .rposition(|&(base, _)| base <= distance)
+            .unwrap();
+        let (base, extra) = DISTANCES[index];
+        // fixed distance codes are all 5 bits
+        self.write_huffman(index as u32, 5);
+        self.write((distance - base) as u32, extra as u32);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inflate a stream of fixed Huffman blocks, which is all `compress` makes
+    fn decompress(stream: &[u8]) -> Vec<u8> {
+        assert_eq!(0, ((stream[0] as u16) << 8 | stream[1] as u16) % 31);
+        let data = &stream[2..stream.len() - 4];
+        let mut pos = 0;
+        let mut bit = |n: u32| {
+            let mut v = 0;
+            for i in 0..n {
+                v |= (((data[pos / 8] >> (pos % 8)) & 1) as u32) << i;
+                pos += 1;
+            }
+            v
+        };
+        let mut out: Vec<u8> = vec![];
+        loop {
+            let last = bit(1);
+            assert_eq!(1, bit(2), "only fixed Huffman blocks are expected");
+            loop {
+                // read the code one bit at a time, most significant first
+                let mut code = 0;
+                let mut len = 0;
+                let symbol = loop {
+                    code = code << 1 | bit(1);
+                    len += 1;
+                    match (len, code) {
+                        (7, 0..=0x17) => break code + 256,
+                        (8, 0x30..=0xbf) => break code - 0x30,
+                        (8, 0xc0..=0xc7) => break code - 0xc0 + 280,
+                        (9, 0x190..=0x1ff) => break code - 0x190 + 144,
+                        _ => assert!(len < 9),
+                    }
+                };
+                match symbol {
+                    0..=255 => out.push(symbol as u8),
+                    256 => break,
+                    _ => {
+                        let (base, extra) = LENGTHS[symbol as usize - 257];
+                        let length = base as usize + bit(extra as u32) as usize;
+                        let dist_code = (0..5).fold(0, |c, _| c << 1 | bit(1));
+                        let (base, extra) = DISTANCES[dist_code as usize];
+                        let distance = base as usize + bit(extra as u32) as usize;
+                        for _ in 0..length {
+                            out.push(out[out.len() - distance]);
+                        }
+                    }
+                }
+            }
+            if last == 1 {
+                break;
+            }
+        }
+        let checksum = &stream[stream.len() - 4..];
+        assert_eq!(&adler32(&out).to_be_bytes(), checksum);
+        out
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut seed = 7u32;
+        let noisy: Vec<u8> = (0..100_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        // long runs with matches far back, across the edge of the window
+        let repeats: Vec<u8> = (0..200_000u32).map(|i| ((i / 1000) % 37) as u8).collect();
+
+        for data in [noisy, repeats, vec![], vec![42], b"abcabcabcabc".to_vec()].iter() {
+            assert_eq!(*data, decompress(&compress(data)));
+        }
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(0x11e6_0398, adler32(b"Wikipedia"));
+    }
+}
@@ -1,27 +1,77 @@
 //! Utility fn to help working with various processes, especially imagemagick
 
-use std::process::{Child, Command, Stdio};
+use std::{
+    io,
+    process::{Child, Command, ExitStatus, Stdio},
+};
+
+use thiserror::Error;
+
+/// Name of the imagemagick program that converts images
+const MAGICK: &str = if cfg!(windows) { "magick" } else { "convert" };
+
+/// An external program that couldn't be run, or that failed
+#[derive(Error, Debug)]
+pub enum ToolError {
+    #[error("can't run `{program}`, is imagemagick installed? ({source})")]
+    Spawn {
+        program: &'static str,
+        source: io::Error,
+    },
+    #[error("can't wait for `{program}` to exit: {source}")]
+    Wait {
+        program: &'static str,
+        source: io::Error,
+    },
+    #[error("`{program}` failed with {status}")]
+    Failed {
+        program: &'static str,
+        status: ExitStatus,
+    },
+}
+
+/// Kept inside the io error, so it can be told apart from other io errors with `get_ref`
+impl From<ToolError> for io::Error {
+    fn from(e: ToolError) -> Self {
+        io::Error::other(e)
+    }
+}
 
 /// Subprocess (and run) `(magick) convert` with the given `args`
-pub fn magick(args: Vec<&str>) -> Child {
-    Command::new(if cfg!(windows) { "magick" } else { "convert" })
+pub fn magick(args: Vec<&str>) -> Result<Child, ToolError> {
+    Command::new(MAGICK)
         .args(args)
         .spawn()
-        .expect("Can't spawn imagemagick")
+        .map_err(|source| ToolError::Spawn {
+            program: MAGICK,
+            source,
+        })
 }
 
 /// Subprocess (and run) `(magick) convert` with a piped stdin with the given `args`
-pub fn pipe_to_magick(args: Vec<&str>) -> Child {
-    Command::new(if cfg!(windows) { "magick" } else { "convert" })
+pub fn pipe_to_magick(args: Vec<&str>) -> Result<Child, ToolError> {
+    Command::new(MAGICK)
         .args(args)
         .stdin(Stdio::piped())
         .spawn()
-        .expect("Can't spawn imagemagick")
+        .map_err(|source| ToolError::Spawn {
+            program: MAGICK,
+            source,
+        })
 }
 
-/// Wait for `magick` to exit with the appropriate printlns. This is not designed to be composable. It's used usually as the last statement in program.
-pub fn wait_for_magick(mut magick: Child) -> std::process::ExitStatus {
-    // println!("Waiting for magick to exit...");
-    magick.wait().expect("Failed to wait on magick")
-    // println!("magick {}", exit_status);
+/// Wait for `magick` to exit, which is an error if it didn't exit successfully
+pub fn wait_for_magick(mut magick: Child) -> Result<ExitStatus, ToolError> {
+    let status = magick.wait().map_err(|source| ToolError::Wait {
+        program: MAGICK,
+        source,
+    })?;
+    if status.success() {
+        Ok(status)
+    } else {
+        Err(ToolError::Failed {
+            program: MAGICK,
+            status,
+        })
+    }
 }
@@ -9,13 +9,15 @@ use crate::{
     light::{self, LightProps},
 };
 
-pub(crate) fn create_file(filepath: &str) -> File {
+/// Create a file, with its path in the error
+pub(crate) fn create_file(filepath: &str) -> io::Result<File> {
     let path = Path::new(filepath);
-    let display = path.display();
-    match File::create(&path) {
-        Err(why) => panic!("Could not create {}: {}", display, why),
-        Ok(file) => file,
-    }
+    File::create(path).map_err(|why| {
+        io::Error::new(
+            why.kind(),
+            format!("could not create {}: {}", path.display(), why),
+        )
+    })
 }
 
 pub(crate) fn polar_to_xy(mag: f64, angle_degrees: f64) -> (f64, f64) {
@@ -52,27 +54,57 @@ pub(crate) fn compute_hermite3_coef(p0: f64, p1: f64, r0: f64, r1: f64) -> (f64,
 }
 
 use crate::{Matrix, PPMImg};
-use std::{fs, process::Command};
+use std::{
+    fs, io,
+    process::{self, Command},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use super::RGB;
 
+/// Used to give every image shown by `display_with` its own tmp file
+static DISPLAY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 pub(crate) fn display_ppm(img: &PPMImg) {
-    let tmpfile_name = "tmp.ppm";
-    img.write_binary(tmpfile_name)
-        .expect("Error writing to file");
+    display_with("ppm", |file| img.write_bin_to_buf(file));
+}
+
+/// Show an image with `display`, from a tmp file with the given extension that `write` fills
+pub(crate) fn display_with(
+    extension: &str,
+    write: impl FnOnce(&mut dyn io::Write) -> io::Result<()>,
+) {
+    let tmpfile_name = format!(
+        "tmp-{}-{}.{}",
+        process::id(),
+        DISPLAY_COUNT.fetch_add(1, Ordering::Relaxed),
+        extension
+    );
+    // showing an image is best effort, so problems are printed instead of returned
+    let written = create_file(&tmpfile_name).and_then(|mut file| write(&mut file));
+    if let Err(e) = written {
+        eprintln!("Can't write image to show to {}: {}", tmpfile_name, e);
+        let _ = fs::remove_file(&tmpfile_name);
+        return;
+    }
 
     let mut cmd = Command::new(if cfg!(windows) {
         "imdisplay"
     } else {
         "display"
     });
-    let mut display = cmd
-        // .arg("-flip")
-        .arg(tmpfile_name)
-        .spawn()
-        .unwrap();
-    let _result = display.wait().unwrap();
-    fs::remove_file(tmpfile_name).expect("Error removing tmp file");
+    // .arg("-flip")
+    match cmd.arg(&tmpfile_name).spawn() {
+        Ok(mut display) => {
+            if let Err(e) = display.wait() {
+                eprintln!("Can't wait for `display` to exit: {}", e);
+            }
+        }
+        Err(e) => eprintln!("Can't show image with `display`: {}", e),
+    }
+    if let Err(e) = fs::remove_file(&tmpfile_name) {
+        eprintln!("Can't remove {}: {}", tmpfile_name, e);
+    }
 }
 
 /// Convenience method to display an edge matrix for testing purposes
@@ -51,16 +51,31 @@ impl Vec3 {
     }
 
     pub fn transform_by(&self, tr_matrix: &Matrix) -> Self {
-        let mut selfm = Matrix::new_edge_matrix();
-        selfm.append_row(&mut vec![self.0, self.1, self.2, 1.]);
-        let new_m = selfm * tr_matrix;
-        match new_m.iter_by_row().next() {
-            Some(point) => {
-                let w = point[3];
-                Vec3(point[0] / w, point[1] / w, point[2] / w)
-            }
-            None => panic!("error occurred when transforming a vec3. This should not happen."),
-        }
+        let point = [self.0, self.1, self.2, 1.];
+        // the point as a row, times a column of the matrix
+        let coord = |c| -> f64 {
+            point
+                .iter()
+                .zip(tr_matrix.col_iter(c))
+                .map(|(p, m)| p * m)
+                .sum()
+        };
+        let w = coord(3);
+        Vec3(coord(0) / w, coord(1) / w, coord(2) / w)
+    }
+
+    /// Transform a normal, so it stays perpendicular to a surface transformed by `tr_matrix`
+    ///
+    /// Normals go through the cofactor matrix, whose rows are cross products of the rows of `tr_matrix`.
+    /// That's how the cross product of two edges changes, so the normal flips with mirror images too.
+    pub fn transform_normal_by(&self, tr_matrix: &Matrix) -> Self {
+        let row = |r| -> Vec3 {
+            let mut row = tr_matrix.row_iter(r).copied();
+            let mut next = || row.next().unwrap_or(0.);
+            Vec3(next(), next(), next())
+        };
+        let (r0, r1, r2) = (row(0), row(1), row(2));
+        r1.cross(r2) * self.0 + r2.cross(r0) * self.1 + r0.cross(r1) * self.2
     }
 
     pub fn from_pt(point: (f64, f64, f64)) -> Self {
@@ -174,9 +189,9 @@ impl From<RGB> for Vec3 {
 impl From<Vec3> for RGB {
     fn from(v: Vec3) -> Self {
         Self {
-            red: v.0.max(0.).min(255.) as u16,
-            blue: v.2.max(0.).min(255.) as u16,
-            green: v.1.max(0.).min(255.) as u16,
+            red: v.0.clamp(0., 255.) as u16,
+            blue: v.2.clamp(0., 255.) as u16,
+            green: v.1.clamp(0., 255.) as u16,
         }
     }
 }